@@ -0,0 +1,95 @@
+// Things embedders may want to react to. Torrents post Alerts, everyone who
+// subscribed gets a copy over a channel. Debugging chatter goes to the log
+// crate instead.
+
+use crate::session::GoalAction;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlertKind {
+    TorrentAdded { name: String },
+    // The info dictionary is known; from a .torrent file that's right away
+    MetadataReceived,
+    PieceVerified { index: usize },
+    HashFailed { index: usize },
+    // ip:port
+    PeerConnected { peer: String },
+    PeerDisconnected { peer: String, reason: String },
+    TrackerReply { url: String, peers: usize },
+    TrackerError { url: String, error: String },
+    TorrentFinished,
+    SeedGoalReached { action: GoalAction },
+    StorageError { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub info_hash: Vec<u8>,
+    pub kind: AlertKind,
+}
+
+/// Where alerts are posted. Clones share subscribers, so one Alerts can
+/// serve several torrents.
+#[derive(Debug, Clone, Default)]
+pub struct Alerts {
+    subscribers: Arc<Mutex<Vec<Sender<Alert>>>>,
+}
+
+impl Alerts {
+    pub fn new() -> Alerts {
+        Alerts::default()
+    }
+
+    /// Get every alert posted from now on
+    pub fn subscribe(&self) -> Receiver<Alert> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn post(&self, info_hash: &[u8], kind: AlertKind) {
+        let alert = Alert {
+            info_hash: info_hash.to_vec(),
+            kind,
+        };
+        // Subscribers that hung up are dropped
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(alert.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe() {
+        let alerts = Alerts::new();
+        let first = alerts.subscribe();
+        let shared = alerts.clone();
+        let second = shared.subscribe();
+
+        alerts.post(&[1; 20], AlertKind::PieceVerified { index: 3 });
+        shared.post(&[2; 20], AlertKind::TorrentFinished);
+        for rx in &[&first, &second] {
+            let alert = rx.recv().unwrap();
+            assert_eq!(alert.info_hash, vec![1; 20]);
+            assert_eq!(alert.kind, AlertKind::PieceVerified { index: 3 });
+            assert_eq!(rx.recv().unwrap().kind, AlertKind::TorrentFinished);
+        }
+
+        // Late subscribers only see what comes after
+        drop(first);
+        let late = alerts.subscribe();
+        alerts.post(&[1; 20], AlertKind::HashFailed { index: 0 });
+        assert_eq!(
+            late.try_recv().unwrap().kind,
+            AlertKind::HashFailed { index: 0 }
+        );
+        assert!(late.try_recv().is_err());
+        assert_eq!(alerts.subscribers.lock().unwrap().len(), 2);
+    }
+}
@@ -6,6 +6,26 @@ pub struct Bitfield(Vec<u8>);
 //
 
 impl Bitfield {
+    pub fn new(num_pieces: usize) -> Bitfield {
+        Bitfield(vec![0; num_pieces.div_ceil(8)])
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Bitfield {
+        Bitfield(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // Right length for num_pieces, with the spare bits at the end cleared
+    pub fn is_valid(&self, num_pieces: usize) -> bool {
+        if self.0.len() != num_pieces.div_ceil(8) {
+            return false;
+        }
+        (num_pieces..self.0.len() * 8).all(|i| !self.has_piece(i))
+    }
+
     pub fn has_piece(&self, index: usize) -> bool {
         let byte_index = index / 8;
         let byte_offset = index % 8;
@@ -42,6 +62,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_valid() {
+        assert!(Bitfield(vec![0b11111111, 0b11110000]).is_valid(12));
+        assert!(!Bitfield(vec![0b11111111, 0b11111000]).is_valid(12));
+        assert!(!Bitfield(vec![0b11111111]).is_valid(12));
+        assert!(Bitfield::new(16).is_valid(16));
+    }
+
     #[test]
     fn test_set_piece() {
         // 5th bit set
@@ -0,0 +1,367 @@
+// Decides which peers we upload to.
+//
+// Every REGULAR_INTERVAL the policy picks the peers that get a regular
+// unchoke slot, and every OPTIMISTIC_INTERVAL one extra choked peer is
+// unchoked in turn so new peers get a chance to prove themselves.
+// Time is always passed in by the caller so the choker can be driven by a
+// simulated clock.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub const REGULAR_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// A peer that hasn't sent us a block for this long is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// Peers connected for less than this are favored for the optimistic slot
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// What the caller knows about a connected peer
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: usize,
+    pub interested: bool,
+    pub snubbed: bool,
+    // Total payload bytes, the choker turns these into rates
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub connected_at: Instant,
+}
+
+/// A peer as seen by a policy, with rates over the last interval
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: usize,
+    pub snubbed: bool,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub connected_at: Instant,
+    pub unchoked: bool,
+}
+
+pub trait ChokePolicy: fmt::Debug {
+    /// Picks the peers that get a regular unchoke. Only interested peers are
+    /// passed in.
+    fn regular_unchokes(&mut self, candidates: &[Candidate], seeding: bool) -> Vec<usize>;
+
+    /// Number of optimistic unchoke slots rotated on top of the regular ones
+    fn optimistic_slots(&self) -> usize {
+        1
+    }
+}
+
+/// The standard BitTorrent choker: reciprocate with the peers we download
+/// from fastest, or upload to fastest once we're seeding.
+#[derive(Debug)]
+pub struct TitForTat {
+    pub slots: usize,
+}
+
+/// Always ranks by upload rate, for seeding boxes that don't care about
+/// reciprocation.
+#[derive(Debug)]
+pub struct FastestUpload {
+    pub slots: usize,
+}
+
+/// Unchokes a fixed number of peers, keeping the current ones as long as
+/// they stay interested.
+#[derive(Debug)]
+pub struct FixedSlots {
+    pub slots: usize,
+}
+
+fn top_by<F: Fn(&Candidate) -> f64>(candidates: &[Candidate], slots: usize, key: F) -> Vec<usize> {
+    let mut ranked: Vec<&Candidate> = candidates.iter().filter(|c| !c.snubbed).collect();
+    // Stable sort keeps ties in connection order
+    ranked.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap());
+    ranked.iter().take(slots).map(|c| c.id).collect()
+}
+
+impl ChokePolicy for TitForTat {
+    fn regular_unchokes(&mut self, candidates: &[Candidate], seeding: bool) -> Vec<usize> {
+        if seeding {
+            top_by(candidates, self.slots, |c| c.upload_rate)
+        } else {
+            top_by(candidates, self.slots, |c| c.download_rate)
+        }
+    }
+}
+
+impl ChokePolicy for FastestUpload {
+    fn regular_unchokes(&mut self, candidates: &[Candidate], _seeding: bool) -> Vec<usize> {
+        top_by(candidates, self.slots, |c| c.upload_rate)
+    }
+}
+
+impl ChokePolicy for FixedSlots {
+    fn regular_unchokes(&mut self, candidates: &[Candidate], _seeding: bool) -> Vec<usize> {
+        let mut ranked: Vec<&Candidate> = candidates.iter().collect();
+        ranked.sort_by_key(|c| (!c.unchoked, c.connected_at));
+        ranked.iter().take(self.slots).map(|c| c.id).collect()
+    }
+
+    fn optimistic_slots(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct Choker {
+    policy: Box<dyn ChokePolicy>,
+    last_regular: Option<Instant>,
+    last_optimistic: Option<Instant>,
+    // Byte counters at the last regular rechoke, to compute rates
+    last_totals: HashMap<usize, (u64, u64)>,
+    // When each peer last got the optimistic slot
+    optimistic_history: HashMap<usize, Instant>,
+    optimistic: Vec<usize>,
+    unchoked: Vec<usize>,
+}
+
+impl Choker {
+    pub fn new(policy: Box<dyn ChokePolicy>) -> Choker {
+        Choker {
+            policy,
+            last_regular: None,
+            last_optimistic: None,
+            last_totals: HashMap::new(),
+            optimistic_history: HashMap::new(),
+            optimistic: vec![],
+            unchoked: vec![],
+        }
+    }
+
+    /// Peers currently unchoked
+    pub fn unchoked(&self) -> &[usize] {
+        &self.unchoked
+    }
+
+    /// Run the next rechoke as soon as tick is called, e.g. because a peer
+    /// became interested and there may be a free slot.
+    pub fn force_rechoke(&mut self) {
+        self.last_regular = None;
+    }
+
+    /// Returns the new set of unchoked peers if a rechoke was due
+    pub fn tick(&mut self, now: Instant, peers: &[PeerInfo], seeding: bool) -> Option<Vec<usize>> {
+        if let Some(last) = self.last_regular {
+            if now.duration_since(last) < REGULAR_INTERVAL {
+                return None;
+            }
+        }
+        let elapsed = self
+            .last_regular
+            .map(|last| now.duration_since(last).as_secs_f64())
+            .unwrap_or(0.0);
+        self.last_regular = Some(now);
+
+        let candidates: Vec<Candidate> = peers
+            .iter()
+            .filter(|p| p.interested)
+            .map(|p| {
+                let (down, up) = self
+                    .last_totals
+                    .get(&p.id)
+                    .cloned()
+                    .unwrap_or((p.downloaded, p.uploaded));
+                let rate = |now: u64, before: u64| {
+                    if elapsed > 0.0 {
+                        now.saturating_sub(before) as f64 / elapsed
+                    } else {
+                        0.0
+                    }
+                };
+                Candidate {
+                    id: p.id,
+                    snubbed: p.snubbed,
+                    download_rate: rate(p.downloaded, down),
+                    upload_rate: rate(p.uploaded, up),
+                    connected_at: p.connected_at,
+                    unchoked: self.unchoked.contains(&p.id),
+                }
+            })
+            .collect();
+        self.last_totals = peers
+            .iter()
+            .map(|p| (p.id, (p.downloaded, p.uploaded)))
+            .collect();
+
+        let regular = self.policy.regular_unchokes(&candidates, seeding);
+
+        // Keep the optimistic peers until their turn is over, unless they
+        // left, lost interest or earned a regular slot
+        let rotate = match self.last_optimistic {
+            Some(last) => now.duration_since(last) >= OPTIMISTIC_INTERVAL,
+            None => true,
+        };
+        if rotate {
+            self.optimistic.clear();
+            self.last_optimistic = Some(now);
+        }
+        self.optimistic
+            .retain(|id| candidates.iter().any(|c| c.id == *id) && !regular.contains(id));
+
+        let mut waiting: Vec<&Candidate> = candidates
+            .iter()
+            .filter(|c| !regular.contains(&c.id) && !self.optimistic.contains(&c.id))
+            .collect();
+        // New peers first, then whoever waited longest for the slot
+        waiting.sort_by_key(|c| {
+            let is_new = now.duration_since(c.connected_at) < NEW_PEER_AGE;
+            (!is_new, self.optimistic_history.get(&c.id).cloned())
+        });
+        let free = self
+            .policy
+            .optimistic_slots()
+            .saturating_sub(self.optimistic.len());
+        for c in waiting.into_iter().take(free) {
+            self.optimistic.push(c.id);
+            self.optimistic_history.insert(c.id, now);
+        }
+
+        self.optimistic_history
+            .retain(|id, _| peers.iter().any(|p| p.id == *id));
+        self.unchoked = regular;
+        self.unchoked.extend(&self.optimistic);
+
+        Some(self.unchoked.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: usize, connected_at: Instant) -> PeerInfo {
+        PeerInfo {
+            id,
+            interested: true,
+            snubbed: false,
+            downloaded: 0,
+            uploaded: 0,
+            connected_at,
+        }
+    }
+
+    fn sorted(mut ids: Vec<usize>) -> Vec<usize> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_rechoke_interval() {
+        let start = Instant::now();
+        let mut choker = Choker::new(Box::new(TitForTat { slots: 2 }));
+        let peers = vec![peer(0, start)];
+
+        assert!(choker.tick(start, &peers, false).is_some());
+        assert!(choker
+            .tick(start + Duration::from_secs(5), &peers, false)
+            .is_none());
+        assert!(choker
+            .tick(start + Duration::from_secs(10), &peers, false)
+            .is_some());
+
+        choker.force_rechoke();
+        assert!(choker
+            .tick(start + Duration::from_secs(11), &peers, false)
+            .is_some());
+    }
+
+    #[test]
+    fn test_tit_for_tat() {
+        let start = Instant::now() - NEW_PEER_AGE;
+        let mut choker = Choker::new(Box::new(TitForTat { slots: 2 }));
+        let mut peers: Vec<PeerInfo> = (0..5).map(|id| peer(id, start)).collect();
+        choker.tick(start, &peers, false);
+
+        // Peers 3 and 1 send us the most
+        peers[3].downloaded = 300_000;
+        peers[1].downloaded = 200_000;
+        peers[0].downloaded = 100_000;
+        peers[4].uploaded = 500_000;
+        let now = start + REGULAR_INTERVAL;
+        let unchoked = choker.tick(now, &peers, false).unwrap();
+        assert_eq!(&unchoked[..2], &[3, 1]);
+        assert_eq!(unchoked.len(), 3);
+
+        // Seeding ranks by upload rate instead
+        peers[4].uploaded = 1_000_000;
+        peers[2].uploaded = 100_000;
+        let unchoked = choker.tick(now + REGULAR_INTERVAL, &peers, true).unwrap();
+        assert_eq!(&unchoked[..2], &[4, 2]);
+    }
+
+    #[test]
+    fn test_uninterested_and_snubbed() {
+        let start = Instant::now();
+        let mut choker = Choker::new(Box::new(FixedSlots { slots: 3 }));
+        let mut peers: Vec<PeerInfo> = (0..3).map(|id| peer(id, start)).collect();
+        peers[0].interested = false;
+        assert_eq!(
+            sorted(choker.tick(start, &peers, false).unwrap()),
+            vec![1, 2]
+        );
+
+        // Snubbing peers lose their regular slot but may still be optimistic
+        let mut choker = Choker::new(Box::new(TitForTat { slots: 1 }));
+        let mut peers: Vec<PeerInfo> = (0..2).map(|id| peer(id, start)).collect();
+        choker.tick(start, &peers, false);
+        peers[0].downloaded = 1_000_000;
+        peers[0].snubbed = true;
+        peers[1].snubbed = true;
+        let unchoked = choker
+            .tick(start + REGULAR_INTERVAL, &peers, false)
+            .unwrap();
+        assert_eq!(unchoked.len(), 1);
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let start = Instant::now() - NEW_PEER_AGE;
+        let mut choker = Choker::new(Box::new(TitForTat { slots: 1 }));
+        let mut peers: Vec<PeerInfo> = (0..4).map(|id| peer(id, start)).collect();
+
+        // Peer 0 holds the regular slot throughout
+        let mut now = start;
+        let mut optimistic = vec![];
+        for round in 0..9 {
+            peers[0].downloaded += 1_000_000;
+            let unchoked = choker.tick(now, &peers, false).unwrap();
+            if round > 0 {
+                assert_eq!(unchoked[0], 0);
+            }
+            if round % 3 == 0 {
+                optimistic.push(*unchoked.last().unwrap());
+            } else {
+                // The slot is held for the whole optimistic interval
+                assert_eq!(*unchoked.last().unwrap(), *optimistic.last().unwrap());
+            }
+            now += REGULAR_INTERVAL;
+        }
+        // Every waiting peer got a turn
+        assert_eq!(sorted(optimistic), vec![1, 2, 3]);
+
+        // A fresh connection jumps the queue
+        peers.push(peer(4, now));
+        let unchoked = choker.tick(now, &peers, false).unwrap();
+        assert_eq!(*unchoked.last().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_fastest_upload() {
+        let start = Instant::now();
+        let mut choker = Choker::new(Box::new(FastestUpload { slots: 1 }));
+        let mut peers: Vec<PeerInfo> = (0..3).map(|id| peer(id, start)).collect();
+        choker.tick(start, &peers, false);
+
+        peers[1].downloaded = 1_000_000;
+        peers[2].uploaded = 10;
+        let unchoked = choker
+            .tick(start + REGULAR_INTERVAL, &peers, false)
+            .unwrap();
+        assert_eq!(unchoked[0], 2);
+    }
+}
@@ -1,28 +1,45 @@
-use crate::message::Message;
+use crate::bitfield::Bitfield;
+use crate::choker;
+use crate::message::{HashRequest, Message};
+use crate::mse::{self, Cipher, EncryptionPolicy};
+use crate::peer::PeerStream;
+use crate::ratelimit::{Limits, Throttled};
+use crate::transport::Transport;
+use crate::utp::UtpSocket;
 use crate::{torrent::TorrentFile, tracker::Peer};
 use byteorder::{BigEndian, WriteBytesExt};
+use log::{debug, info, trace};
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
 use std::io::{self, Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::string::FromUtf8Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Reserved byte and bit of peers that speak the v2 hash messages (BEP 52)
+const V2_BYTE: usize = 7;
+const V2_BIT: u8 = 0x10;
 
 #[derive(Debug)]
 pub struct Handshake {
     pstr: String,
+    reserved: [u8; 8],
     info_hash: Vec<u8>,
     peer_id: Vec<u8>,
-    stream: TcpStream,
 }
 
 impl Handshake {
-    fn new(stream: TcpStream, info_hash: Vec<u8>, peer_id: Vec<u8>) -> Handshake {
+    pub fn new(info_hash: Vec<u8>, peer_id: Vec<u8>) -> Handshake {
+        let mut reserved = [0; 8];
+        reserved[V2_BYTE] |= V2_BIT;
         Handshake {
             pstr: String::from("BitTorrent protocol"),
+            reserved,
             info_hash,
             peer_id,
-            stream,
         }
     }
 
@@ -31,27 +48,30 @@ impl Handshake {
 
         result.push(self.pstr.len() as u8);
         result.extend(self.pstr.as_bytes());
-        result.extend(&[0; 8]);
+        result.extend(&self.reserved);
         result.extend(&self.info_hash);
         result.extend(&self.peer_id);
 
         result
     }
 
-    fn check_response(&self, b: &[u8]) -> Result<(), Box<dyn Error>> {
+    // Returns the peer's reserved bytes
+    fn check_response(&self, b: &[u8]) -> Result<[u8; 8], Box<dyn Error>> {
         // Deserialize (mainly care about info hash)
         let pstr_len = 19;
         let pstr = String::from_utf8(b[1..pstr_len + 1].to_vec())?;
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&b[pstr_len + 1..pstr_len + 1 + 8]);
         let info_hash = b[pstr_len + 1 + 8..pstr_len + 1 + 8 + 20].to_vec();
         let peer_id = &b[pstr_len + 1 + 8 + 20..];
         let peer_id = peer_id.to_vec();
 
         if self.info_hash.eq(&info_hash) {
-            println!("Successful handshake.");
+            debug!("Successful handshake.");
 
-            Ok(())
+            Ok(reserved)
         } else {
-            println!(
+            debug!(
                 "Expected info_hash: {:?} but got {:?}",
                 self.info_hash, info_hash
             );
@@ -59,75 +79,610 @@ impl Handshake {
         }
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Returns the peer's reserved bytes
+    pub fn run(
+        &self,
+        stream: &mut dyn Transport,
+        mut cipher: Option<&mut Cipher>,
+    ) -> Result<[u8; 8], Box<dyn Error>> {
         // Initiate handshake
-        self.stream.write_all(&self.serialize().as_slice())?;
+        self.send(stream, cipher.as_deref_mut())?;
 
         // Receive and verify response
         let mut buf = [0; 68];
-        self.stream.read_exact(&mut buf)?;
-        self.check_response(&buf)?;
+        read_exact(stream, cipher, &mut buf)?;
+        self.check_response(&buf)
+    }
+
+    /// Async run for tokio streams, see peer.rs. Encryption isn't
+    /// supported there yet.
+    pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(), String> {
+        // Scoped so they don't clash with byteorder's write_u32
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        stream
+            .write_all(&self.serialize())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut buf = [0; 68];
+        stream
+            .read_exact(&mut buf)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.check_response(&buf)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
 
+    // Answer an incoming handshake, the start of which may have come along
+    // with the encrypted handshake
+    fn answer(
+        &self,
+        stream: &mut dyn Transport,
+        mut cipher: Option<&mut Cipher>,
+        initial: &[u8],
+    ) -> Result<[u8; 8], Box<dyn Error>> {
+        if initial.len() > 68 {
+            return Err("ERR: initial payload longer than a handshake".into());
+        }
+        let mut buf = initial.to_vec();
+        buf.resize(68, 0);
+        read_exact(stream, cipher.as_deref_mut(), &mut buf[initial.len()..])?;
+        let reserved = self.check_response(&buf)?;
+
+        self.send(stream, cipher)?;
+        Ok(reserved)
+    }
+
+    fn send(
+        &self,
+        stream: &mut dyn Transport,
+        cipher: Option<&mut Cipher>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut bytes = self.serialize();
+        if let Some(cipher) = cipher {
+            cipher.encrypt(&mut bytes);
+        }
+        stream.write_all(&bytes)?;
         Ok(())
     }
 }
 
+fn read_exact(
+    stream: &mut dyn Transport,
+    cipher: Option<&mut Cipher>,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    match cipher {
+        Some(cipher) => cipher.reader(stream).read_exact(buf),
+        None => stream.read_exact(buf),
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// Give up on peers that stall halfway through the handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Send a keepalive if we haven't sent anything for this long
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+// Drop peers that haven't sent anything, not even a keepalive, for this long
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_secs(180);
+// Drop connections where neither side has been interested for this long
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Why a connection was closed
+#[derive(Debug)]
+pub enum DisconnectReason {
+    Io(io::Error),
+    /// The peer sent something the protocol doesn't allow
+    ProtocolViolation(String),
+    /// The peer didn't send anything for RECEIVE_TIMEOUT
+    Timeout,
+    /// Neither side was interested for IDLE_TIMEOUT
+    Idle,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Io(e) => write!(f, "io error: {}", e),
+            DisconnectReason::ProtocolViolation(msg) => write!(f, "protocol violation: {}", msg),
+            DisconnectReason::Timeout => write!(f, "peer timed out"),
+            DisconnectReason::Idle => write!(f, "connection idle"),
+        }
+    }
+}
+
+impl Error for DisconnectReason {}
+
+impl From<io::Error> for DisconnectReason {
+    fn from(err: io::Error) -> DisconnectReason {
+        // Malformed messages are reported by Message::read as InvalidData
+        if err.kind() == io::ErrorKind::InvalidData {
+            DisconnectReason::ProtocolViolation(err.to_string())
+        } else {
+            DisconnectReason::Io(err)
+        }
+    }
+}
+
+// Counts the bytes read through it
+struct Counted<'a, R> {
+    inner: R,
+    count: &'a mut u64,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        *self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// A block the peer asked us to upload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
 #[derive(Debug)]
 pub struct Connection {
-    pub stream: TcpStream,
-    pub choked: bool,
+    pub stream: Box<dyn Transport>,
+    // The four flags of the peer wire protocol, all connections start out
+    // choked and not interested in both directions
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
     pub peer: Peer,
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>,
-    pub bitfield: Vec<u8>,
+    pub bitfield: Bitfield,
+    num_pieces: usize,
+    pub upload_queue: VecDeque<BlockRequest>,
+    // Both sides set the v2 bit, so hash messages may be exchanged
+    pub supports_v2: bool,
+    // Hash requests we're waiting on an answer for
+    pub hash_requests: Vec<HashRequest>,
+    // Blocks we asked for and haven't got yet
+    pub requests: Vec<BlockRequest>,
+    // Payload bytes transferred, fed to the choker
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Payload of blocks we never asked for, dropped unread
+    pub wasted: u64,
+    // Everything on the wire after the handshakes, payload included
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub connected_at: Instant,
+    pub last_block_received: Instant,
+    last_sent: Instant,
+    last_received: Instant,
+    // Last time either side was interested
+    last_interest: Instant,
+    received_any: bool,
+    // Set when the stream is RC4 encrypted
+    cipher: Option<Cipher>,
 }
 
 impl Connection {
-    // TODO: this should execute the handshake
     pub fn connect(
         peer: Peer,
         info_hash: Vec<u8>,
         peer_id: Vec<u8>,
+        num_pieces: usize,
+        encryption: EncryptionPolicy,
     ) -> Result<Connection, Box<dyn Error>> {
-        // Create TCP stream
+        // Create TCP stream, run by a task of its own
         let addr = SocketAddr::new(IpAddr::from(peer.ip), peer.port);
-        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(3))?;
+        let open = || -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(PeerStream::connect(addr, CONNECT_TIMEOUT)?))
+        };
+        Connection::establish(open, peer, info_hash, peer_id, num_pieces, encryption)
+    }
+
+    /// Like connect, over uTP from `socket`
+    pub fn connect_utp(
+        socket: &UtpSocket,
+        peer: Peer,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        num_pieces: usize,
+        encryption: EncryptionPolicy,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let addr = SocketAddr::new(IpAddr::from(peer.ip), peer.port);
+        let open = || -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(socket.connect(addr, CONNECT_TIMEOUT)?))
+        };
+        Connection::establish(open, peer, info_hash, peer_id, num_pieces, encryption)
+    }
+
+    // Handshake over a stream from `open`, which is called again for the
+    // plaintext fallback
+    fn establish<F: Fn() -> io::Result<Box<dyn Transport>>>(
+        open: F,
+        peer: Peer,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        num_pieces: usize,
+        encryption: EncryptionPolicy,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let open = || -> io::Result<Box<dyn Transport>> {
+            let stream = open()?;
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            Ok(stream)
+        };
+        let mut stream = open()?;
+
+        let mut cipher = None;
+        if encryption != EncryptionPolicy::Disabled {
+            match mse::initiate(&mut stream, &info_hash, encryption) {
+                Ok(selected) => cipher = selected,
+                // Peers that don't know encryption just hang up, try again
+                // in the clear
+                Err(e) if encryption == EncryptionPolicy::Prefer => {
+                    info!("encrypted handshake failed, retrying plaintext: {}", e);
+                    stream = open()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
         // Execute bittorrent handshake with peer
         // FIXME: cloning here is lame
-        Handshake::new(stream.try_clone()?, info_hash.clone(), peer_id.clone()).run()?;
+        let reserved = Handshake::new(info_hash.clone(), peer_id.clone())
+            .run(&mut *stream, cipher.as_mut())?;
+
+        // Don't hang forever on a peer that stops halfway through a message
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+        let mut conn = Connection::new(stream, peer, info_hash, peer_id, num_pieces);
+        conn.cipher = cipher;
+        conn.supports_v2 = reserved[V2_BYTE] & V2_BIT != 0;
+        Ok(conn)
+    }
 
-        // Receive bitfield
-        let msg = Message::read(&stream).unwrap();
-        let bitfield = match msg {
-            Message::Bitfield(bitfield) => bitfield,
-            _ => panic!("BAD BAD BAD"),
+    /// Take an incoming connection for the torrent with `info_hash`. Whether
+    /// it may be encrypted, or has to be, depends on `encryption`.
+    pub fn accept(
+        stream: Box<dyn Transport>,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        num_pieces: usize,
+        encryption: EncryptionPolicy,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let torrents = [(info_hash, num_pieces)];
+        Connection::accept_any(stream, &torrents, peer_id, encryption).map(|(_, conn)| conn)
+    }
+
+    /// Like accept, for whichever of `torrents` (info hash and number of
+    /// pieces) the peer asks for. Returns its index with the connection.
+    pub fn accept_any(
+        mut stream: Box<dyn Transport>,
+        torrents: &[(Vec<u8>, usize)],
+        peer_id: Vec<u8>,
+        encryption: EncryptionPolicy,
+    ) -> Result<(usize, Connection), Box<dyn Error>> {
+        let peer = match stream.peer_addr()? {
+            SocketAddr::V4(addr) => Peer {
+                ip: *addr.ip(),
+                port: addr.port(),
+            },
+            addr => return Err(format!("ERR: unsupported peer address {}", addr).into()),
+        };
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        // A plaintext handshake names the torrent, an encrypted one proves
+        // which it's for
+        let info_hashes: Vec<Vec<u8>> = torrents.iter().map(|t| t.0.clone()).collect();
+        let (index, mut cipher, initial) = match (mse::is_plaintext(&*stream)?, encryption) {
+            (true, EncryptionPolicy::Require) => {
+                return Err("ERR: plaintext connection refused".into())
+            }
+            (true, _) => {
+                let mut initial = vec![0; 68];
+                stream.read_exact(&mut initial)?;
+                let index = info_hashes
+                    .iter()
+                    .position(|info_hash| info_hash[..] == initial[28..48])
+                    .ok_or("ERR: Not serving that torrent")?;
+                (index, None, initial)
+            }
+            (false, EncryptionPolicy::Disabled) => {
+                return Err("ERR: encrypted connection refused".into())
+            }
+            (false, _) => mse::respond_any(&mut stream, &info_hashes, encryption)?,
         };
+        let (info_hash, num_pieces) = torrents[index].clone();
+        let reserved = Handshake::new(info_hash.clone(), peer_id.clone()).answer(
+            &mut *stream,
+            cipher.as_mut(),
+            &initial,
+        )?;
+
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
 
-        // Instantiate connection
-        Ok(Connection {
+        let mut conn = Connection::new(stream, peer, info_hash, peer_id, num_pieces);
+        conn.cipher = cipher;
+        conn.supports_v2 = reserved[V2_BYTE] & V2_BIT != 0;
+        Ok((index, conn))
+    }
+
+    /// Charge the connection's traffic to `scopes`, e.g. global, torrent
+    /// and peer limits
+    pub fn throttle(self, scopes: Vec<Limits>) -> Connection {
+        Connection {
+            stream: Box::new(Throttled::new(self.stream, scopes)),
+            ..self
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    // The peer's bitfield, if any, is the first message read after this
+    fn new(
+        stream: Box<dyn Transport>,
+        peer: Peer,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        num_pieces: usize,
+    ) -> Connection {
+        let now = Instant::now();
+        Connection {
             stream,
-            choked: true,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
             peer,
             info_hash,
             peer_id,
-            bitfield,
-        })
+            bitfield: Bitfield::new(num_pieces),
+            num_pieces,
+            upload_queue: VecDeque::new(),
+            supports_v2: false,
+            hash_requests: vec![],
+            requests: vec![],
+            downloaded: 0,
+            uploaded: 0,
+            wasted: 0,
+            received_bytes: 0,
+            sent_bytes: 0,
+            connected_at: now,
+            last_block_received: now,
+            last_sent: now,
+            last_received: now,
+            last_interest: now,
+            received_any: false,
+            cipher: None,
+        }
     }
 
-    pub fn send_unchoke(&mut self) -> Result<(), Box<dyn Error>> {
-        let msg = Message::Unchoke;
-        let payload = vec![];
-        let bytes = msg.serialize(&payload);
+    /// Whether the peer has sent bytes we haven't read yet. Used to process
+    /// incoming messages (e.g. cancels) before uploading queued blocks.
+    pub fn has_pending_input(&self) -> io::Result<bool> {
+        self.stream.wait_readable(Duration::from_secs(0))
+    }
+
+    /// Wait up to `timeout` for the peer to send something
+    pub fn wait_for_input(&self, timeout: Duration) -> io::Result<bool> {
+        self.stream.wait_readable(timeout)
+    }
+
+    /// Read the next message and apply it to the connection state. Messages
+    /// that only matter to the torrent (requests, pieces) are passed through,
+    /// None means it was dropped, e.g. a block we never asked for.
+    pub fn read_message(&mut self) -> Result<Option<Message>, DisconnectReason> {
+        let msg = self.next_message()?;
+        match self.handle(&msg)? {
+            true => Ok(Some(msg)),
+            false => Ok(None),
+        }
+    }
+
+    fn next_message(&mut self) -> io::Result<Message> {
+        let count = &mut self.received_bytes;
+        match self.cipher.as_mut() {
+            Some(cipher) => Message::read(Counted {
+                inner: cipher.reader(&mut self.stream),
+                count,
+            }),
+            None => Message::read(Counted {
+                inner: &mut self.stream,
+                count,
+            }),
+        }
+    }
+
+    // Whether the message is passed on
+    fn handle(&mut self, msg: &Message) -> Result<bool, DisconnectReason> {
+        let now = Instant::now();
+        let first = !self.received_any;
+        self.received_any = true;
+        self.last_received = now;
+
+        match msg {
+            Message::KeepAlive => {}
+            Message::Choke => {
+                // Outstanding requests are discarded by the peer
+                self.peer_choking = true;
+                self.requests.clear();
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                // Only start the snub clock once blocks can arrive
+                self.last_block_received = now;
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                self.last_interest = now;
+            }
+            Message::NotInterested => self.peer_interested = false,
+            Message::Have(index) => {
+                if *index as usize >= self.num_pieces {
+                    return Err(DisconnectReason::ProtocolViolation(format!(
+                        "have for piece {} of {}",
+                        index, self.num_pieces
+                    )));
+                }
+                self.bitfield.set_piece(*index as usize);
+            }
+            Message::Bitfield(bytes) => {
+                if !first {
+                    return Err(DisconnectReason::ProtocolViolation(
+                        "bitfield after first message".to_string(),
+                    ));
+                }
+                let bitfield = Bitfield::from_bytes(bytes.clone());
+                if !bitfield.is_valid(self.num_pieces) {
+                    return Err(DisconnectReason::ProtocolViolation(format!(
+                        "bitfield of {} bytes for {} pieces",
+                        bytes.len(),
+                        self.num_pieces
+                    )));
+                }
+                self.bitfield = bitfield;
+            }
+            Message::Request(_, _, _) => {}
+            Message::Cancel(index, begin, length) => self.cancel_request(BlockRequest {
+                index: *index,
+                begin: *begin,
+                length: *length,
+            }),
+            Message::Piece(index, begin, data) => {
+                let request = BlockRequest {
+                    index: *index,
+                    begin: *begin,
+                    length: data.len() as u32,
+                };
+                match self.requests.iter().position(|r| *r == request) {
+                    Some(i) => {
+                        self.requests.remove(i);
+                        self.downloaded += data.len() as u64;
+                        self.last_block_received = now;
+                    }
+                    None => {
+                        self.wasted += data.len() as u64;
+                        return Ok(false);
+                    }
+                }
+            }
+            Message::HashRequest(_) | Message::Hashes(..) | Message::HashReject(_)
+                if !self.supports_v2 =>
+            {
+                return Err(DisconnectReason::ProtocolViolation(
+                    "hash message without v2 support".to_string(),
+                ))
+            }
+            Message::HashRequest(_) => {}
+            Message::Hashes(req, _) | Message::HashReject(req) => {
+                match self.hash_requests.iter().position(|r| r == req) {
+                    Some(i) => {
+                        self.hash_requests.remove(i);
+                    }
+                    None => {
+                        return Err(DisconnectReason::ProtocolViolation(
+                            "answer to a hash request we didn't send".to_string(),
+                        ))
+                    }
+                }
+                if let Message::Hashes(req, hashes) = msg {
+                    if hashes.len() < req.length as usize {
+                        return Err(DisconnectReason::ProtocolViolation(format!(
+                            "{} hashes for a request of {}",
+                            hashes.len(),
+                            req.length
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Run the connection timers: send keepalives and close connections that
+    /// went quiet.
+    pub fn tick(&mut self, now: Instant) -> Result<(), DisconnectReason> {
+        if self.am_interested || self.peer_interested {
+            self.last_interest = now;
+        }
+        if now.duration_since(self.last_received) >= RECEIVE_TIMEOUT {
+            return Err(DisconnectReason::Timeout);
+        }
+        if now.duration_since(self.last_interest) >= IDLE_TIMEOUT {
+            return Err(DisconnectReason::Idle);
+        }
+        if now.duration_since(self.last_sent) >= KEEPALIVE_INTERVAL {
+            self.send(Message::KeepAlive, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// The peer unchoked us and we want blocks, but none arrived lately
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        self.am_interested
+            && !self.peer_choking
+            && now.duration_since(self.last_block_received) >= choker::SNUB_TIMEOUT
+    }
+
+    pub fn queue_request(&mut self, request: BlockRequest) {
+        // Requests received while choking are dropped, the peer must re-send
+        // them once unchoked
+        if self.am_choking || self.upload_queue.contains(&request) {
+            return;
+        }
+        self.upload_queue.push_back(request);
+    }
+
+    pub fn cancel_request(&mut self, request: BlockRequest) {
+        self.upload_queue.retain(|r| *r != request);
+    }
+
+    fn send(&mut self, msg: Message, payload: &[u8]) -> io::Result<()> {
+        let mut bytes = msg.serialize(payload);
+        if let Some(cipher) = self.cipher.as_mut() {
+            cipher.encrypt(&mut bytes);
+        }
         self.stream.write_all(&bytes)?;
+        self.sent_bytes += bytes.len() as u64;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    pub fn send_choke(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(Message::Choke, &[])?;
+        self.am_choking = true;
+        self.upload_queue.clear();
+        Ok(())
+    }
+
+    pub fn send_unchoke(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(Message::Unchoke, &[])?;
+        self.am_choking = false;
         Ok(())
     }
 
     pub fn send_interested(&mut self) -> Result<(), Box<dyn Error>> {
-        let msg = Message::Interested;
-        let payload = vec![];
-        let bytes = msg.serialize(&payload);
-        self.stream.write_all(&bytes)?;
+        self.send(Message::Interested, &[])?;
+        self.am_interested = true;
+        self.last_block_received = Instant::now();
+        Ok(())
+    }
+
+    pub fn send_not_interested(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(Message::NotInterested, &[])?;
+        self.am_interested = false;
         Ok(())
     }
 
@@ -143,8 +698,67 @@ impl Connection {
         payload.write_u32::<BigEndian>(index)?;
         payload.write_u32::<BigEndian>(requested)?;
         payload.write_u32::<BigEndian>(block_size)?;
-        let bytes = msg.serialize(&payload);
-        self.stream.write_all(&bytes)?;
+        self.send(msg, &payload)?;
+        self.requests.push(BlockRequest {
+            index,
+            begin: requested,
+            length: block_size,
+        });
+        Ok(())
+    }
+
+    pub fn send_have(&mut self, index: u32) -> Result<(), Box<dyn Error>> {
+        let msg = Message::Have(index);
+        let mut payload = vec![];
+        payload.write_u32::<BigEndian>(index)?;
+        self.send(msg, &payload)?;
+        Ok(())
+    }
+
+    pub fn send_bitfield(&mut self, bitfield: &Bitfield) -> Result<(), Box<dyn Error>> {
+        let msg = Message::Bitfield(bitfield.as_bytes().to_vec());
+        self.send(msg, bitfield.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn send_piece(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut payload = vec![];
+        payload.write_u32::<BigEndian>(index)?;
+        payload.write_u32::<BigEndian>(begin)?;
+        payload.extend(block);
+        let msg = Message::Piece(index, begin, vec![]);
+        self.send(msg, &payload)?;
+        self.uploaded += block.len() as u64;
+        Ok(())
+    }
+
+    pub fn send_hash_request(&mut self, req: HashRequest) -> Result<(), Box<dyn Error>> {
+        if !self.supports_v2 {
+            return Err("ERR: Peer doesn't support v2 hash messages".into());
+        }
+        self.send(Message::HashRequest(req), &req.to_bytes())?;
+        self.hash_requests.push(req);
+        Ok(())
+    }
+
+    pub fn send_hashes(
+        &mut self,
+        req: &HashRequest,
+        hashes: &[[u8; 32]],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut payload = req.to_bytes();
+        payload.extend(hashes.concat());
+        self.send(Message::Hashes(*req, vec![]), &payload)?;
+        Ok(())
+    }
+
+    pub fn send_hash_reject(&mut self, req: &HashRequest) -> Result<(), Box<dyn Error>> {
+        self.send(Message::HashReject(*req), &req.to_bytes())?;
         Ok(())
     }
 
@@ -155,9 +769,9 @@ impl Connection {
 
         // Wait for unchoke
         loop {
-            let msg = Message::read(&self.stream)?;
+            let msg = self.next_message()?;
             if let Message::Unchoke = msg {
-                println!("Unchoked");
+                debug!("Unchoked");
                 break;
             }
         }
@@ -171,12 +785,12 @@ impl Connection {
         loop {
             // Request piece
             self.send_request(index, requested, block_size)?;
-            println!("send request #{}", index);
+            trace!("send request #{}", index);
 
             // Receive next piece
             loop {
-                let msg = Message::read(&self.stream)?;
-                println!("new msg: {:?}", msg);
+                let msg = self.next_message()?;
+                trace!("new msg: {:?}", msg);
 
                 if let Message::Piece(_, _, _) = msg {
                     // FIXME: increment requested
@@ -197,7 +811,9 @@ mod tests {
     use crate::tracker::request_peers;
     use env_logger;
     use rand::Rng;
+    use std::net::TcpStream;
     use std::path::Path;
+    use std::thread;
 
     #[test]
     pub fn test_connection() {
@@ -211,9 +827,375 @@ mod tests {
 
         // connect to the first peer
         let peer = peers_response.peers[2].clone();
-        let mut conn = Connection::connect(peer, torrent.info_hash, peer_id).unwrap();
+        let num_pieces = torrent.num_pieces();
+        let mut conn = Connection::connect(
+            peer,
+            torrent.info_hash,
+            peer_id,
+            num_pieces,
+            EncryptionPolicy::Disabled,
+        )
+        .unwrap();
 
         // download chunks
         conn.download().unwrap();
     }
+
+    // Returns the connection along with the remote end of its stream
+    fn local_connection() -> (Connection, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port: 6881,
+        };
+        let conn = Connection::new(Box::new(stream), peer, vec![0; 20], vec![0; 20], 12);
+        (conn, remote)
+    }
+
+    #[test]
+    pub fn test_upload_queue() {
+        let (mut conn, _remote) = local_connection();
+        let request = BlockRequest {
+            index: 1,
+            begin: 0,
+            length: 16384,
+        };
+
+        // Ignored while choking
+        conn.queue_request(request);
+        assert!(conn.upload_queue.is_empty());
+
+        // Queued once unchoked, duplicates dropped
+        conn.send_unchoke().unwrap();
+        conn.queue_request(request);
+        conn.queue_request(request);
+        assert_eq!(conn.upload_queue.len(), 1);
+
+        // Cancelled
+        conn.cancel_request(request);
+        assert!(conn.upload_queue.is_empty());
+
+        // Choking drops anything pending
+        conn.queue_request(request);
+        conn.send_choke().unwrap();
+        assert!(conn.upload_queue.is_empty());
+        assert!(!conn.has_pending_input().unwrap());
+    }
+
+    fn send_raw(remote: &mut TcpStream, id: u8, payload: &[u8]) {
+        let mut bytes = vec![0, 0, 0, payload.len() as u8 + 1, id];
+        bytes.extend(payload);
+        remote.write_all(&bytes).unwrap();
+    }
+
+    #[test]
+    pub fn test_state_machine() {
+        let (mut conn, mut remote) = local_connection();
+        assert!(conn.am_choking && conn.peer_choking);
+        assert!(!conn.am_interested && !conn.peer_interested);
+
+        // Bitfield is accepted as the first message only
+        send_raw(&mut remote, 5, &[0b1000_0000, 0b0001_0000]);
+        conn.read_message().unwrap();
+        assert!(conn.bitfield.has_piece(0) && conn.bitfield.has_piece(11));
+
+        send_raw(&mut remote, 4, &[0, 0, 0, 3]);
+        send_raw(&mut remote, 1, &[]);
+        send_raw(&mut remote, 2, &[]);
+        for _ in 0..3 {
+            conn.read_message().unwrap();
+        }
+        assert!(conn.bitfield.has_piece(3));
+        assert!(!conn.peer_choking && conn.peer_interested);
+        assert_eq!(conn.received_bytes, 7 + 9 + 5 + 5);
+        conn.send_have(2).unwrap();
+        assert_eq!(conn.sent_bytes, 9);
+
+        send_raw(&mut remote, 5, &[0, 0]);
+        match conn.read_message() {
+            Err(DisconnectReason::ProtocolViolation(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_unrequested_blocks() {
+        let (mut conn, mut remote) = local_connection();
+        let block = [0, 0, 0, 1, 0, 0, 0, 0, 9, 9, 9, 9];
+        send_raw(&mut remote, 7, &block);
+        assert!(conn.read_message().unwrap().is_none());
+        assert_eq!((conn.downloaded, conn.wasted), (0, 4));
+
+        conn.send_request(1, 0, 4).unwrap();
+        send_raw(&mut remote, 7, &block);
+        assert!(matches!(
+            conn.read_message().unwrap(),
+            Some(Message::Piece(1, 0, _))
+        ));
+        assert_eq!((conn.downloaded, conn.wasted), (4, 4));
+        assert!(conn.requests.is_empty());
+
+        // Choking discards what we asked for
+        conn.send_request(1, 0, 4).unwrap();
+        send_raw(&mut remote, 0, &[]);
+        send_raw(&mut remote, 7, &block);
+        conn.read_message().unwrap();
+        assert!(conn.read_message().unwrap().is_none());
+        assert_eq!((conn.downloaded, conn.wasted), (4, 8));
+    }
+
+    #[test]
+    pub fn test_protocol_violations() {
+        // Have past the last piece
+        let (mut conn, mut remote) = local_connection();
+        send_raw(&mut remote, 4, &[0, 0, 0, 12]);
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+
+        // Spare bits set in the bitfield
+        let (mut conn, mut remote) = local_connection();
+        send_raw(&mut remote, 5, &[0, 0b0000_1000]);
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+
+        // Unknown message id
+        let (mut conn, mut remote) = local_connection();
+        send_raw(&mut remote, 99, &[]);
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_hash_requests() {
+        let req = HashRequest {
+            pieces_root: [1; 32],
+            base_layer: 2,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+        // Not without the v2 bit on both sides
+        let (mut conn, mut remote) = local_connection();
+        assert!(conn.send_hash_request(req).is_err());
+        send_raw(&mut remote, 21, &req.to_bytes());
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+
+        let (mut conn, mut remote) = local_connection();
+        conn.supports_v2 = true;
+        conn.send_hash_request(req).unwrap();
+        let mut buf = [0; 53];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[4], 21);
+        assert_eq!(&buf[5..], &req.to_bytes()[..]);
+
+        // Too few hashes
+        let mut payload = req.to_bytes();
+        payload.extend(&[0; 32]);
+        send_raw(&mut remote, 22, &payload);
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+
+        let (mut conn, mut remote) = local_connection();
+        conn.supports_v2 = true;
+        conn.send_hash_request(req).unwrap();
+        send_raw(&mut remote, 23, &req.to_bytes());
+        conn.read_message().unwrap();
+        assert!(conn.hash_requests.is_empty());
+
+        // Nothing outstanding any more
+        payload.extend(&[0; 32]);
+        send_raw(&mut remote, 22, &payload);
+        assert!(matches!(
+            conn.read_message(),
+            Err(DisconnectReason::ProtocolViolation(_))
+        ));
+    }
+
+    // Accept up to `attempts` connections on a local port until one gets
+    // through the handshakes
+    fn accept_local(
+        attempts: usize,
+        encryption: EncryptionPolicy,
+    ) -> (Peer, thread::JoinHandle<Result<Connection, String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let handle = thread::spawn(move || {
+            let mut result = Err("no connection".to_string());
+            for stream in listener.incoming().take(attempts) {
+                result = Connection::accept(
+                    Box::new(stream.unwrap()),
+                    vec![3; 20],
+                    vec![4; 20],
+                    12,
+                    encryption,
+                )
+                .map_err(|e| e.to_string());
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        });
+        (peer, handle)
+    }
+
+    #[test]
+    pub fn test_encryption() {
+        let (peer, remote) = accept_local(1, EncryptionPolicy::Require);
+        let mut conn =
+            Connection::connect(peer, vec![3; 20], vec![5; 20], 12, EncryptionPolicy::Prefer)
+                .unwrap();
+        let mut remote = remote.join().unwrap().unwrap();
+        assert!(conn.is_encrypted() && remote.is_encrypted());
+        assert!(conn.supports_v2 && remote.supports_v2);
+        assert_eq!(remote.peer.ip, conn.peer.ip);
+
+        conn.send_have(7).unwrap();
+        assert!(matches!(
+            remote.read_message().unwrap(),
+            Some(Message::Have(7))
+        ));
+        remote.send_interested().unwrap();
+        conn.read_message().unwrap();
+        assert!(conn.peer_interested);
+
+        // Falls back to plaintext when the peer hangs up on encryption
+        let (peer, remote) = accept_local(2, EncryptionPolicy::Disabled);
+        let conn =
+            Connection::connect(peer, vec![3; 20], vec![5; 20], 12, EncryptionPolicy::Prefer)
+                .unwrap();
+        assert!(!conn.is_encrypted());
+        assert!(!remote.join().unwrap().unwrap().is_encrypted());
+
+        // No fallback when encryption is required
+        let (peer, remote) = accept_local(1, EncryptionPolicy::Disabled);
+        assert!(Connection::connect(
+            peer,
+            vec![3; 20],
+            vec![5; 20],
+            12,
+            EncryptionPolicy::Require
+        )
+        .is_err());
+        assert!(remote.join().unwrap().is_err());
+    }
+
+    #[test]
+    pub fn test_utp() {
+        let socket = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let remote = thread::spawn(move || {
+            let stream = listener.accept().unwrap();
+            Connection::accept(
+                Box::new(stream),
+                vec![3; 20],
+                vec![4; 20],
+                12,
+                EncryptionPolicy::Prefer,
+            )
+            .unwrap()
+        });
+        let mut conn = Connection::connect_utp(
+            &socket,
+            peer,
+            vec![3; 20],
+            vec![5; 20],
+            12,
+            EncryptionPolicy::Prefer,
+        )
+        .unwrap();
+        let mut remote = remote.join().unwrap();
+        assert!(conn.is_encrypted() && remote.is_encrypted());
+
+        conn.send_have(7).unwrap();
+        assert!(remote.wait_for_input(Duration::from_secs(5)).unwrap());
+        assert!(matches!(
+            remote.read_message().unwrap(),
+            Some(Message::Have(7))
+        ));
+        remote.send_interested().unwrap();
+        conn.read_message().unwrap();
+        assert!(conn.peer_interested);
+        assert!(!conn.has_pending_input().unwrap());
+    }
+
+    #[test]
+    pub fn test_throttle() {
+        let (conn, remote) = local_connection();
+        let limits = Limits::new(100_000, 0);
+        let mut conn = conn.throttle(vec![Limits::default(), limits.clone()]);
+
+        // 48 KB less the burst at 100 KB/s
+        let start = Instant::now();
+        for begin in 0..3 {
+            conn.send_piece(0, begin * 16384, &[1; 16384]).unwrap();
+        }
+        assert!(start.elapsed() > Duration::from_millis(250));
+        for _ in 0..3 {
+            assert!(matches!(
+                Message::read(&remote).unwrap(),
+                Message::Piece(0, _, _)
+            ));
+        }
+
+        // Lifted at runtime
+        limits.upload.set_rate(0);
+        let start = Instant::now();
+        for begin in 0..3 {
+            conn.send_piece(1, begin * 16384, &[1; 16384]).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    pub fn test_timers() {
+        let (mut conn, mut remote) = local_connection();
+
+        // Keepalive once we've been quiet for a while
+        conn.send_interested().unwrap();
+        let start = Instant::now();
+        conn.tick(start + KEEPALIVE_INTERVAL).unwrap();
+        let mut buf = [0; 9];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[5..], &[0, 0, 0, 0]);
+
+        // Snubbed once unchoked without blocks arriving
+        assert!(!conn.is_snubbed(start));
+        send_raw(&mut remote, 1, &[]);
+        conn.read_message().unwrap();
+        assert!(!conn.is_snubbed(Instant::now()));
+        assert!(conn.is_snubbed(Instant::now() + choker::SNUB_TIMEOUT));
+
+        // Silent peers time out
+        assert!(matches!(
+            conn.tick(Instant::now() + RECEIVE_TIMEOUT),
+            Err(DisconnectReason::Timeout)
+        ));
+
+        // Nobody interested for too long
+        let (mut conn, _remote) = local_connection();
+        let now = Instant::now() + IDLE_TIMEOUT;
+        conn.last_received = now;
+        assert!(matches!(conn.tick(now), Err(DisconnectReason::Idle)));
+    }
 }
@@ -0,0 +1,326 @@
+// Making .torrent files from data on disk.
+//
+// The info dict is built from the same types TorrentFile parses, so a
+// created torrent opens with the info hash it was written with. Pieces are
+// hashed on every core, see verify::hash_pieces.
+
+use crate::storage::{FileStorage, StorageFile};
+use crate::torrent::{BencodeFile, BencodeInfo, FileAttr};
+use crate::verify::hash_pieces;
+use glob::Pattern;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+// Automatic piece lengths aim for about this many pieces
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // Picked from the total size when not set
+    pub piece_length: Option<u64>,
+    // Tracker tiers, the first tracker is also written as announce
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Unix time
+    pub creation_date: Option<u64>,
+    pub private: bool,
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+    // Globs matched against both the path relative to the root and the
+    // file name. Excluding a directory excludes everything in it.
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+    announce_list: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<u64>,
+    info: BencodeInfo,
+    #[serde(rename = "url-list", skip_serializing_if = "Vec::is_empty")]
+    url_list: Vec<String>,
+}
+
+// A file to add, with its path inside the torrent
+struct Entry {
+    components: Vec<String>,
+    path: PathBuf,
+    length: u64,
+}
+
+/// Power of two piece length giving a reasonable number of pieces
+pub fn piece_length_for(total: u64) -> u64 {
+    (total / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Make a torrent of the file or directory at `root`, returning the bencoded
+/// .torrent contents
+pub fn create(root: &Path, options: &CreateOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let name = root
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(format!("{} has no usable name", root.display()))?
+        .to_string();
+    let excludes = options
+        .exclude
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let single = fs::metadata(root)?.is_file();
+    let mut entries = vec![];
+    if single {
+        entries.push(Entry {
+            components: vec![],
+            path: root.to_path_buf(),
+            length: fs::metadata(root)?.len(),
+        });
+    } else {
+        walk(root, &[], &excludes, &mut entries)?;
+    }
+
+    let total: u64 = entries.iter().map(|e| e.length).sum();
+    if total == 0 {
+        return Err(format!("nothing to add under {}", root.display()).into());
+    }
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| piece_length_for(total));
+    if piece_length == 0 {
+        return Err("piece length must not be zero".into());
+    }
+
+    let mut offset = 0;
+    let layout: Vec<StorageFile> = entries
+        .iter()
+        .map(|e| {
+            let file = StorageFile {
+                path: e.path.clone(),
+                offset,
+                length: e.length,
+                attr: FileAttr::default(),
+            };
+            offset += e.length;
+            file
+        })
+        .collect();
+
+    let num_pieces = total.div_ceil(piece_length) as usize;
+    let mut hashes = vec![[0; 20]; num_pieces];
+    let mut error = None;
+    hash_pieces(
+        || FileStorage::from_layout(layout.clone(), piece_length),
+        num_pieces,
+        |index, hash| match hash {
+            Ok(hash) => hashes[index] = hash,
+            Err(e) => error = Some(e),
+        },
+    );
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    let info = BencodeInfo {
+        name,
+        length: if single { Some(total) } else { None },
+        files: if single {
+            None
+        } else {
+            Some(
+                entries
+                    .into_iter()
+                    .map(|e| BencodeFile {
+                        length: e.length,
+                        path: e.components,
+                        attr: None,
+                        symlink_path: None,
+                    })
+                    .collect(),
+            )
+        },
+        attr: None,
+        piece_length,
+        pieces: Some(ByteBuf::from(hashes.concat())),
+        meta_version: None,
+        file_tree: None,
+        private: if options.private { Some(1) } else { None },
+        source: options.source.clone(),
+    };
+
+    let trackers: Vec<Vec<String>> = options
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+    let meta = MetaInfo {
+        announce: trackers.first().map(|tier| tier[0].clone()),
+        // Only worth writing when there's more than the one tracker
+        announce_list: if trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+            trackers
+        } else {
+            vec![]
+        },
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: options.creation_date,
+        info,
+        url_list: options.web_seeds.clone(),
+    };
+
+    Ok(serde_bencode::to_bytes(&meta)?)
+}
+
+// Collect the files under dir in a stable order, skipping excluded ones
+fn walk(
+    dir: &Path,
+    prefix: &[String],
+    excludes: &[Pattern],
+    entries: &mut Vec<Entry>,
+) -> Result<(), Box<dyn Error>> {
+    let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let name = child
+            .file_name()
+            .into_string()
+            .map_err(|name| format!("{:?} is not valid UTF-8", name))?;
+        let mut components = prefix.to_vec();
+        components.push(name.clone());
+        let relative = components.join("/");
+        if excludes
+            .iter()
+            .any(|pattern| pattern.matches(&relative) || pattern.matches(&name))
+        {
+            continue;
+        }
+
+        let path = child.path();
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            walk(&path, &components, excludes, entries)?;
+        } else {
+            entries.push(Entry {
+                components,
+                path,
+                length: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::TorrentFile;
+    use crate::tracker::PeerSource;
+    use crate::verify::verify;
+    use sha1::{Digest, Sha1};
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_piece_length_for() {
+        assert_eq!(piece_length_for(0), MIN_PIECE_LENGTH);
+        assert_eq!(piece_length_for(4 << 30), 4 << 20);
+        assert_eq!(piece_length_for(1 << 50), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_create_round_trip() {
+        let dir = env::temp_dir().join(format!("bittorrent-create-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("release");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/app"), vec![7; 40_000]).unwrap();
+        fs::write(root.join("README"), b"hello").unwrap();
+        fs::write(root.join("build.log"), b"noise").unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+
+        let options = CreateOptions {
+            piece_length: Some(1 << 14),
+            trackers: vec![
+                vec!["http://a.example/announce".to_string()],
+                vec!["http://b.example/announce".to_string()],
+            ],
+            comment: Some("nightly".to_string()),
+            creation_date: Some(1_600_000_000),
+            private: true,
+            source: Some("builds".to_string()),
+            exclude: vec!["*.log".to_string()],
+            ..CreateOptions::default()
+        };
+        let bytes = create(&root, &options).unwrap();
+        let path = dir.join("release.torrent");
+        fs::write(&path, &bytes).unwrap();
+
+        let torrent = TorrentFile::open(&path).unwrap();
+        assert_eq!(torrent.announce, "http://a.example/announce");
+        assert_eq!(torrent.num_pieces(), 3);
+        assert!(torrent.private);
+        assert!(torrent.allows_peer_source(PeerSource::Tracker));
+        assert!(!torrent.allows_peer_source(PeerSource::Dht));
+        assert!(!torrent.allows_peer_source(PeerSource::Pex));
+        let paths: Vec<String> = torrent.files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(
+            paths,
+            ["release/README", "release/bin/app", "release/empty"]
+        );
+
+        // The info dict is the last key here, hash it as written
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let info = &bytes[start..bytes.len() - 1];
+        assert_eq!(torrent.info_hash, Sha1::digest(info).to_vec());
+
+        let have = verify(&torrent, &dir, |_| {});
+        assert!((0..torrent.num_pieces()).all(|i| have.has_piece(i)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_single_file() {
+        let dir = env::temp_dir().join(format!("bittorrent-create-single-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("image.iso");
+        fs::write(&file, vec![1; 100_000]).unwrap();
+
+        let options = CreateOptions {
+            web_seeds: vec!["http://mirror.example/image.iso".to_string()],
+            ..CreateOptions::default()
+        };
+        let bytes = create(&file, &options).unwrap();
+        let path = dir.join("image.torrent");
+        fs::write(&path, &bytes).unwrap();
+
+        let torrent = TorrentFile::open(&path).unwrap();
+        assert_eq!(torrent.name(), "image.iso");
+        assert_eq!(torrent.announce, "");
+        assert_eq!(torrent.piece_length(), MIN_PIECE_LENGTH);
+        assert_eq!(torrent.length, 100_000);
+        assert!(!torrent.private);
+        assert!(torrent.allows_peer_source(PeerSource::Lsd));
+        let have = verify(&torrent, &dir, |_| {});
+        assert!((0..torrent.num_pieces()).all(|i| have.has_piece(i)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,788 @@
+// Mainline DHT (BEP 5): a Kademlia node over UDP that finds peers for an
+// info hash without asking a tracker.
+//
+// Nodes that answered or queried us are kept in buckets by how many leading
+// bits their id shares with ours, at most K per bucket. A search walks
+// towards its target, asking the closest nodes it knows of that haven't
+// been asked yet, ALPHA at a time, for peers and for nodes closer still.
+// Once the K closest that are still around have all answered it's done,
+// and a get_peers search announces us to them with the tokens they gave.
+//
+// Other nodes' ping, find_node, get_peers and announce_peer are answered
+// too. A token is a hash of the asker's IP and a secret that changes every
+// few minutes, tokens from the previous secret still count.
+
+use crate::tracker::Peer;
+use log::debug;
+use rand::{self, Rng};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Nodes per bucket, and how many of the closest a search settles on
+const K: usize = 8;
+// Queries a search has out at once
+const ALPHA: usize = 3;
+// Nodes a search keeps in mind, closest first
+const MAX_CANDIDATES: usize = 64;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(4);
+// Nodes in a full bucket that haven't been heard from for this long make
+// way for new ones
+const STALE: Duration = Duration::from_secs(15 * 60);
+// How long the background thread waits for packets before running timers
+const TICK: Duration = Duration::from_millis(250);
+// Tokens are good for one to two of these
+const SECRET_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Peers announced to us are forgotten after this, unless announced again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// Peers kept per info hash, and handed out in one answer
+const MAX_PEERS: usize = 100;
+const MAX_VALUES: usize = 50;
+
+type Id = [u8; 20];
+type Dict = HashMap<Vec<u8>, Value>;
+// KRPC error code and message
+type KrpcError = (i64, &'static str);
+
+fn distance(a: &Id, b: &Id) -> Id {
+    let mut d = [0; 20];
+    for (d, (a, b)) in d.iter_mut().zip(a.iter().zip(b)) {
+        *d = a ^ b;
+    }
+    d
+}
+
+// The number of leading bits `id` shares with `own`
+fn bucket_index(own: &Id, id: &Id) -> usize {
+    let d = distance(own, id);
+    d.iter()
+        .position(|&b| b != 0)
+        .map_or(159, |i| (i * 8 + d[i].leading_zeros() as usize).min(159))
+}
+
+// Info hashes are searched by their first 20 bytes, which for v2 ones is
+// the truncated hash (BEP 52)
+fn target(info_hash: &[u8]) -> Option<Id> {
+    Id::try_from(info_hash.get(..20)?).ok()
+}
+
+fn compact_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut bytes = addr.ip().octets().to_vec();
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+fn parse_peer(bytes: &[u8]) -> SocketAddrV4 {
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    SocketAddrV4::new(ip, u16::from_be_bytes([bytes[4], bytes[5]]))
+}
+
+fn compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for node in nodes {
+        bytes.extend_from_slice(&node.id);
+        bytes.extend(compact_peer(&node.addr));
+    }
+    bytes
+}
+
+fn parse_nodes(bytes: &[u8]) -> Vec<(Id, SocketAddrV4)> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| {
+            (
+                Id::try_from(&chunk[..20]).unwrap(),
+                parse_peer(&chunk[20..]),
+            )
+        })
+        .collect()
+}
+
+fn token(secret: &[u8; 8], ip: &Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.input(secret);
+    hasher.input(ip.octets());
+    hasher.result()[..8].to_vec()
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn bytes(bytes: &[u8]) -> Value {
+    Value::Bytes(bytes.to_vec())
+}
+
+fn get_bytes<'a>(dict: &'a Dict, key: &str) -> Option<&'a [u8]> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Bytes(bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn get_dict<'a>(dict: &'a Dict, key: &str) -> Option<&'a Dict> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Dict(dict)) => Some(dict),
+        _ => None,
+    }
+}
+
+fn get_int(dict: &Dict, key: &str) -> Option<i64> {
+    match dict.get(key.as_bytes()) {
+        Some(Value::Int(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+fn get_id(dict: &Dict, key: &str) -> Option<Id> {
+    Id::try_from(get_bytes(dict, key)?).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    id: Id,
+    addr: SocketAddrV4,
+    last_seen: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+enum Asked {
+    Not,
+    Waiting,
+    // With the token it gave, if any
+    Answered(Option<Vec<u8>>),
+    Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    // Unknown for routers until they answer
+    id: Option<Id>,
+    addr: SocketAddrV4,
+    asked: Asked,
+}
+
+#[derive(Debug)]
+struct Search {
+    // get_peers, or find_node when filling the table
+    get_peers: bool,
+    // Port to announce once done
+    announce: Option<u16>,
+    // Closest first
+    candidates: Vec<Candidate>,
+}
+
+impl Search {
+    fn add(&mut self, target: &Id, id: Option<Id>, addr: SocketAddrV4) {
+        if self.candidates.iter().any(|c| c.addr == addr) {
+            return;
+        }
+        self.candidates.push(Candidate {
+            id,
+            addr,
+            asked: Asked::Not,
+        });
+        self.candidates
+            .sort_by_key(|c| c.id.map_or([0xff; 20], |id| distance(&id, target)));
+        self.candidates.truncate(MAX_CANDIDATES);
+    }
+}
+
+// One of our queries waiting for an answer
+#[derive(Debug)]
+struct Query {
+    addr: SocketAddrV4,
+    sent: Instant,
+    // The search it's for
+    target: Option<Id>,
+}
+
+#[derive(Debug)]
+struct State {
+    buckets: Vec<Vec<Node>>,
+    routers: Vec<SocketAddrV4>,
+    searches: HashMap<Id, Search>,
+    // Peers searches found, until they're taken
+    found: HashMap<Id, Vec<Peer>>,
+    // Peers announced to us
+    stored: HashMap<Id, Vec<(SocketAddrV4, Instant)>>,
+    queries: HashMap<u16, Query>,
+    next_tid: u16,
+    secret: [u8; 8],
+    previous_secret: [u8; 8],
+    secret_changed: Instant,
+}
+
+impl State {
+    // Answering and querying nodes go in their bucket, if there's room or
+    // one in there has been quiet for long
+    fn insert(&mut self, own: &Id, node: Node) {
+        if node.id == *own {
+            return;
+        }
+        let bucket = &mut self.buckets[bucket_index(own, &node.id)];
+        if let Some(known) = bucket.iter_mut().find(|n| n.id == node.id) {
+            *known = node;
+        } else if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(stale) = bucket
+            .iter_mut()
+            .find(|n| node.last_seen.duration_since(n.last_seen) >= STALE)
+        {
+            *stale = node;
+        }
+    }
+
+    fn remove(&mut self, addr: &SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|node| node.addr != *addr);
+        }
+    }
+
+    fn closest(&self, target: &Id, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    fn valid_token(&self, given: &[u8], ip: &Ipv4Addr) -> bool {
+        given == &token(&self.secret, ip)[..] || given == &token(&self.previous_secret, ip)[..]
+    }
+}
+
+struct Inner {
+    udp: UdpSocket,
+    id: Id,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, addr: SocketAddrV4, msg: &Value) {
+        if let Ok(bytes) = serde_bencode::to_bytes(msg) {
+            // Lost like any other datagram, the timeout deals with it
+            let _ = self.udp.send_to(&bytes, addr);
+        }
+    }
+
+    fn query(
+        &self,
+        state: &mut State,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: Vec<(&str, Value)>,
+        target: Option<Id>,
+    ) {
+        let tid = state.next_tid;
+        state.next_tid = tid.wrapping_add(1);
+        let query = Query {
+            addr,
+            sent: Instant::now(),
+            target,
+        };
+        state.queries.insert(tid, query);
+        args.push(("id", bytes(&self.id)));
+        let msg = dict(vec![
+            ("t", bytes(&tid.to_be_bytes())),
+            ("y", bytes(b"q")),
+            ("q", bytes(method.as_bytes())),
+            ("a", dict(args)),
+        ]);
+        self.send(addr, &msg);
+    }
+
+    fn handle(&self, packet: &[u8], from: SocketAddr) {
+        let from = match from {
+            SocketAddr::V4(from) => from,
+            SocketAddr::V6(_) => return,
+        };
+        let msg = match serde_bencode::from_bytes::<Value>(packet) {
+            Ok(Value::Dict(msg)) => msg,
+            _ => return,
+        };
+        let tid = match get_bytes(&msg, "t") {
+            Some(tid) => tid.to_vec(),
+            None => return,
+        };
+        let mut state = self.lock();
+        match get_bytes(&msg, "y") {
+            Some(b"q") => {
+                let reply = match self.answer(&mut state, &msg, from) {
+                    Ok(mut values) => {
+                        values.push(("id", bytes(&self.id)));
+                        ("r", dict(values))
+                    }
+                    Err((code, message)) => {
+                        let error = vec![Value::Int(code), bytes(message.as_bytes())];
+                        ("e", Value::List(error))
+                    }
+                };
+                let msg = dict(vec![
+                    ("t", bytes(&tid)),
+                    ("y", bytes(reply.0.as_bytes())),
+                    reply,
+                ]);
+                self.send(from, &msg);
+            }
+            Some(b"r") | Some(b"e") => {
+                let tid = match <[u8; 2]>::try_from(&tid[..]) {
+                    Ok(tid) => u16::from_be_bytes(tid),
+                    Err(_) => return,
+                };
+                match state.queries.get(&tid) {
+                    Some(query) if query.addr == from => {}
+                    _ => return,
+                }
+                let query = state.queries.remove(&tid).unwrap();
+                match get_dict(&msg, "r") {
+                    Some(response) => self.on_response(&mut state, query, response),
+                    None => self.on_failure(&mut state, query),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // What to answer another node's query with, besides our id
+    fn answer(
+        &self,
+        state: &mut State,
+        msg: &Dict,
+        from: SocketAddrV4,
+    ) -> Result<Vec<(&'static str, Value)>, KrpcError> {
+        const PROTOCOL_ERROR: KrpcError = (203, "Protocol Error");
+        let args = get_dict(msg, "a").ok_or(PROTOCOL_ERROR)?;
+        let id = get_id(args, "id").ok_or(PROTOCOL_ERROR)?;
+        let node = Node {
+            id,
+            addr: from,
+            last_seen: Instant::now(),
+        };
+        state.insert(&self.id, node);
+
+        match get_bytes(msg, "q") {
+            Some(b"ping") => Ok(vec![]),
+            Some(b"find_node") => {
+                let target = get_id(args, "target").ok_or(PROTOCOL_ERROR)?;
+                let nodes = compact_nodes(&state.closest(&target, K));
+                Ok(vec![("nodes", bytes(&nodes))])
+            }
+            Some(b"get_peers") => {
+                let info_hash = get_id(args, "info_hash").ok_or(PROTOCOL_ERROR)?;
+                let nodes = compact_nodes(&state.closest(&info_hash, K));
+                let mut values = vec![
+                    ("token", bytes(&token(&state.secret, from.ip()))),
+                    ("nodes", bytes(&nodes)),
+                ];
+                if let Some(peers) = state.stored.get(&info_hash) {
+                    let peers = peers
+                        .iter()
+                        .take(MAX_VALUES)
+                        .map(|(addr, _)| bytes(&compact_peer(addr)))
+                        .collect();
+                    values.push(("values", Value::List(peers)));
+                }
+                Ok(values)
+            }
+            Some(b"announce_peer") => {
+                let info_hash = get_id(args, "info_hash").ok_or(PROTOCOL_ERROR)?;
+                let token = get_bytes(args, "token").ok_or(PROTOCOL_ERROR)?;
+                if !state.valid_token(token, from.ip()) {
+                    return Err((203, "Bad Token"));
+                }
+                // implied_port says to take the port the query came from,
+                // for peers behind a NAT that only know their uTP port
+                let port = match get_int(args, "implied_port") {
+                    Some(1) => from.port(),
+                    _ => get_int(args, "port")
+                        .and_then(|port| u16::try_from(port).ok())
+                        .ok_or(PROTOCOL_ERROR)?,
+                };
+                let addr = SocketAddrV4::new(*from.ip(), port);
+                let peers = state.stored.entry(info_hash).or_default();
+                peers.retain(|(peer, _)| *peer != addr);
+                if peers.len() < MAX_PEERS {
+                    peers.push((addr, Instant::now()));
+                }
+                Ok(vec![])
+            }
+            _ => Err((204, "Method Unknown")),
+        }
+    }
+
+    fn on_response(&self, state: &mut State, query: Query, response: &Dict) {
+        let id = get_id(response, "id");
+        if let Some(id) = id {
+            let node = Node {
+                id,
+                addr: query.addr,
+                last_seen: Instant::now(),
+            };
+            state.insert(&self.id, node);
+        }
+        let target = match query.target {
+            Some(target) => target,
+            None => return,
+        };
+        let State {
+            searches, found, ..
+        } = &mut *state;
+        let search = match searches.get_mut(&target) {
+            Some(search) => search,
+            None => return,
+        };
+        if let Some(candidate) = search.candidates.iter_mut().find(|c| c.addr == query.addr) {
+            candidate.id = candidate.id.or(id);
+            let token = get_bytes(response, "token").map(<[u8]>::to_vec);
+            candidate.asked = Asked::Answered(token);
+        }
+        let nodes = get_bytes(response, "nodes").map_or(vec![], parse_nodes);
+        for (id, addr) in nodes {
+            if id != self.id {
+                search.add(&target, Some(id), addr);
+            }
+        }
+        if let Some(Value::List(values)) = response.get(&b"values"[..]) {
+            let peers = found.entry(target).or_default();
+            for value in values {
+                let addr = match value {
+                    Value::Bytes(bytes) if bytes.len() == 6 => parse_peer(bytes),
+                    _ => continue,
+                };
+                let known = peers
+                    .iter()
+                    .any(|peer| peer.ip == *addr.ip() && peer.port == addr.port());
+                if !known && peers.len() < MAX_PEERS {
+                    peers.push(Peer {
+                        ip: *addr.ip(),
+                        port: addr.port(),
+                    });
+                }
+            }
+        }
+        self.step(state, target);
+    }
+
+    // An error or no answer at all. Nodes that go quiet leave the table.
+    fn on_failure(&self, state: &mut State, query: Query) {
+        state.remove(&query.addr);
+        let target = match query.target {
+            Some(target) => target,
+            None => return,
+        };
+        if let Some(search) = state.searches.get_mut(&target) {
+            if let Some(candidate) = search.candidates.iter_mut().find(|c| c.addr == query.addr) {
+                candidate.asked = Asked::Failed;
+            }
+        }
+        self.step(state, target);
+    }
+
+    fn start(&self, state: &mut State, target: Id, get_peers: bool, announce: Option<u16>) {
+        let mut search = Search {
+            get_peers,
+            announce,
+            candidates: vec![],
+        };
+        for node in state.closest(&target, K) {
+            search.add(&target, Some(node.id), node.addr);
+        }
+        // Routers only help until the table has filled up some
+        if search.candidates.len() < K {
+            for &addr in &state.routers {
+                search.add(&target, None, addr);
+            }
+        }
+        state.searches.insert(target, search);
+        self.step(state, target);
+    }
+
+    // Ask the next closest nodes, or finish once the closest have answered
+    fn step(&self, state: &mut State, target: Id) {
+        let search = match state.searches.get_mut(&target) {
+            Some(search) => search,
+            None => return,
+        };
+        let mut waiting = search
+            .candidates
+            .iter()
+            .filter(|c| c.asked == Asked::Waiting)
+            .count();
+        let mut done = true;
+        let mut ask = vec![];
+        let closest = search
+            .candidates
+            .iter_mut()
+            .filter(|c| c.asked != Asked::Failed)
+            .take(K);
+        for candidate in closest {
+            match candidate.asked {
+                Asked::Not if waiting < ALPHA => {
+                    candidate.asked = Asked::Waiting;
+                    waiting += 1;
+                    ask.push(candidate.addr);
+                    done = false;
+                }
+                Asked::Not | Asked::Waiting => done = false,
+                _ => {}
+            }
+        }
+        let method = match search.get_peers {
+            true => "get_peers",
+            false => "find_node",
+        };
+        let key = match search.get_peers {
+            true => "info_hash",
+            false => "target",
+        };
+        if !done {
+            for addr in ask {
+                let args = vec![(key, bytes(&target))];
+                self.query(state, addr, method, args, Some(target));
+            }
+            return;
+        }
+
+        let search = state.searches.remove(&target).unwrap();
+        let port = match search.announce {
+            Some(port) => port,
+            None => return,
+        };
+        let answered = search
+            .candidates
+            .into_iter()
+            .filter(|c| c.asked != Asked::Failed)
+            .take(K);
+        for candidate in answered {
+            if let Asked::Answered(Some(token)) = candidate.asked {
+                let args = vec![
+                    ("info_hash", bytes(&target)),
+                    ("port", Value::Int(port as i64)),
+                    ("token", bytes(&token)),
+                ];
+                self.query(state, candidate.addr, "announce_peer", args, None);
+            }
+        }
+    }
+
+    fn tick(&self, now: Instant) {
+        let mut state = self.lock();
+        let expired: Vec<u16> = state
+            .queries
+            .iter()
+            .filter(|(_, query)| now.duration_since(query.sent) >= QUERY_TIMEOUT)
+            .map(|(&tid, _)| tid)
+            .collect();
+        for tid in expired {
+            let query = state.queries.remove(&tid).unwrap();
+            self.on_failure(&mut state, query);
+        }
+
+        if now.duration_since(state.secret_changed) >= SECRET_INTERVAL {
+            state.previous_secret = state.secret;
+            state.secret = rand::thread_rng().gen();
+            state.secret_changed = now;
+        }
+        for peers in state.stored.values_mut() {
+            peers.retain(|(_, at)| now.duration_since(*at) < PEER_TTL);
+        }
+        state.stored.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+// Read packets and run timers for as long as the node is around
+fn run(inner: Weak<Inner>) {
+    let mut buf = vec![0; 1 << 16];
+    while let Some(inner) = inner.upgrade() {
+        match inner.udp.recv_from(&mut buf) {
+            Ok((n, addr)) => inner.handle(&buf[..n], addr),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => debug!("DHT receive failed: {}", e),
+        }
+        inner.tick(Instant::now());
+    }
+}
+
+/// A DHT node on a UDP socket of its own. Searches run in the background,
+/// take_peers picks up what they found.
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for Dht {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dht({:?})", self.inner.udp.local_addr())
+    }
+}
+
+impl Dht {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Dht> {
+        let udp = UdpSocket::bind(addr)?;
+        udp.set_read_timeout(Some(TICK))?;
+        let mut rng = rand::thread_rng();
+        let inner = Arc::new(Inner {
+            udp,
+            id: rng.gen(),
+            state: Mutex::new(State {
+                buckets: vec![vec![]; 160],
+                routers: vec![],
+                searches: HashMap::new(),
+                found: HashMap::new(),
+                stored: HashMap::new(),
+                queries: HashMap::new(),
+                next_tid: rng.gen(),
+                secret: rng.gen(),
+                previous_secret: rng.gen(),
+                secret_changed: Instant::now(),
+            }),
+        });
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || run(weak));
+        Ok(Dht { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.udp.local_addr()
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.inner.id
+    }
+
+    /// Join the network through `addr`, a well known node or any other,
+    /// by looking for the nodes closest to us
+    pub fn add_router(&self, addr: SocketAddr) {
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => return,
+        };
+        let own = self.inner.id;
+        let mut state = self.inner.lock();
+        if !state.routers.contains(&addr) {
+            state.routers.push(addr);
+        }
+        match state.searches.get_mut(&own) {
+            Some(search) => {
+                search.add(&own, None, addr);
+                self.inner.step(&mut state, own);
+            }
+            None => self.inner.start(&mut state, own, false, None),
+        }
+    }
+
+    /// Nodes in the routing table
+    pub fn num_nodes(&self) -> usize {
+        self.inner.lock().buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Look for peers of `info_hash` in the background, then announce that
+    /// we're one on `announce` if given. Replaces a search already running
+    /// for it.
+    pub fn search(&self, info_hash: &[u8], announce: Option<u16>) {
+        if let Some(target) = target(info_hash) {
+            let mut state = self.inner.lock();
+            self.inner.start(&mut state, target, true, announce);
+        }
+    }
+
+    pub fn is_searching(&self, info_hash: &[u8]) -> bool {
+        target(info_hash).is_some_and(|target| self.inner.lock().searches.contains_key(&target))
+    }
+
+    /// Peers found for `info_hash` since it was last asked
+    pub fn take_peers(&self, info_hash: &[u8]) -> Vec<Peer> {
+        target(info_hash)
+            .and_then(|target| self.inner.lock().found.remove(&target))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_until<F: Fn() -> bool>(done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let own = [0; 20];
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        assert_eq!(bucket_index(&own, &id), 0);
+        id[0] = 0;
+        id[2] = 0x10;
+        assert_eq!(bucket_index(&own, &id), 19);
+        assert_eq!(bucket_index(&own, &own), 159);
+
+        let node = Node {
+            id: [7; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+            last_seen: Instant::now(),
+        };
+        let parsed = parse_nodes(&compact_nodes(&[node, node]));
+        assert_eq!(parsed, vec![(node.id, node.addr); 2]);
+        // Trailing garbage is ignored
+        assert_eq!(parse_nodes(&[0; 30]).len(), 1);
+        assert!(target(&[1; 10]).is_none());
+        assert_eq!(target(&[1; 32]), Some([1; 20]));
+    }
+
+    #[test]
+    fn test_dht() {
+        let router = Dht::bind("127.0.0.1:0").unwrap();
+        let seed = Dht::bind("127.0.0.1:0").unwrap();
+        let leech = Dht::bind("127.0.0.1:0").unwrap();
+        seed.add_router(router.local_addr().unwrap());
+        leech.add_router(router.local_addr().unwrap());
+        wait_until(|| router.num_nodes() == 2 && seed.num_nodes() > 0);
+
+        // The seed announces itself to the nodes nearest the info hash...
+        let info_hash = [5; 20];
+        seed.search(&info_hash, Some(6881));
+        wait_until(|| !seed.is_searching(&info_hash));
+        assert!(seed.take_peers(&info_hash).is_empty());
+
+        // ...where the leech finds it
+        wait_until(|| leech.num_nodes() > 0);
+        leech.search(&info_hash, None);
+        wait_until(|| !leech.is_searching(&info_hash));
+        let peers = leech.take_peers(&info_hash);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip, Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(peers[0].port, 6881);
+        assert!(leech.take_peers(&info_hash).is_empty());
+
+        // Unanswered queries time out and the search still ends
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let lonely = Dht::bind("127.0.0.1:0").unwrap();
+        lonely.add_router(silent.local_addr().unwrap());
+        lonely.search(&info_hash, None);
+        assert!(lonely.is_searching(&info_hash));
+        wait_until(|| !lonely.is_searching(&info_hash));
+        assert_eq!(lonely.num_nodes(), 0);
+    }
+}
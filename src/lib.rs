@@ -0,0 +1,25 @@
+pub mod alert;
+pub mod bitfield;
+pub mod choker;
+pub mod connection;
+pub mod create;
+pub mod dht;
+pub mod error;
+pub mod merkle;
+pub mod message;
+pub mod mmap_storage;
+pub mod mse;
+pub mod p2p;
+pub mod partfile;
+pub mod peer;
+pub mod ratelimit;
+pub mod resume;
+pub mod session;
+pub mod stats;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
+pub mod transport;
+pub mod utp;
+pub mod verify;
+pub mod webseed;
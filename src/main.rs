@@ -1,22 +1,127 @@
-use crate::p2p::Torrent;
+use bittorrent_client::create::{create, CreateOptions};
+use bittorrent_client::session::{Session, SessionSettings};
+use bittorrent_client::torrent::TorrentFile;
+use bittorrent_client::verify::verify;
+use std::env;
 use std::error::Error;
-use std::io;
-use std::path::Path;
-
-mod bitfield;
-mod connection;
-mod error;
-mod message;
-mod p2p;
-mod torrent;
-mod tracker;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("verify") {
+        let path = args.get(1).expect("usage: verify <torrent> [dir]");
+        let dir = args.get(2).map(String::as_str).unwrap_or(".");
+        verify_command(Path::new(path), Path::new(dir)).unwrap();
+        return;
+    }
+    if args.first().map(String::as_str) == Some("create") {
+        create_command(&args[1..]).unwrap();
+        return;
+    }
+
     //let input = read_input().unwrap();
     //let path = Path::new(&input);
-    let path = Path::new("data/ubuntu-18.04.4-desktop-amd64.iso.torrent");
-    let mut torrent = Torrent::new(&path).unwrap();
-    torrent.download().unwrap()
+    env_logger::init();
+    let paths = match args.is_empty() {
+        true => vec!["data/ubuntu-18.04.4-desktop-amd64.iso.torrent".to_string()],
+        false => args,
+    };
+    let session = Session::new(SessionSettings::default()).unwrap();
+    let alerts = session.subscribe();
+    for path in &paths {
+        session.add(Path::new(path)).unwrap();
+    }
+    for alert in alerts {
+        println!("{:?}", alert.kind);
+    }
+}
+
+fn verify_command(path: &Path, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let torrent = TorrentFile::open(path)?;
+    let have = verify(&torrent, dir, |p| {
+        print!("\rchecked {}/{} pieces, {} ok", p.checked, p.total, p.valid);
+        let _ = io::stdout().flush();
+    });
+    println!();
+
+    let missing: Vec<usize> = (0..torrent.num_pieces())
+        .filter(|&i| !have.has_piece(i))
+        .collect();
+    if missing.is_empty() {
+        println!("{}: complete", torrent.name());
+    } else {
+        println!(
+            "{}: {} of {} pieces missing or corrupt",
+            torrent.name(),
+            missing.len(),
+            torrent.num_pieces()
+        );
+    }
+    Ok(())
+}
+
+// create <path> [-o out] [--announce url]... [--announce-tier url]...
+//        [--piece-length n] [--comment text] [--private] [--source tag]
+//        [--web-seed url]... [--exclude glob]...
+//
+// --announce adds a tracker to the current tier, --announce-tier starts a
+// new tier with it.
+fn create_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut root = None;
+    let mut out = None;
+    let mut options = CreateOptions {
+        created_by: Some(format!(
+            "{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )),
+        creation_date: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+        ..CreateOptions::default()
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-o" => out = Some(PathBuf::from(value()?)),
+            "--announce" => match options.trackers.last_mut() {
+                Some(tier) => tier.push(value()?),
+                None => options.trackers.push(vec![value()?]),
+            },
+            "--announce-tier" => options.trackers.push(vec![value()?]),
+            "--piece-length" => options.piece_length = Some(value()?.parse()?),
+            "--comment" => options.comment = Some(value()?),
+            "--private" => options.private = true,
+            "--source" => options.source = Some(value()?),
+            "--web-seed" => options.web_seeds.push(value()?),
+            "--exclude" => options.exclude.push(value()?),
+            _ if root.is_none() => root = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg).into()),
+        }
+    }
+
+    let root = root.ok_or("usage: create <path> [options]")?;
+    let bytes = create(&root, &options)?;
+    let out = match out {
+        Some(out) => out,
+        None => {
+            let name = root.file_name().ok_or("path has no name")?;
+            PathBuf::from(format!("{}.torrent", name.to_string_lossy()))
+        }
+    };
+    fs::write(&out, bytes)?;
+
+    let torrent = TorrentFile::open(&out)?;
+    let hex_hash: String = torrent
+        .info_hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    println!("wrote {} ({})", out.display(), hex_hash);
+    Ok(())
 }
 
 fn read_input() -> Result<String, Box<dyn Error>> {
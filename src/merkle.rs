@@ -0,0 +1,191 @@
+// SHA-256 merkle trees for v2 torrents (BEP 52).
+//
+// Every file gets its own tree over 16 KiB blocks. The bottom layer is
+// padded with zero hashes up to a power of two, and the layer where each
+// hash covers one piece is what the piece layers in the torrent hold.
+
+use sha2::{Digest, Sha256};
+
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_block(block: &[u8]) -> Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(block));
+    hash
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Hashes of the 16 KiB blocks in data, the last one may be short
+pub fn leaves(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash_block).collect()
+}
+
+/// Root of a subtree of zero leaves `width` wide
+pub fn pad_hash(width: usize) -> Hash {
+    root(&[], width, [0; 32])
+}
+
+/// Root of a tree with `hashes` at the bottom, filled up to `width` (a
+/// power of two) with `pad`
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = hashes.to_vec();
+    if layer.is_empty() {
+        layer.push(pad);
+    }
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer[0]
+}
+
+/// Merkle root of a whole file
+pub fn file_root(data: &[u8]) -> Hash {
+    let leaves = leaves(data);
+    root(&leaves, leaves.len().next_power_of_two(), [0; 32])
+}
+
+/// The layer of a file's tree with one hash per piece
+pub fn piece_layer(data: &[u8], piece_length: usize) -> Vec<Hash> {
+    let per_piece = piece_length / BLOCK_SIZE;
+    data.chunks(piece_length)
+        .map(|piece| root(&leaves(piece), per_piece, [0; 32]))
+        .collect()
+}
+
+/// Root of a file's tree given its piece layer
+pub fn root_from_layer(layer: &[Hash], piece_length: usize) -> Hash {
+    let pad = pad_hash(piece_length / BLOCK_SIZE);
+    root(layer, layer.len().next_power_of_two(), pad)
+}
+
+/// Every layer of the tree over `hashes`, bottom first, with the bottom
+/// filled up to `width` (a power of two) with `pad`
+pub fn tree(hashes: &[Hash], width: usize, pad: Hash) -> Vec<Vec<Hash>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(1), pad);
+    let mut layers = vec![layer];
+    while layers.last().unwrap().len() > 1 {
+        let above = layers
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(above);
+    }
+    layers
+}
+
+/// `length` hashes from the bottom of `tree` starting at `index`, followed
+/// by the uncle hashes for up to `proof_layers` layers above them
+pub fn proof(
+    tree: &[Vec<Hash>],
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    let mut hashes = tree[0].get(index..index + length)?.to_vec();
+    let level = length.trailing_zeros() as usize;
+    let mut pos = index / length;
+    for layer in tree.iter().skip(level).take(proof_layers) {
+        if layer.len() == 1 {
+            break;
+        }
+        hashes.push(layer[pos ^ 1]);
+        pos /= 2;
+    }
+    Some(hashes)
+}
+
+/// Check that `hashes` at `index` together with the uncles lead up to
+/// `root`. The uncles have to go all the way up.
+pub fn verify_proof(root: &Hash, hashes: &[Hash], index: usize, uncles: &[Hash]) -> bool {
+    if !hashes.len().is_power_of_two() || !index.is_multiple_of(hashes.len()) {
+        return false;
+    }
+    let mut node = self::root(hashes, hashes.len(), [0; 32]);
+    let mut pos = index / hashes.len();
+    for uncle in uncles {
+        node = if pos.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        pos /= 2;
+    }
+    pos == 0 && node == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_file() {
+        // One block, the root is just its hash
+        assert_eq!(file_root(b"abc"), hash_block(b"abc"));
+
+        // Three blocks are padded with a zero leaf
+        let data = vec![1; 2 * BLOCK_SIZE + 10];
+        let l = leaves(&data);
+        let expected = hash_pair(&hash_pair(&l[0], &l[1]), &hash_pair(&l[2], &[0; 32]));
+        assert_eq!(file_root(&data), expected);
+    }
+
+    #[test]
+    fn test_piece_layer() {
+        let piece_length = 4 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..9 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let layer = piece_layer(&data, piece_length);
+        assert_eq!(layer.len(), 3);
+
+        // The last piece only has two blocks, padded to a full piece
+        let l = leaves(&data[8 * BLOCK_SIZE..]);
+        let zero = [0; 32];
+        let expected = hash_pair(&hash_pair(&l[0], &l[1]), &hash_pair(&zero, &zero));
+        assert_eq!(layer[2], expected);
+
+        // Going through the piece layer gives the same root as the blocks
+        assert_eq!(root_from_layer(&layer, piece_length), file_root(&data));
+    }
+
+    #[test]
+    fn test_proof() {
+        let hashes: Vec<Hash> = (0..6u8).map(|i| hash_block(&[i])).collect();
+        let file_root = root(&hashes, 8, [0; 32]);
+        let tree = tree(&hashes, 8, [0; 32]);
+        assert_eq!(tree.last().unwrap()[0], file_root);
+
+        // Two hashes and the two uncles above them
+        let got = proof(&tree, 2, 2, 5).unwrap();
+        assert_eq!(got.len(), 4);
+        assert_eq!(&got[..2], &hashes[2..4]);
+        assert!(verify_proof(&file_root, &got[..2], 2, &got[2..]));
+
+        // Wrong position, tampered hash or missing uncles don't verify
+        assert!(!verify_proof(&file_root, &got[..2], 0, &got[2..]));
+        let mut bad = got.clone();
+        bad[1][0] ^= 1;
+        assert!(!verify_proof(&file_root, &bad[..2], 2, &bad[2..]));
+        assert!(!verify_proof(&file_root, &got[..2], 2, &got[2..3]));
+
+        assert!(proof(&tree, 8, 2, 1).is_none());
+    }
+}
@@ -2,8 +2,57 @@
 // Message does serialization, perhaps reads from socket?
 
 use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BytesMut};
+use log::trace;
 use std::io::{self, Read};
-use std::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A run of hashes in a v2 file's merkle tree, as used by the hash request,
+/// hashes and hash reject messages (BEP 52)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    // Counted up from the 16 KiB block hashes
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    // How many layers of uncle hashes to send along
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const LEN: usize = 48;
+
+    fn from_bytes(b: &[u8]) -> HashRequest {
+        let mut pieces_root = [0; 32];
+        pieces_root.copy_from_slice(&b[..32]);
+        HashRequest {
+            pieces_root,
+            base_layer: BigEndian::read_u32(&b[32..36]),
+            index: BigEndian::read_u32(&b[36..40]),
+            length: BigEndian::read_u32(&b[40..44]),
+            proof_layers: BigEndian::read_u32(&b[44..48]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![0; HashRequest::LEN];
+        b[..32].copy_from_slice(&self.pieces_root);
+        BigEndian::write_u32(&mut b[32..36], self.base_layer);
+        BigEndian::write_u32(&mut b[36..40], self.index);
+        BigEndian::write_u32(&mut b[40..44], self.length);
+        BigEndian::write_u32(&mut b[44..48], self.proof_layers);
+        b
+    }
+
+    // A power of two between 2 and 512 hashes, starting at a multiple of
+    // the length
+    pub fn is_valid(&self) -> bool {
+        self.length.is_power_of_two()
+            && (2..=512).contains(&self.length)
+            && self.index.is_multiple_of(self.length)
+    }
+}
 
 #[derive(Debug)]
 pub enum Message {
@@ -16,21 +65,54 @@ pub enum Message {
     Bitfield(Vec<u8>),
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
-    Cancel,
+    Cancel(u32, u32, u32),
+    HashRequest(HashRequest),
+    // The requested hashes followed by the uncle hashes
+    Hashes(HashRequest, Vec<[u8; 32]>),
+    HashReject(HashRequest),
+}
+
+// Largest message we accept: a 16 KiB block plus header, or a bitfield for
+// roughly two million pieces
+pub const MAX_MESSAGE_LEN: u32 = 1 << 18;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
 
 impl Message {
     // FIXME: this is a really dumb new() method
-    pub fn new(id: u8, payload: &[u8]) -> Message {
-        println!("Message::new({} {})", id, payload.len());
+    pub fn new(id: u8, payload: &[u8]) -> Result<Message, io::Error> {
+        trace!("Message::new({} {})", id, payload.len());
+        let expect_len = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(invalid(format!(
+                    "message {} has {} byte payload, expected {}",
+                    id,
+                    payload.len(),
+                    len
+                )))
+            }
+        };
         let msg = match id {
-            0 => Message::Choke,
-            1 => Message::Unchoke,
-            2 => Message::Interested,
-            3 => Message::NotInterested,
-            4 => Message::Have(BigEndian::read_u32(payload)),
+            0..=3 => {
+                expect_len(0)?;
+                match id {
+                    0 => Message::Choke,
+                    1 => Message::Unchoke,
+                    2 => Message::Interested,
+                    _ => Message::NotInterested,
+                }
+            }
+            4 => {
+                expect_len(4)?;
+                Message::Have(BigEndian::read_u32(payload))
+            }
             5 => Message::Bitfield(payload.to_vec()),
             6 => {
+                expect_len(12)?;
                 let index = BigEndian::read_u32(&payload[..4]);
                 let begin = BigEndian::read_u32(&payload[4..8]);
                 let length = BigEndian::read_u32(&payload[8..]);
@@ -38,49 +120,122 @@ impl Message {
                 Message::Request(index, begin, length)
             }
             7 => {
-                println!("len: {:?}", &payload.len());
-                println!("index: {:?}", &payload[0..4]);
+                if payload.len() < 8 {
+                    return Err(invalid(format!(
+                        "piece message too short: {}",
+                        payload.len()
+                    )));
+                }
                 let index = BigEndian::read_u32(&payload[..4]);
-                println!("begin: {:?}", &payload[4..8]);
                 let begin = BigEndian::read_u32(&payload[4..8]);
                 let piece = payload[8..].to_vec();
 
                 Message::Piece(index, begin, piece)
             }
-            8 => Message::Cancel,
-            _ => panic!("Bad message ID: {}", id),
+            8 => {
+                expect_len(12)?;
+                let index = BigEndian::read_u32(&payload[..4]);
+                let begin = BigEndian::read_u32(&payload[4..8]);
+                let length = BigEndian::read_u32(&payload[8..]);
+
+                Message::Cancel(index, begin, length)
+            }
+            21 => {
+                expect_len(HashRequest::LEN)?;
+                Message::HashRequest(HashRequest::from_bytes(payload))
+            }
+            22 => {
+                if payload.len() < HashRequest::LEN
+                    || !(payload.len() - HashRequest::LEN).is_multiple_of(32)
+                {
+                    return Err(invalid(format!(
+                        "hashes message of {} bytes",
+                        payload.len()
+                    )));
+                }
+                let hashes = payload[HashRequest::LEN..]
+                    .chunks(32)
+                    .map(|h| {
+                        let mut hash = [0; 32];
+                        hash.copy_from_slice(h);
+                        hash
+                    })
+                    .collect();
+                Message::Hashes(HashRequest::from_bytes(payload), hashes)
+            }
+            23 => {
+                expect_len(HashRequest::LEN)?;
+                Message::HashReject(HashRequest::from_bytes(payload))
+            }
+            _ => return Err(invalid(format!("bad message id: {}", id))),
         };
-        println!("msg: {:?}", msg);
-        msg
+        Ok(msg)
     }
 
-    pub fn read(mut conn: &TcpStream) -> Result<Message, io::Error> {
+    pub fn read<R: Read>(mut conn: R) -> Result<Message, io::Error> {
         let mut msg_len = [0; 4];
 
         conn.read_exact(&mut msg_len)?;
 
         let msg_len = BigEndian::read_u32(&msg_len);
-        println!("msg len: {:?}", msg_len);
-        let mut msg = Vec::new();
+        if msg_len > MAX_MESSAGE_LEN {
+            return Err(invalid(format!("message too long: {}", msg_len)));
+        }
+        let mut msg = vec![0; msg_len as usize];
 
-        conn.take(msg_len as u64).read_to_end(&mut msg)?;
+        conn.read_exact(&mut msg)?;
 
         if msg_len > 0 {
-            Ok(Message::new(msg[0], &msg[1..]))
+            Message::new(msg[0], &msg[1..])
         } else {
             Ok(Message::KeepAlive)
         }
     }
 
+    // The payload as serialize wants it
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        match self {
+            Message::Have(index) => payload.extend(&index.to_be_bytes()),
+            Message::Bitfield(bits) => payload.extend(bits),
+            Message::Request(index, begin, length) | Message::Cancel(index, begin, length) => {
+                for n in &[index, begin, length] {
+                    payload.extend(&n.to_be_bytes());
+                }
+            }
+            Message::Piece(index, begin, block) => {
+                payload.extend(&index.to_be_bytes());
+                payload.extend(&begin.to_be_bytes());
+                payload.extend(block);
+            }
+            Message::HashRequest(req) | Message::HashReject(req) => payload = req.to_bytes(),
+            Message::Hashes(req, hashes) => {
+                payload = req.to_bytes();
+                for hash in hashes {
+                    payload.extend(hash);
+                }
+            }
+            _ => {}
+        }
+        payload
+    }
+
     pub fn serialize(&self, payload: &[u8]) -> Vec<u8> {
         let length = payload.len() + 1;
         let id = match self {
+            Message::KeepAlive => return vec![0; 4],
             Message::Choke => 0,
             Message::Unchoke => 1,
             Message::Interested => 2,
             Message::NotInterested => 3,
+            Message::Have(_) => 4,
+            Message::Bitfield(_) => 5,
             Message::Request(_, _, _) => 6,
-            _ => 4,
+            Message::Piece(_, _, _) => 7,
+            Message::Cancel(_, _, _) => 8,
+            Message::HashRequest(_) => 21,
+            Message::Hashes(_, _) => 22,
+            Message::HashReject(_) => 23,
         };
 
         let mut buf = [0; 5];
@@ -88,10 +243,143 @@ impl Message {
         buf[4] = id;
         let mut done = Vec::from(buf);
         done.extend(payload);
-        println!("serializing: {:?}", done);
+        trace!("serializing: {:?}", done);
         done
     }
 }
 
+/// Length prefixed framing of messages for tokio's Framed, the async
+/// counterpart of read and serialize
+#[derive(Debug, Default)]
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, io::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let msg_len = BigEndian::read_u32(&src[..4]);
+        if msg_len > MAX_MESSAGE_LEN {
+            return Err(invalid(format!("message too long: {}", msg_len)));
+        }
+        let msg_len = msg_len as usize;
+        if src.len() < 4 + msg_len {
+            src.reserve(4 + msg_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let msg = src.split_to(msg_len);
+        if msg_len > 0 {
+            Message::new(msg[0], &msg[1..]).map(Some)
+        } else {
+            Ok(Some(Message::KeepAlive))
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(&msg.serialize(&msg.payload()));
+        Ok(())
+    }
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        match Message::new(8, &[0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0]).unwrap() {
+            Message::Cancel(1, 16384, 16384) => {}
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // Malformed messages are errors rather than panics
+        assert!(Message::new(4, &[0, 0, 1]).is_err());
+        assert!(Message::new(6, &[0; 13]).is_err());
+        assert!(Message::new(7, &[0; 7]).is_err());
+        assert!(Message::new(1, &[0]).is_err());
+        assert!(Message::new(42, &[]).is_err());
+    }
+
+    #[test]
+    fn test_hash_messages() {
+        let req = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 1,
+            index: 4,
+            length: 4,
+            proof_layers: 3,
+        };
+        match Message::new(21, &req.to_bytes()).unwrap() {
+            Message::HashRequest(got) => assert_eq!(got, req),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        let mut payload = req.to_bytes();
+        payload.extend(&[1; 64]);
+        match Message::new(22, &payload).unwrap() {
+            Message::Hashes(got, hashes) => {
+                assert_eq!(got, req);
+                assert_eq!(hashes, vec![[1; 32]; 2]);
+            }
+            msg => panic!("unexpected {:?}", msg),
+        }
+        payload.push(0);
+        assert!(Message::new(22, &payload).is_err());
+        assert!(Message::new(23, &[0; 47]).is_err());
+
+        assert!(req.is_valid());
+        assert!(!HashRequest { index: 2, ..req }.is_valid());
+        assert!(!HashRequest { length: 3, ..req }.is_valid());
+        assert!(!HashRequest {
+            length: 1024,
+            index: 0,
+            ..req
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_codec() {
+        let mut buf = BytesMut::new();
+        let msgs = vec![
+            Message::KeepAlive,
+            Message::Have(9),
+            Message::Request(1, 16384, 16384),
+            Message::Piece(2, 0, vec![5; 100]),
+        ];
+        for msg in msgs {
+            MessageCodec.encode(msg, &mut buf).unwrap();
+        }
+
+        // Frames come out whole however the bytes trickle in
+        let mut stream = BytesMut::new();
+        let mut decoded = vec![];
+        while !buf.is_empty() {
+            let n = buf.len().min(7);
+            stream.extend_from_slice(&buf.split_to(n));
+            while let Some(msg) = MessageCodec.decode(&mut stream).unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert!(stream.is_empty());
+        assert!(matches!(decoded[0], Message::KeepAlive));
+        assert!(matches!(decoded[1], Message::Have(9)));
+        assert!(matches!(decoded[2], Message::Request(1, 16384, 16384)));
+        match &decoded[3] {
+            Message::Piece(2, 0, block) => assert_eq!(block, &vec![5; 100]),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        let mut huge = BytesMut::from(&(MAX_MESSAGE_LEN + 1).to_be_bytes()[..]);
+        assert!(MessageCodec.decode(&mut huge).is_err());
+    }
+}
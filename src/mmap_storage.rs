@@ -0,0 +1,210 @@
+// Storage backend that maps every file into memory.
+//
+// Blocks are copied straight into the mapping and pieces are hashed from it,
+// so data isn't buffered a second time in userspace. Files are resized to
+// their length in the torrent when first written to.
+
+use crate::storage::{block_offset, layout, split_range, Storage, StorageFile};
+use crate::torrent::{FileInfo, TorrentFile};
+use memmap::MmapMut;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct MmapStorage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    length: u64,
+    maps: HashMap<usize, MmapMut>,
+}
+
+impl MmapStorage {
+    pub fn new(torrent: &TorrentFile, dir: &Path) -> MmapStorage {
+        MmapStorage::from_files(dir, &torrent.files, torrent.piece_length())
+    }
+
+    pub fn from_files(dir: &Path, files: &[FileInfo], piece_length: u64) -> MmapStorage {
+        let files = layout(dir, files);
+        let length = files.iter().map(|f| f.length).sum();
+        MmapStorage {
+            files,
+            piece_length,
+            length,
+            maps: HashMap::new(),
+        }
+    }
+
+    fn block_range(&self, index: u32, begin: u32, length: u64) -> io::Result<u64> {
+        block_offset(self.piece_length, self.length, index, begin, length)
+    }
+
+    // Map file i. Writers create the file and grow or truncate it to the
+    // right size, readers only accept files that already have it.
+    fn map(&mut self, i: usize, write: bool) -> io::Result<&mut MmapMut> {
+        if !self.maps.contains_key(&i) {
+            let StorageFile { path, length, .. } = &self.files[i];
+            if write {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(write)
+                .truncate(false)
+                .open(path)?;
+
+            if file.metadata()?.len() != *length {
+                if !write {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("{} has the wrong size", path.display()),
+                    ));
+                }
+                file.set_len(*length)?;
+            }
+
+            let map = unsafe { MmapMut::map_mut(&file)? };
+            self.maps.insert(i, map);
+        }
+        Ok(self.maps.get_mut(&i).unwrap())
+    }
+
+    // Run f over the mapped slices covering [offset, offset + length)
+    fn for_each_slice<F>(
+        &mut self,
+        offset: u64,
+        length: u64,
+        write: bool,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&mut [u8]),
+    {
+        for (i, file_offset, length) in split_range(&self.files, offset, length) {
+            if !self.files[i].on_disk() {
+                // Reads see zeros, writes go nowhere
+                f(&mut vec![0; length as usize]);
+                continue;
+            }
+            let map = self.map(i, write)?;
+            let start = file_offset as usize;
+            f(&mut map[start..start + length as usize]);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let offset = self.block_range(index, begin, length as u64)?;
+        let mut block = Vec::with_capacity(length as usize);
+        self.for_each_slice(offset, length as u64, false, |slice| {
+            block.extend_from_slice(slice)
+        })?;
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let offset = self.block_range(index, begin, data.len() as u64)?;
+        let mut pos = 0;
+        self.for_each_slice(offset, data.len() as u64, true, |slice| {
+            slice.copy_from_slice(&data[pos..pos + slice.len()]);
+            pos += slice.len();
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for map in self.maps.values() {
+            map.flush()?;
+        }
+        // Empty files can't be mapped, create them directly
+        for file in self.files.iter().filter(|f| f.length == 0 && f.on_disk()) {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)?;
+        }
+        Ok(())
+    }
+
+    fn hash_piece(&mut self, index: u32) -> io::Result<[u8; 20]> {
+        let offset = index as u64 * self.piece_length;
+        let length = (offset + self.piece_length).min(self.length) - offset;
+        let mut hasher = Sha1::new();
+        self.for_each_slice(offset, length, false, |slice| hasher.input(slice))?;
+
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&hasher.result());
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use std::env;
+    use std::process;
+
+    fn file(path: &[&str], length: u64) -> FileInfo {
+        FileInfo {
+            path: path.iter().map(|c| c.to_string()).collect(),
+            length,
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_mmap_storage() {
+        let dir = env::temp_dir().join(format!("bittorrent-mmap-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let files = [
+            file(&["t", "a"], 20),
+            file(&["t", "b"], 8),
+            file(&["t", "c"], 30),
+        ];
+
+        // A stale file that is too long gets truncated
+        fs::create_dir_all(dir.join("t")).unwrap();
+        fs::write(dir.join("t/c"), vec![0xff; 100]).unwrap();
+
+        let mut storage = MmapStorage::from_files(&dir, &files, 16);
+        let data: Vec<u8> = (0..58).collect();
+        for (index, piece) in data.chunks(16).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.flush().unwrap();
+
+        assert_eq!(fs::read(dir.join("t/a")).unwrap(), &data[..20]);
+        assert_eq!(fs::read(dir.join("t/b")).unwrap(), &data[20..28]);
+        assert_eq!(fs::read(dir.join("t/c")).unwrap(), &data[28..]);
+        assert_eq!(storage.read_block(1, 2, 14).unwrap(), &data[18..32]);
+        assert!(storage.read_block(3, 0, 11).is_err());
+
+        // Both backends agree on what's on disk
+        let mut plain = FileStorage::from_files(&dir, &files, 16);
+        for index in 0..4 {
+            assert_eq!(
+                storage.hash_piece(index).unwrap(),
+                plain.hash_piece(index).unwrap()
+            );
+        }
+
+        // Reading never resizes files
+        fs::write(dir.join("t/a"), [0; 5]).unwrap();
+        let mut storage = MmapStorage::from_files(&dir, &files, 16);
+        assert!(storage.hash_piece(0).is_err());
+        assert_eq!(fs::metadata(dir.join("t/a")).unwrap().len(), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+// Message stream encryption, also known as protocol encryption.
+//
+// Both sides do a Diffie-Hellman exchange, prove they know the info hash
+// without sending it, then agree on RC4 or plaintext for the rest of the
+// connection. RC4 is keyed from the shared secret and the info hash, and
+// the first 1024 bytes of its output are thrown away.
+//
+// The BitTorrent handshake follows as usual, through the cipher if one was
+// picked.
+
+use crate::transport::Transport;
+use num_bigint::BigUint;
+use rand::{self, Rng};
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// The 768 bit prime everybody uses, the generator is 2
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+// Verification constant, eight zeros before encryption
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const DISCARD: usize = 1024;
+
+/// Whether connections get encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EncryptionPolicy {
+    /// Plaintext only, encrypted incoming connections are refused
+    Disabled,
+    /// Encrypt when the other side can, fall back to plaintext otherwise
+    #[default]
+    Prefer,
+    /// Only talk to peers that encrypt
+    Require,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Require => CRYPTO_RC4,
+        }
+    }
+
+    // Pick one of the methods the other side offered
+    fn crypto_select(self, provide: u32) -> Option<u32> {
+        if provide & CRYPTO_RC4 != 0 && self != EncryptionPolicy::Disabled {
+            Some(CRYPTO_RC4)
+        } else if provide & CRYPTO_PLAINTEXT != 0 && self != EncryptionPolicy::Require {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+// Keep key state out of logs
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rc4")
+    }
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *byte ^= self.s[k as usize];
+        }
+    }
+}
+
+/// The two RC4 streams of an encrypted connection
+#[derive(Debug)]
+pub struct Cipher {
+    encrypt: Rc4,
+    decrypt: Rc4,
+}
+
+impl Cipher {
+    // The side that connected sends with keyA, the other with keyB
+    fn new(secret: &[u8], info_hash: &[u8], initiator: bool) -> Cipher {
+        let key = |name: &[u8]| Rc4::new(&hash(&[name, secret, info_hash]));
+        let (mut encrypt, mut decrypt) = (key(b"keyA"), key(b"keyB"));
+        if !initiator {
+            std::mem::swap(&mut encrypt, &mut decrypt);
+        }
+        encrypt.apply(&mut [0; DISCARD]);
+        decrypt.apply(&mut [0; DISCARD]);
+        Cipher { encrypt, decrypt }
+    }
+
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.encrypt.apply(data);
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.decrypt.apply(data);
+    }
+
+    /// Read from `inner`, decrypting on the way
+    pub fn reader<R: Read>(&mut self, inner: R) -> Decrypting<'_, R> {
+        Decrypting {
+            inner,
+            rc4: &mut self.decrypt,
+        }
+    }
+}
+
+pub struct Decrypting<'a, R> {
+    inner: R,
+    rc4: &'a mut Rc4,
+}
+
+impl<R: Read> Read for Decrypting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.rc4.apply(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).unwrap()
+}
+
+// Big endian, zero padded to the full key length
+fn to_key_bytes(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut key = vec![0; KEY_LEN - bytes.len()];
+    key.extend(bytes);
+    key
+}
+
+// A random private key and the public key that goes with it
+fn keypair() -> (BigUint, Vec<u8>) {
+    let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+    let public = BigUint::from(2u32).modpow(&private, &prime());
+    (private, to_key_bytes(&public))
+}
+
+fn shared_secret(private: &BigUint, public: &[u8]) -> Vec<u8> {
+    let public = BigUint::from_bytes_be(public);
+    to_key_bytes(&public.modpow(private, &prime()))
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0, MAX_PAD + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+// Read byte by byte until `pattern` turns up, giving up after `limit`
+// bytes. Nothing past the pattern is consumed.
+fn sync<S: Read>(stream: &mut S, pattern: &[u8], limit: usize) -> Result<(), Box<dyn Error>> {
+    let mut window = vec![];
+    let mut byte = [0; 1];
+    while window.len() < limit {
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err("ERR: encrypted handshake out of sync".into())
+}
+
+fn read_u16<S: Read>(stream: &mut S, cipher: &mut Cipher) -> io::Result<usize> {
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf)?;
+    cipher.decrypt(&mut buf);
+    Ok(u16::from_be_bytes(buf) as usize)
+}
+
+/// Whether an incoming connection starts with a plaintext BitTorrent
+/// handshake rather than an encrypted one. Nothing is consumed.
+pub fn is_plaintext(stream: &dyn Transport) -> io::Result<bool> {
+    let header = b"\x13BitTorrent protocol";
+    let mut buf = [0; 20];
+    let n = stream.peek(&mut buf)?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf[..n] == header[..n])
+}
+
+/// Run the connecting side of the handshake. Returns the cipher to use
+/// from here on, or None when the peer picked plaintext.
+pub fn initiate<S: Read + Write>(
+    stream: &mut S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+) -> Result<Option<Cipher>, Box<dyn Error>> {
+    let (private, public) = keypair();
+    stream.write_all(&[public, random_pad()].concat())?;
+
+    let mut peer_public = [0; KEY_LEN];
+    stream.read_exact(&mut peer_public)?;
+    let secret = shared_secret(&private, &peer_public);
+
+    // Prove we know the secret and which torrent we want, without saying
+    // the info hash out loud
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let mut out = hash(&[b"req1", &secret]).to_vec();
+    out.extend(req2.iter().zip(&req3).map(|(a, b)| a ^ b));
+
+    // No padding and no initial payload, the handshake follows on its own
+    let mut cipher = Cipher::new(&secret, info_hash, true);
+    let mut step3 = VC.to_vec();
+    step3.extend(&policy.crypto_provide().to_be_bytes());
+    step3.extend(&[0, 0, 0, 0]);
+    cipher.encrypt(&mut step3);
+    out.extend(step3);
+    stream.write_all(&out)?;
+
+    // The peer's padding ends where its encrypted VC starts
+    let mut probe = cipher.decrypt.clone();
+    let mut vc = VC;
+    probe.apply(&mut vc);
+    sync(stream, &vc, MAX_PAD + VC.len())?;
+    cipher.decrypt = probe;
+
+    let mut select = [0; 4];
+    stream.read_exact(&mut select)?;
+    cipher.decrypt(&mut select);
+    let select = u32::from_be_bytes(select);
+    let pad_len = read_u16(stream, &mut cipher)?;
+    if pad_len > MAX_PAD {
+        return Err(format!("ERR: padding of {} bytes", pad_len).into());
+    }
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad)?;
+    cipher.decrypt(&mut pad);
+
+    match select {
+        CRYPTO_RC4 if policy != EncryptionPolicy::Disabled => Ok(Some(cipher)),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Require => Ok(None),
+        _ => Err(format!("ERR: peer selected crypto method {}", select).into()),
+    }
+}
+
+/// Run the accepting side of the handshake for the torrent with
+/// `info_hash`. Returns the cipher, if any, and the initial payload the
+/// peer sent along, which is the start of its BitTorrent handshake.
+pub fn respond<S: Read + Write>(
+    stream: &mut S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+) -> Result<(Option<Cipher>, Vec<u8>), Box<dyn Error>> {
+    let (_, cipher, initial) = respond_any(stream, &[info_hash.to_vec()], policy)?;
+    Ok((cipher, initial))
+}
+
+// Which torrent the peer asked for, then what respond returns
+type Responded = (usize, Option<Cipher>, Vec<u8>);
+
+/// Like respond, for whichever of `info_hashes` the peer asks for. Its
+/// index comes first in the result.
+pub fn respond_any<S: Read + Write>(
+    stream: &mut S,
+    info_hashes: &[Vec<u8>],
+    policy: EncryptionPolicy,
+) -> Result<Responded, Box<dyn Error>> {
+    let mut peer_public = [0; KEY_LEN];
+    stream.read_exact(&mut peer_public)?;
+    let (private, public) = keypair();
+    stream.write_all(&[public, random_pad()].concat())?;
+    let secret = shared_secret(&private, &peer_public);
+
+    sync(stream, &hash(&[b"req1", &secret]), MAX_PAD + 20)?;
+    let mut req23 = [0; 20];
+    stream.read_exact(&mut req23)?;
+    // Undo req3 to get HASH('req2', info_hash), the torrent's SKEY
+    let req3 = hash(&[b"req3", &secret]);
+    let req2: Vec<u8> = req23.iter().zip(&req3).map(|(a, b)| a ^ b).collect();
+    let index = info_hashes
+        .iter()
+        .position(|info_hash| hash(&[b"req2", info_hash]) == req2.as_slice())
+        .ok_or("ERR: encrypted handshake for another torrent")?;
+    let info_hash = &info_hashes[index];
+
+    let mut cipher = Cipher::new(&secret, info_hash, false);
+    let mut step3 = [0; 12];
+    stream.read_exact(&mut step3)?;
+    cipher.decrypt(&mut step3);
+    if step3[..8] != VC {
+        return Err("ERR: bad verification constant".into());
+    }
+    let provide = u32::from_be_bytes([step3[8], step3[9], step3[10], step3[11]]);
+    let pad_len = read_u16(stream, &mut cipher)?;
+    if pad_len > MAX_PAD {
+        return Err(format!("ERR: padding of {} bytes", pad_len).into());
+    }
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad)?;
+    cipher.decrypt(&mut pad);
+    let initial_len = read_u16(stream, &mut cipher)?;
+    let mut initial = vec![0; initial_len];
+    stream.read_exact(&mut initial)?;
+    cipher.decrypt(&mut initial);
+
+    let select = policy
+        .crypto_select(provide)
+        .ok_or(format!("ERR: no acceptable crypto method in {}", provide))?;
+    let mut step4 = VC.to_vec();
+    step4.extend(&select.to_be_bytes());
+    step4.extend(&[0, 0]);
+    cipher.encrypt(&mut step4);
+    stream.write_all(&step4)?;
+
+    if select == CRYPTO_RC4 {
+        Ok((index, Some(cipher), initial))
+    } else {
+        Ok((index, None, initial))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_rc4() {
+        // Test vector from the original RC4 posting
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        assert_eq!(prime().bits(), 768);
+        let (a, a_public) = keypair();
+        let (b, b_public) = keypair();
+        assert_eq!(a_public.len(), KEY_LEN);
+        assert_eq!(shared_secret(&a, &b_public), shared_secret(&b, &a_public));
+    }
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let info_hash = [7; 20];
+        let (mut a, mut b) = pair();
+        let responder = thread::spawn(move || {
+            let (cipher, initial) = respond(&mut b, &info_hash, EncryptionPolicy::Prefer).unwrap();
+            assert!(initial.is_empty());
+            let mut cipher = cipher.unwrap();
+            let mut buf = [0; 5];
+            cipher.reader(&b).read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+            let mut reply = b"world".to_vec();
+            cipher.encrypt(&mut reply);
+            b.write_all(&reply).unwrap();
+        });
+
+        let mut cipher = initiate(&mut a, &info_hash, EncryptionPolicy::Require)
+            .unwrap()
+            .unwrap();
+        let mut hello = b"hello".to_vec();
+        cipher.encrypt(&mut hello);
+        a.write_all(&hello).unwrap();
+        let mut buf = [0; 5];
+        cipher.reader(&a).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_plaintext() {
+        let (mut a, b) = pair();
+        a.write_all(b"\x13BitTorrent protocol").unwrap();
+        assert!(is_plaintext(&b).unwrap());
+        let (mut a, b) = pair();
+        a.write_all(&[0x13, b'X']).unwrap();
+        assert!(!is_plaintext(&b).unwrap());
+    }
+
+    #[test]
+    fn test_policies() {
+        // Plaintext is only picked when RC4 isn't on offer
+        assert_eq!(
+            EncryptionPolicy::Prefer.crypto_select(CRYPTO_PLAINTEXT | CRYPTO_RC4),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(
+            EncryptionPolicy::Prefer.crypto_select(CRYPTO_PLAINTEXT),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(
+            EncryptionPolicy::Require.crypto_select(CRYPTO_PLAINTEXT),
+            None
+        );
+
+        // A responder that requires RC4 turns down a plaintext only offer
+        let (mut a, mut b) = pair();
+        let responder = thread::spawn(move || {
+            assert!(respond(&mut b, &[1; 20], EncryptionPolicy::Require).is_err());
+        });
+        assert!(initiate(&mut a, &[1; 20], EncryptionPolicy::Disabled).is_err());
+        responder.join().unwrap();
+
+        // Wrong torrent
+        let (mut a, mut b) = pair();
+        let responder = thread::spawn(move || {
+            assert!(respond(&mut b, &[1; 20], EncryptionPolicy::Prefer).is_err());
+        });
+        assert!(initiate(&mut a, &[2; 20], EncryptionPolicy::Prefer).is_err());
+        responder.join().unwrap();
+
+        // The responder finds the torrent among several
+        let (mut a, mut b) = pair();
+        let responder = thread::spawn(move || {
+            let info_hashes = vec![vec![1; 20], vec![2; 20], vec![3; 20]];
+            let (index, cipher, _) =
+                respond_any(&mut b, &info_hashes, EncryptionPolicy::Prefer).unwrap();
+            assert_eq!(index, 1);
+            assert!(cipher.is_some());
+        });
+        assert!(initiate(&mut a, &[2; 20], EncryptionPolicy::Require).is_ok());
+        responder.join().unwrap();
+    }
+}
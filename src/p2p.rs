@@ -1,19 +1,63 @@
-use crate::connection::Connection;
+use crate::alert::{Alert, AlertKind, Alerts};
+use crate::bitfield::Bitfield;
+use crate::choker::{ChokePolicy, Choker, PeerInfo, TitForTat};
+use crate::connection::{BlockRequest, Connection, DisconnectReason};
 //use crate::error::Error as TorrentError;
 use crate::message::Message;
+use crate::mse::EncryptionPolicy;
+use crate::partfile;
+use crate::ratelimit::{self, Limits};
+use crate::resume::{self, PartialPiece, ResumeData};
+use crate::session::{ConnectionLimit, Slot};
+use crate::stats::{self, History, RateMeter, TorrentStats, Totals};
+pub use crate::stats::{PeerKind, PeerStats};
+use crate::storage::{self, Backend, FilePriority, Storage};
 use crate::torrent::TorrentFile;
-use crate::tracker::{request_peers, Peer};
+use crate::tracker::{request_peers, Peer, PeerSource};
+use crate::utp::UtpSocket;
+use crate::verify;
+use crate::webseed::{SeedKind, WebSeed};
+use log::{debug, info, trace, warn};
 use rand::{self, Rng};
+use serde_bytes::ByteBuf;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
-struct MyError;
+// Largest block we request or serve. Peers asking for more get disconnected,
+// as every current implementation does.
+pub const MAX_BLOCK_SIZE: u32 = 16384;
+// Number of unfulfilled requests to keep in flight
+const MAX_BACKLOG: u64 = 5;
+// Regular unchoke slots per torrent
+const UPLOAD_SLOTS: usize = 4;
+// How long to wait for a message before running timers
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// How long each peer is waited on when none has anything to read
+const POLL_WAIT: Duration = Duration::from_millis(20);
+// How long to do without a dialed peer after failing to get one
+const REDIAL_INTERVAL: Duration = Duration::from_secs(30);
+// Bad pieces an address may send before it's banned
+const MAX_STRIKES: u32 = 3;
+// How often resume data is written while running
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+// Bytes past a reader's position to treat as time critical
+const READAHEAD: u64 = 4 * 1024 * 1024;
+// Readahead pieces are due one after the other, this far apart
+const READAHEAD_STEP: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub struct Progress {
     index: u64,
-    buf: Vec<u8>,
     downloaded: u64,
     requested: u64,
     backlog: u64,
@@ -23,7 +67,6 @@ impl Progress {
     fn new() -> Self {
         Progress {
             index: 0,
-            buf: vec![],
             downloaded: 0,
             requested: 0,
             backlog: 0,
@@ -31,103 +74,1500 @@ impl Progress {
     }
 }
 
+// A connected peer and the piece we're getting from it
+#[derive(Debug)]
+struct PeerConn {
+    // Stays the same while connected, for the choker
+    id: usize,
+    conn: Connection,
+    // Dialed by us rather than accepted
+    outbound: bool,
+    // Counts against the session's connection limit while open
+    _slot: Option<Slot>,
+    // Its own caps, None when exempt
+    limits: Option<Limits>,
+    progress: Option<Progress>,
+    // How many of the torrent's verified pieces it was sent a have for
+    haves: usize,
+    // Its counters as of the last count into the totals
+    seen: [u64; 5],
+    // Payload rates down and up
+    meters: (RateMeter, RateMeter),
+    // Pieces it sent bad data for, left to other peers where possible
+    bad_pieces: HashSet<usize>,
+}
+
+impl PeerConn {
+    fn address(&self) -> String {
+        format!("{}:{}", self.conn.peer.ip, self.conn.peer.port)
+    }
+
+    fn stats(&self, num_pieces: usize) -> PeerStats {
+        let conn = &self.conn;
+        PeerStats {
+            kind: PeerKind::BitTorrent,
+            address: self.address(),
+            downloaded: conn.downloaded,
+            uploaded: conn.uploaded,
+            protocol_downloaded: conn.received_bytes - conn.downloaded,
+            protocol_uploaded: conn.sent_bytes - conn.uploaded,
+            download_rate: self.meters.0.rate(),
+            upload_rate: self.meters.1.rate(),
+            is_seed: (0..num_pieces).all(|i| conn.bitfield.has_piece(i)),
+        }
+    }
+}
+
+// Reading or writing the torrent's data failed, which ends the download
+// rather than just the connection it happened on
+#[derive(Debug)]
+struct StorageFailed(io::Error);
+
+impl fmt::Display for StorageFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for StorageFailed {}
+
+/// Lets a Session steer a torrent running on another thread
+#[derive(Debug, Default)]
+pub struct Control {
+    stopped: AtomicBool,
+    // Connections accepted for this torrent by the session's listener
+    incoming: Mutex<Vec<(Connection, Option<Slot>)>>,
+    // Peers found outside the torrent's own announces
+    peers: Mutex<Vec<(PeerSource, Peer)>>,
+    // Published by the torrent about once a tick
+    stats: Mutex<Option<TorrentStats>>,
+    // To apply when the torrent next looks
+    file_priorities: Mutex<Option<Vec<FilePriority>>>,
+    sequential: Mutex<Option<bool>>,
+    // None clears a piece's deadline
+    deadlines: Mutex<Vec<(usize, Option<Instant>)>>,
+    read_position: Mutex<Option<Option<u64>>>,
+}
+
+impl Control {
+    /// Have the torrent save its progress and return from download
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    pub fn add_incoming(&self, conn: Connection, slot: Option<Slot>) {
+        self.incoming.lock().unwrap().push((conn, slot));
+    }
+
+    pub fn add_peers(&self, source: PeerSource, peers: Vec<Peer>) {
+        let mut queued = self.peers.lock().unwrap();
+        queued.extend(peers.into_iter().map(|peer| (source, peer)));
+    }
+
+    pub fn set_file_priorities(&self, priorities: Vec<FilePriority>) {
+        *self.file_priorities.lock().unwrap() = Some(priorities);
+    }
+
+    pub fn set_sequential(&self, sequential: bool) {
+        *self.sequential.lock().unwrap() = Some(sequential);
+    }
+
+    pub fn set_piece_deadline(&self, index: usize, deadline: Instant) {
+        self.deadlines.lock().unwrap().push((index, Some(deadline)));
+    }
+
+    pub fn reset_piece_deadline(&self, index: usize) {
+        self.deadlines.lock().unwrap().push((index, None));
+    }
+
+    pub fn set_read_position(&self, offset: Option<u64>) {
+        *self.read_position.lock().unwrap() = Some(offset);
+    }
+
+    /// The torrent's latest stats, None until it has published any
+    pub fn stats(&self) -> Option<TorrentStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
 #[derive(Debug)]
 pub struct Torrent {
     torrent_file: TorrentFile,
     peers: Vec<Peer>,
-    progress: Progress,
+    // Dialed and accepted connections, all running at once
+    connected: Vec<PeerConn>,
+    next_peer_id: usize,
+    // When to dial out again after failing to
+    redial_at: Option<Instant>,
+    // Peers that couldn't be reached or dropped us, until the next announce
+    failed: HashSet<(Ipv4Addr, u16)>,
+    // Bad pieces per address, banned at MAX_STRIKES
+    strikes: HashMap<Ipv4Addr, u32>,
+    // Pieces some connected peer is downloading
+    claimed: HashSet<usize>,
+    // Pieces verified this run in order, every peer gets a have for each
+    verified: Vec<u32>,
     peer_id: Vec<u8>,
+    have: Bitfield,
+    // Received blocks of pieces we don't have yet
+    partial: HashMap<u32, Bitfield>,
+    download_dir: PathBuf,
+    storage: Box<dyn Storage>,
+    choker: Choker,
+    resume_path: PathBuf,
+    last_resume_save: Instant,
+    tracker_interval: u32,
+    last_announce: u64,
+    web_seeds: Vec<WebSeed>,
+    totals: Totals,
+    // Payload rates of the torrent and each web seed
+    download_rate: RateMeter,
+    upload_rate: RateMeter,
+    seed_meters: Vec<RateMeter>,
+    // Seeds and leechers from the last announce
+    swarm: (Option<u32>, Option<u32>),
+    encryption: EncryptionPolicy,
+    // Peers are tried over uTP first when set
+    utp: Option<UtpSocket>,
+    // Bandwidth caps: shared with other torrents, this torrent's own, and
+    // the rates each peer connection gets
+    global_limits: Limits,
+    limits: Limits,
+    peer_rates: (u64, u64),
+    lan_exempt: bool,
+    alerts: Alerts,
+    control: Arc<Control>,
+    // Caps the connections of every torrent in a session
+    connection_limit: Option<ConnectionLimit>,
+    // Where peers can reach us, as told to trackers
+    listen_port: u16,
+    last_publish: Option<Instant>,
+    // Counters from earlier runs, for ratio and seeding goals
+    history: History,
+    // When we last became a seed, and last uploaded since
+    seeding_since: Option<Instant>,
+    last_upload: Option<Instant>,
+    uploaded_seen: u64,
+    // One per file, and the resulting priority of each piece
+    file_priorities: Vec<FilePriority>,
+    piece_priorities: Vec<FilePriority>,
+    // Pick pieces in order rather than by priority
+    sequential: bool,
+    // When pieces are needed by, they're picked before anything else
+    deadlines: HashMap<usize, Instant>,
+    // Where a reader is in the torrent and since when, the readahead
+    // window past it is time critical too
+    reader: Option<(u64, Instant)>,
+    readahead: u64,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
 impl Torrent {
-    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+    pub fn new(path: &Path, download_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        Torrent::with_alerts(path, download_dir, Alerts::new())
+    }
+
+    /// Like new, posting to `alerts` from the start so TorrentAdded isn't
+    /// missed
+    pub fn with_alerts(
+        path: &Path,
+        download_dir: &Path,
+        alerts: Alerts,
+    ) -> Result<Self, Box<dyn Error>> {
         let torrent_file = TorrentFile::open(path)?;
         let peer_id = rand::thread_rng().gen::<[u8; 20]>().to_vec();
-        let port = 6881; // FIXME: what does this mean?
-        let tracker_response = request_peers(&torrent_file, &peer_id, &port)?;
-        let peers = tracker_response.peers; // FIXME: keep tracker_response.interval?
-        let progress = Progress::new();
-        Ok(Self {
+        let have = Bitfield::new(torrent_file.num_pieces());
+        let storage = Backend::File.open(&torrent_file, download_dir);
+        let resume_path = resume::resume_path(download_dir, &torrent_file.info_hash);
+        let mut torrent = Self {
             torrent_file,
-            peers,
-            progress,
+            peers: vec![],
+            connected: vec![],
+            next_peer_id: 0,
+            redial_at: None,
+            failed: HashSet::new(),
+            strikes: HashMap::new(),
+            claimed: HashSet::new(),
+            verified: vec![],
             peer_id,
-        })
+            have,
+            partial: HashMap::new(),
+            download_dir: download_dir.to_path_buf(),
+            storage,
+            choker: Choker::new(Box::new(TitForTat {
+                slots: UPLOAD_SLOTS,
+            })),
+            resume_path,
+            last_resume_save: Instant::now(),
+            tracker_interval: 0,
+            last_announce: 0,
+            web_seeds: vec![],
+            totals: Totals::default(),
+            download_rate: RateMeter::new(),
+            upload_rate: RateMeter::new(),
+            seed_meters: vec![],
+            swarm: (None, None),
+            encryption: EncryptionPolicy::default(),
+            utp: None,
+            global_limits: Limits::default(),
+            limits: Limits::default(),
+            peer_rates: (0, 0),
+            lan_exempt: true,
+            alerts,
+            control: Arc::new(Control::default()),
+            connection_limit: None,
+            listen_port: 6881,
+            last_publish: None,
+            history: History::default(),
+            seeding_since: None,
+            last_upload: None,
+            uploaded_seen: 0,
+            file_priorities: vec![],
+            piece_priorities: vec![],
+            sequential: false,
+            deadlines: HashMap::new(),
+            reader: None,
+            readahead: READAHEAD,
+        };
+        torrent.file_priorities = vec![FilePriority::Normal; torrent.torrent_file.files.len()];
+        torrent.piece_priorities = vec![FilePriority::Normal; torrent.torrent_file.num_pieces()];
+        let name = torrent.torrent_file.name();
+        torrent.post(AlertKind::TorrentAdded {
+            name: name.to_string(),
+        });
+        torrent.post(AlertKind::MetadataReceived);
+        let url_list = torrent.torrent_file.url_list.iter();
+        let http_seeds = torrent.torrent_file.http_seeds.iter();
+        torrent.web_seeds = url_list
+            .map(|url| WebSeed::new(url, SeedKind::UrlList))
+            .chain(http_seeds.map(|url| WebSeed::new(url, SeedKind::HttpSeed)))
+            .collect();
+        torrent.seed_meters = vec![RateMeter::new(); torrent.web_seeds.len()];
+
+        torrent.load_resume();
+        Ok(torrent)
+    }
+
+    fn announce(&mut self) -> Result<(), Box<dyn Error>> {
+        let port = self.listen_port;
+        let url = self.torrent_file.announce.clone();
+        let tracker_response = match request_peers(&self.torrent_file, &self.peer_id, &port) {
+            Ok(response) => response,
+            Err(e) => {
+                let error = e.to_string();
+                self.post(AlertKind::TrackerError { url, error });
+                return Err(e);
+            }
+        };
+        let peers = tracker_response.peers.len();
+        self.post(AlertKind::TrackerReply { url, peers });
+        self.peers = tracker_response.peers;
+        self.failed.clear();
+        self.tracker_interval = tracker_response.interval;
+        self.swarm = (tracker_response.complete, tracker_response.incomplete);
+        self.last_announce = unix_time();
+        Ok(())
+    }
+
+    /// Add peers found some other way than announcing, unless the torrent
+    /// is private and they didn't come from its trackers
+    pub fn add_peers(&mut self, source: PeerSource, peers: Vec<Peer>) {
+        if self.torrent_file.allows_peer_source(source) {
+            self.peers.extend(peers);
+        }
+    }
+
+    // Restore progress from the resume file, if there is a usable one.
+    // Pieces in files that changed since it was written are hashed again.
+    fn load_resume(&mut self) {
+        let num_pieces = self.torrent_file.num_pieces();
+        let resume = match ResumeData::load(&self.resume_path) {
+            Ok(resume) => resume,
+            Err(_) => return self.check_all(),
+        };
+        let pieces = Bitfield::from_bytes(resume.pieces.to_vec());
+        if resume.info_hash.as_slice() != self.torrent_file.info_hash.as_slice()
+            || !pieces.is_valid(num_pieces)
+        {
+            info!("ignoring resume data for another torrent");
+            return self.check_all();
+        }
+
+        let priorities: Option<Vec<_>> = resume
+            .file_priorities
+            .iter()
+            .map(|&n| FilePriority::from_u8(n))
+            .collect();
+        match priorities {
+            Some(priorities) if priorities.len() == self.file_priorities.len() => {
+                // Before anything is read, data of skipped files is elsewhere
+                if let Err(e) = self.set_file_priorities(priorities) {
+                    warn!("ignoring file priorities: {}", e);
+                }
+            }
+            _ => {}
+        }
+        let files = storage::layout(&self.download_dir, &self.torrent_file.files);
+        let part_path = partfile::part_path(&self.download_dir, &self.torrent_file.info_hash);
+        let skipped: Vec<bool> = self
+            .file_priorities
+            .iter()
+            .map(|&p| p == FilePriority::Skip)
+            .collect();
+        let stale = resume.stale_pieces(&self.torrent_file, &files, &part_path, &skipped);
+        self.peers = resume.peers();
+        for index in 0..num_pieces {
+            if pieces.has_piece(index) && (!stale.has_piece(index) || self.check_piece(index)) {
+                self.have.set_piece(index);
+            }
+        }
+        for partial in resume.partial_pieces {
+            let index = partial.index as usize;
+            if index < num_pieces && !self.have.has_piece(index) && !stale.has_piece(index) {
+                let blocks = Bitfield::from_bytes(partial.blocks.to_vec());
+                if blocks.is_valid(self.num_blocks(index)) {
+                    self.partial.insert(partial.index, blocks);
+                }
+            }
+        }
+
+        self.tracker_interval = resume.tracker_interval;
+        self.last_announce = resume.last_announce;
+        self.history = History {
+            downloaded: resume.downloaded,
+            uploaded: resume.uploaded,
+            seeding_time: Duration::from_secs(resume.seeding_time),
+            idle_time: Duration::from_secs(resume.idle_time),
+        };
+    }
+
+    // Without resume data, whatever is already on disk has to be hashed
+    fn check_all(&mut self) {
+        if !self.download_dir.exists() {
+            return;
+        }
+        self.have = verify::verify(&self.torrent_file, &self.download_dir, |_| {});
+    }
+
+    /// Write everything needed to pick up where we left off
+    pub fn save_resume(&mut self) -> Result<(), Box<dyn Error>> {
+        // File mtimes must include everything written so far
+        if let Err(e) = self.storage.flush() {
+            return Err(self.storage_error(e));
+        }
+        let files = storage::layout(&self.download_dir, &self.torrent_file.files);
+        let history = self.history();
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(self.torrent_file.info_hash.clone()),
+            pieces: ByteBuf::from(self.have.as_bytes().to_vec()),
+            partial_pieces: self
+                .partial
+                .iter()
+                .map(|(index, blocks)| PartialPiece {
+                    index: *index,
+                    blocks: ByteBuf::from(blocks.as_bytes().to_vec()),
+                })
+                .collect(),
+            files: resume::file_states(&files),
+            peers: ByteBuf::from(resume::peers_to_bytes(&self.peers)),
+            tracker_interval: self.tracker_interval,
+            last_announce: self.last_announce,
+            downloaded: history.downloaded,
+            uploaded: history.uploaded,
+            seeding_time: history.seeding_time.as_secs(),
+            idle_time: history.idle_time.as_secs(),
+            file_priorities: self.file_priorities.iter().map(|p| p.to_u8()).collect(),
+            part_file: resume::file_state(&partfile::part_path(
+                &self.download_dir,
+                &self.torrent_file.info_hash,
+            )),
+        };
+        resume.save(&self.resume_path)?;
+        self.last_resume_save = Instant::now();
+        Ok(())
+    }
+
+    fn check_piece(&mut self, index: usize) -> bool {
+        let v1 = match self.torrent_file.piece_hashes.get(index) {
+            Some(expected) => {
+                matches!(self.storage.hash_piece(index as u32), Ok(hash) if hash == *expected)
+            }
+            None => self.torrent_file.is_v2_only(),
+        };
+        v1 && self.check_piece_v2(index)
+    }
+
+    // Hybrid and v2 only torrents have to match their v2 merkle hashes too
+    fn check_piece_v2(&mut self, index: usize) -> bool {
+        let (file, piece, length) = match self.torrent_file.v2_piece(index) {
+            Some(location) => location,
+            None => return self.torrent_file.v2.is_none(),
+        };
+        match self.storage.read_block(index as u32, 0, length as u32) {
+            Ok(data) => self.torrent_file.verify_piece_v2(file, piece, &data),
+            Err(_) => false,
+        }
+    }
+
+    fn num_blocks(&self, index: usize) -> usize {
+        self.torrent_file
+            .piece_size(index)
+            .div_ceil(MAX_BLOCK_SIZE as u64) as usize
     }
 
     pub fn download(&mut self) -> Result<(), Box<dyn Error>> {
-        let peer = self.peers.pop().ok_or("ERR: No more peers".to_string())?;
+        let result = self.run();
+        let reason = match &result {
+            Ok(()) => "done".to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.disconnected(reason);
+        // Keep progress however we stopped
+        let saved = self.save_resume();
+        result.and(saved)
+    }
+
+    fn connect(&mut self, peer: &Peer, slot: Option<Slot>) -> Result<(), Box<dyn Error>> {
         // FIXME: clones are whack
-        let mut conn = Connection::connect(
-            peer.clone(),
-            self.torrent_file.info_hash.clone(),
-            self.peer_id.clone(),
-        )?;
+        let info_hash = self.torrent_file.info_hash.clone();
+        let num_pieces = self.torrent_file.num_pieces();
+        let utp = self.utp.as_ref().and_then(|socket| {
+            Connection::connect_utp(
+                socket,
+                peer.clone(),
+                info_hash.clone(),
+                self.peer_id.clone(),
+                num_pieces,
+                self.encryption,
+            )
+            .ok()
+        });
+        // Plenty of peers only speak TCP
+        let conn = match utp {
+            Some(conn) => conn,
+            None => Connection::connect(
+                peer.clone(),
+                info_hash,
+                self.peer_id.clone(),
+                num_pieces,
+                self.encryption,
+            )?,
+        };
+        self.attach(conn, slot, true)
+    }
 
-        while self.progress.index < self.torrent_file.length {
-            // TODO: handle failures by switching to new peer
-            // Perhaps new_peer method could help here and ^^
-            let piece = self.download_piece(&mut conn);
+    // Keep a connection we dialed ourselves, trying each known peer in
+    // turn. Failing that, go on with web seeds and whoever connected to us,
+    // if there's anyone.
+    fn dial(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.connected.iter().any(|peer| peer.outbound)
+            || self.redial_at.is_some_and(|at| Instant::now() < at)
+        {
+            return Ok(());
         }
+        self.add_control_peers();
+        let error = loop {
+            let peer = match self.next_peer() {
+                Some(peer) => peer,
+                None => break "ERR: No more peers",
+            };
+            let slot = match &self.connection_limit {
+                Some(limit) => match limit.try_acquire() {
+                    Some(slot) => Some(slot),
+                    None => break "ERR: Connection limit reached",
+                },
+                None => None,
+            };
+            match self.connect(&peer, slot) {
+                Ok(()) => {
+                    self.redial_at = None;
+                    return Ok(());
+                }
+                Err(e) => {
+                    info!("couldn't connect to {}:{}: {}", peer.ip, peer.port, e);
+                    self.failed.insert((peer.ip, peer.port));
+                }
+            }
+        };
+        if self.web_seeds.is_empty() && self.connected.is_empty() {
+            return Err(error.into());
+        }
+        info!("no peer to dial: {}", error);
+        self.redial_at = Some(Instant::now() + REDIAL_INTERVAL);
+        Ok(())
+    }
 
-        self.save()
+    // The newest known peer that isn't connected, failed or banned
+    fn next_peer(&self) -> Option<Peer> {
+        self.peers
+            .iter()
+            .rev()
+            .find(|peer| {
+                !self.failed.contains(&(peer.ip, peer.port))
+                    && !self.is_banned(peer.ip)
+                    && !self.connected.iter().any(|other| {
+                        other.conn.peer.ip == peer.ip && other.conn.peer.port == peer.port
+                    })
+            })
+            .cloned()
+    }
 
-        // Connect to one peer
+    fn is_banned(&self, ip: Ipv4Addr) -> bool {
+        self.strikes
+            .get(&ip)
+            .is_some_and(|&strikes| strikes >= MAX_STRIKES)
+    }
 
-        // Loop: download piece(i) for all i
-        // Optimization: If it fails for some piece, connect to a new peer
-        // Optimization: Threads for every peers, eating from unbounded crossbeam queue, and
-        // feeding results to diff crossbeam queue
+    // Set up a fresh connection, dialed or accepted, next to the others
+    fn attach(
+        &mut self,
+        mut conn: Connection,
+        slot: Option<Slot>,
+        outbound: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut limits = None;
+        if !self.lan_exempt || !ratelimit::is_lan(IpAddr::V4(conn.peer.ip)) {
+            let peer_limits = Limits::new(self.peer_rates.0, self.peer_rates.1);
+            limits = Some(peer_limits.clone());
+            let scopes = vec![self.global_limits.clone(), self.limits.clone(), peer_limits];
+            conn = conn.throttle(scopes);
+        }
+        conn.send_bitfield(&self.have)?;
+        let peer = PeerConn {
+            id: self.next_peer_id,
+            conn,
+            outbound,
+            _slot: slot,
+            limits,
+            progress: None,
+            haves: self.verified.len(),
+            seen: [0; 5],
+            meters: (RateMeter::new(), RateMeter::new()),
+            bad_pieces: HashSet::new(),
+        };
+        self.next_peer_id += 1;
+        self.post(AlertKind::PeerConnected {
+            peer: peer.address(),
+        });
+        self.connected.push(peer);
+        Ok(())
     }
 
-    // FIXME: can we infer index?
-    fn download_piece(&mut self, conn: &mut Connection) -> Result<(), Box<dyn Error>> {
-        // Make sure we have permission to download
-        if conn.choked {
-            println!("choked\n\n");
-            conn.send_unchoke()?;
-            conn.send_interested()?;
-            self.receive_unchoke(conn)?;
+    // Take on whoever the session's listener accepted for us
+    fn attach_incoming(&mut self) {
+        let incoming: Vec<_> = self.control.incoming.lock().unwrap().drain(..).collect();
+        for (conn, slot) in incoming {
+            if self.is_banned(conn.peer.ip) {
+                info!("turning away banned peer {}", conn.peer.ip);
+                continue;
+            }
+            if let Err(e) = self.attach(conn, slot, false) {
+                info!("incoming peer gone: {}", e);
+            }
+        }
+    }
+
+    fn add_control_peers(&mut self) {
+        let peers: Vec<_> = self.control.peers.lock().unwrap().drain(..).collect();
+        for (source, peer) in peers {
+            self.add_peers(source, vec![peer]);
+        }
+    }
+
+    // Let go of a peer, its piece goes back to the picker. Peers we dialed
+    // aren't dialed again until the next announce.
+    fn drop_peer(&mut self, mut peer: PeerConn, reason: String) {
+        if let Some(progress) = peer.progress.take() {
+            self.claimed.remove(&(progress.index as usize));
+        }
+        if peer.outbound {
+            self.failed.insert((peer.conn.peer.ip, peer.conn.peer.port));
         }
+        self.post(AlertKind::PeerDisconnected {
+            peer: peer.address(),
+            reason,
+        });
+    }
 
-        // Download
-        let block_size = 1000;
-        conn.send_request(
-            // FIXME: base conversions are whack
-            self.progress.index as u32,
-            self.progress.requested as u32,
-            block_size,
-        )?;
-        self.receive_piece(conn)
+    // Post PeerDisconnected for every connected peer
+    fn disconnected(&mut self, reason: String) {
+        for peer in std::mem::take(&mut self.connected) {
+            self.drop_peer(peer, reason.clone());
+        }
     }
 
-    fn receive_unchoke(&mut self, conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.apply_control()?;
+        // Peers from the resume data are good until the next announce is due
+        if self.peers.is_empty() || unix_time() >= self.last_announce + self.tracker_interval as u64
+        {
+            match self.announce() {
+                Ok(()) => {}
+                // Seeds and web seeds get by without the tracker
+                Err(e) if self.is_finished() || !self.web_seeds.is_empty() => {
+                    warn!("announce failed: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Back to downloading whenever more files are wanted
         loop {
-            if let Message::Unchoke = Message::read(&conn.stream)? {
-                conn.choked = false;
-                println!("Unchoked");
+            if !self.is_finished() {
+                self.leech()?;
+                if self.control.is_stopped() {
+                    return Ok(());
+                }
+            }
+            self.seeding_since = Some(Instant::now());
+            self.last_upload = None;
+            self.publish();
+
+            // Keep serving peers now that we have everything, the ones we
+            // have and whoever connects to us
+            while self.is_finished() {
+                if self.control.is_stopped() {
+                    return Ok(());
+                }
+                self.attach_incoming();
+                if self.connected.is_empty() {
+                    thread::sleep(TICK_INTERVAL);
+                    self.update_rates();
+                } else {
+                    self.poll()?;
+                }
+                self.apply_control()?;
+            }
+            self.stop_seeding();
+        }
+    }
+
+    // Download every wanted piece, from peers and web seeds
+    fn leech(&mut self) -> Result<(), Box<dyn Error>> {
+        while !self.is_finished() {
+            if self.control.is_stopped() {
                 return Ok(());
             }
+            self.apply_control()?;
+            self.attach_incoming();
+            self.dial()?;
+
+            // Time critical pieces go to whichever source is faster, and
+            // web seeds have everything so they get what no peer has
+            let unclaimed = |i| !self.claimed.contains(&i);
+            let urgent = self
+                .pick(unclaimed)
+                .filter(|&i| self.deadline(i).is_some() && self.web_seed_faster(i));
+            let lacking = self.pick(|i| {
+                unclaimed(i)
+                    && !self
+                        .connected
+                        .iter()
+                        .any(|peer| peer.conn.bitfield.has_piece(i))
+            });
+            let fetched = match urgent.or(lacking) {
+                Some(index) => self.web_seed_piece(index)?,
+                None => false,
+            };
+
+            if !self.connected.is_empty() {
+                self.poll()?;
+            } else if !fetched {
+                self.wait_for_web_seeds()?;
+            }
         }
+
+        self.post(AlertKind::TorrentFinished);
+        self.save_resume()
     }
-    fn receive_piece(&mut self, conn: &mut Connection) -> Result<(), Box<dyn Error>> {
-        loop {
-            match Message::read(&conn.stream)? {
-                Message::Choke => {
-                    conn.choked = true;
-                    return Ok(());
+
+    // Downloading again, the seeding counters stop here
+    fn stop_seeding(&mut self) {
+        let history = self.history();
+        self.history.seeding_time = history.seeding_time;
+        self.history.idle_time = history.idle_time;
+        self.seeding_since = None;
+        self.last_upload = None;
+    }
+
+    // Pick up what the session asked for
+    fn apply_control(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(sequential) = self.control.sequential.lock().unwrap().take() {
+            self.sequential = sequential;
+        }
+        let deadlines: Vec<_> = self.control.deadlines.lock().unwrap().drain(..).collect();
+        for (index, deadline) in deadlines {
+            match deadline {
+                Some(deadline) => self.deadlines.insert(index, deadline),
+                None => self.deadlines.remove(&index),
+            };
+        }
+        let read_position = self.control.read_position.lock().unwrap().take();
+        if let Some(offset) = read_position {
+            self.set_read_position(offset);
+        }
+        let priorities = self.control.file_priorities.lock().unwrap().take();
+        match priorities {
+            Some(priorities) => self.set_file_priorities(priorities),
+            None => Ok(()),
+        }
+    }
+
+    // The missing piece to get next from `pieces`: the one due first, then
+    // the most wanted. Among equally wanted pieces the rarest among
+    // connected peers goes first, or the lowest index in sequential mode.
+    fn pick(&self, pieces: impl Fn(usize) -> bool) -> Option<usize> {
+        (0..self.torrent_file.num_pieces())
+            .filter(|&i| self.is_wanted(i) && !self.have.has_piece(i) && pieces(i))
+            .max_by_key(|&i| {
+                let deadline = self.deadline(i);
+                let rarity = match self.sequential {
+                    true => Reverse(0),
+                    false => Reverse(self.availability(i)),
+                };
+                let priority = self.piece_priorities[i];
+                (
+                    deadline.is_some(),
+                    Reverse(deadline),
+                    priority,
+                    rarity,
+                    Reverse(i),
+                )
+            })
+    }
+
+    // How many connected peers have the piece
+    fn availability(&self, index: usize) -> usize {
+        self.connected
+            .iter()
+            .filter(|peer| peer.conn.bitfield.has_piece(index))
+            .count()
+    }
+
+    // Whether an idle peer that has the piece and lets us download is
+    // getting data faster than `rate`, and so should get it instead
+    fn faster_peer_free(&self, index: usize, rate: u64) -> bool {
+        self.connected.iter().any(|other| {
+            other.progress.is_none()
+                && !other.conn.peer_choking
+                && other.conn.bitfield.has_piece(index)
+                && !other.bad_pieces.contains(&index)
+                && other.meters.0.rate() > rate
+        })
+    }
+
+    // When the piece is needed by, if it's time critical
+    fn deadline(&self, index: usize) -> Option<Instant> {
+        let ahead = self.reader.and_then(|(offset, since)| {
+            let piece_length = self.torrent_file.piece_length();
+            let first = (offset / piece_length) as usize;
+            let end = (offset + self.readahead).div_ceil(piece_length) as usize;
+            (first..end)
+                .contains(&index)
+                .then(|| since + READAHEAD_STEP * (index - first) as u32)
+        });
+        [self.deadlines.get(&index).copied(), ahead]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    // A piece is in, its deadline no longer matters and every peer gets
+    // told
+    fn piece_verified(&mut self, index: usize) {
+        self.have.set_piece(index);
+        if let Some(deadline) = self.deadlines.remove(&index) {
+            let late = Instant::now().saturating_duration_since(deadline);
+            if late > Duration::ZERO {
+                debug!("piece {} missed its deadline by {:?}", index, late);
+            }
+        }
+        self.post(AlertKind::PieceVerified { index });
+        self.verified.push(index as u32);
+    }
+
+    // The available web seed downloading fastest
+    fn fastest_web_seed(&self, now: Instant) -> Option<usize> {
+        (0..self.web_seeds.len())
+            .filter(|&seed| self.web_seeds[seed].is_available(now))
+            .max_by_key(|&seed| (self.seed_meters[seed].rate(), Reverse(seed)))
+    }
+
+    // Whether a web seed would get the piece sooner than any peer that has
+    // it and lets us download
+    fn web_seed_faster(&self, index: usize) -> bool {
+        let peer_rate = self
+            .connected
+            .iter()
+            .filter(|peer| peer.conn.bitfield.has_piece(index) && !peer.conn.peer_choking)
+            .map(|peer| peer.meters.0.rate())
+            .max();
+        match peer_rate {
+            Some(peer_rate) => self
+                .fastest_web_seed(Instant::now())
+                .is_some_and(|seed| self.seed_meters[seed].rate() > peer_rate),
+            None => true,
+        }
+    }
+
+    fn is_wanted(&self, index: usize) -> bool {
+        self.piece_priorities[index] != FilePriority::Skip
+    }
+
+    // Fetch the piece from the fastest web seed that isn't backing off.
+    // Returns whether one was tried.
+    fn web_seed_piece(&mut self, index: usize) -> Result<bool, Box<dyn Error>> {
+        let seed = match self.fastest_web_seed(Instant::now()) {
+            Some(seed) => seed,
+            None => return Ok(false),
+        };
+
+        let piece = match self.web_seeds[seed].fetch_piece(&self.torrent_file, index) {
+            Ok(piece) => piece,
+            Err(e) => {
+                warn!("web seed {} failed: {}", self.web_seeds[seed].url, e);
+                return Ok(true);
+            }
+        };
+        self.write_block(index as u32, 0, &piece)?;
+        self.partial.remove(&(index as u32));
+        self.web_seeds[seed].downloaded += piece.len() as u64;
+        self.totals.downloaded.payload += piece.len() as u64;
+        self.update_rates();
+        if !self.check_piece(index) {
+            self.totals.failed += piece.len() as u64;
+            self.post(AlertKind::HashFailed { index });
+            warn!(
+                "web seed {} sent a bad piece {}",
+                self.web_seeds[seed].url, index
+            );
+            self.web_seeds[seed].failed(Instant::now());
+            return Ok(true);
+        }
+
+        self.piece_verified(index);
+        Ok(true)
+    }
+
+    // With no peer, sleep until the first web seed can be tried again
+    fn wait_for_web_seeds(&self) -> Result<(), Box<dyn Error>> {
+        let next = self
+            .web_seeds
+            .iter()
+            .filter_map(|s| s.retry_at())
+            .min()
+            .ok_or("ERR: No more peers or web seeds".to_string())?;
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        Ok(())
+    }
+
+    // Give every peer a turn. Peers that fail are dropped, failing storage
+    // ends the download.
+    fn poll(&mut self) -> Result<(), Box<dyn Error>> {
+        // Only wait on peers while none has anything to read
+        let busy = self
+            .connected
+            .iter()
+            .any(|peer| peer.conn.has_pending_input().unwrap_or(true));
+        let wait = if busy { Duration::ZERO } else { POLL_WAIT };
+
+        // Each peer is stepped with all the others still in place, so the
+        // picker can see who else has a piece
+        for _ in 0..self.connected.len() {
+            let mut peer = self.connected.remove(0);
+            match self.step(&mut peer, wait) {
+                Ok(()) => self.connected.push(peer),
+                Err(e) if e.is::<StorageFailed>() => {
+                    self.connected.push(peer);
+                    return Err(e);
+                }
+                Err(e) => {
+                    info!("peer {} gone: {}", peer.address(), e);
+                    self.drop_peer(peer, e.to_string());
                 }
-                Message::Piece(index, requested, data) => {
-                    println!("got piece: {} {} {}", index, requested, data.len());
+            }
+        }
+
+        self.update_rates();
+        if self.last_resume_save.elapsed() >= RESUME_INTERVAL {
+            self.save_resume()?;
+        }
+        self.rechoke();
+        Ok(())
+    }
+
+    // Handle the peer's next message, or upload a queued block if it has
+    // nothing new for us. Reading first lets cancels take effect. Then keep
+    // our requests to it going.
+    fn step(&mut self, peer: &mut PeerConn, wait: Duration) -> Result<(), Box<dyn Error>> {
+        while peer.haves < self.verified.len() {
+            peer.conn.send_have(self.verified[peer.haves])?;
+            peer.haves += 1;
+        }
+        let conn = &mut peer.conn;
+        if !conn.upload_queue.is_empty() && !conn.has_pending_input()? {
+            let request = conn.upload_queue.pop_front().unwrap();
+            self.serve_request(conn, request)?;
+        } else if conn.wait_for_input(wait)? {
+            if let Some(msg) = conn.read_message()? {
+                self.handle_message(peer, msg)?;
+            }
+        }
+        peer.conn.tick(Instant::now())?;
+        self.request_blocks(peer)?;
+
+        self.totals.count(&peer.conn, &mut peer.seen);
+        let now = Instant::now();
+        peer.meters.0.update(now, peer.conn.downloaded);
+        peer.meters.1.update(now, peer.conn.uploaded);
+        Ok(())
+    }
+
+    // Keep a piece going with the peer, one it has and nobody else is
+    // getting, with a backlog of requests out. Pieces it sent bad data for
+    // are only retried with it when no other peer has them, and time
+    // critical ones go to the fastest peer free to take them.
+    fn request_blocks(&mut self, peer: &mut PeerConn) -> Result<(), Box<dyn Error>> {
+        if peer.progress.is_none() {
+            let bitfield = &peer.conn.bitfield;
+            let elsewhere = |i| {
+                self.connected
+                    .iter()
+                    .any(|other| other.conn.bitfield.has_piece(i))
+            };
+            let rate = peer.meters.0.rate();
+            let usable = |i| {
+                bitfield.has_piece(i)
+                    && !self.claimed.contains(&i)
+                    && (!peer.bad_pieces.contains(&i) || !elsewhere(i))
+                    && (self.deadline(i).is_none() || !self.faster_peer_free(i, rate))
+            };
+            match self.pick(usable) {
+                Some(index) => peer.progress = Some(self.claim(index)),
+                None => {
+                    // Wait for the peer to announce more pieces, the idle
+                    // timeout closes the connection if it never does
+                    if peer.conn.am_interested {
+                        peer.conn.send_not_interested()?;
+                    }
                     return Ok(());
                 }
-                _ => println!("ignoring message"),
             }
         }
+
+        // Make sure we have permission to download
+        if !peer.conn.am_interested {
+            peer.conn.send_interested()?;
+        }
+        if peer.conn.peer_choking {
+            return Ok(());
+        }
+        let progress = peer.progress.as_mut().unwrap();
+        let index = progress.index as u32;
+        let size = self.torrent_file.piece_size(index as usize);
+        while progress.backlog < MAX_BACKLOG && progress.requested < size {
+            let block_size = (MAX_BLOCK_SIZE as u64).min(size - progress.requested);
+            let block = (progress.requested / MAX_BLOCK_SIZE as u64) as usize;
+            if !self.partial[&index].has_piece(block) {
+                // FIXME: base conversions are whack
+                peer.conn
+                    .send_request(index, progress.requested as u32, block_size as u32)?;
+                progress.backlog += 1;
+            }
+            progress.requested += block_size;
+        }
+        Ok(())
+    }
+
+    // Start on a piece. Blocks from before a restart are already on disk.
+    fn claim(&mut self, index: usize) -> Progress {
+        let size = self.torrent_file.piece_size(index);
+        let num_blocks = self.num_blocks(index);
+        let blocks = self
+            .partial
+            .entry(index as u32)
+            .or_insert_with(|| Bitfield::new(num_blocks));
+        let downloaded = (0..num_blocks)
+            .filter(|&block| blocks.has_piece(block))
+            .map(|block| (MAX_BLOCK_SIZE as u64).min(size - block as u64 * MAX_BLOCK_SIZE as u64))
+            .sum();
+        self.claimed.insert(index);
+        Progress {
+            index: index as u64,
+            downloaded,
+            ..Progress::new()
+        }
     }
 
-    fn save(&self) -> Result<(), Box<dyn Error>> {
-        // Once we're done downloading, save to a (use TorrentFile.name)
+    // The last block of the peer's piece is in, check the piece. A bad one
+    // goes back to the picker and counts against the peer's address.
+    fn piece_received(&mut self, peer: &mut PeerConn) -> Result<(), Box<dyn Error>> {
+        let index = peer.progress.take().unwrap().index as usize;
+        self.claimed.remove(&index);
+        self.partial.remove(&(index as u32));
+        if !self.check_piece(index) {
+            self.totals.failed += self.torrent_file.piece_size(index);
+            self.post(AlertKind::HashFailed { index });
+            peer.bad_pieces.insert(index);
+            let strikes = self.strikes.entry(peer.conn.peer.ip).or_insert(0);
+            *strikes += 1;
+            warn!("peer {} sent a bad piece {}", peer.address(), index);
+            if *strikes >= MAX_STRIKES {
+                return Err(format!("ERR: Banned after {} bad pieces", strikes).into());
+            }
+            return Ok(());
+        }
+        self.piece_verified(index);
         Ok(())
     }
+
+    /// Replace the default tit-for-tat choker, e.g. with FastestUpload on
+    /// seed boxes
+    pub fn set_choke_policy(&mut self, policy: Box<dyn ChokePolicy>) {
+        self.choker = Choker::new(policy);
+    }
+
+    /// Whether peer connections are encrypted, see mse
+    pub fn set_encryption(&mut self, policy: EncryptionPolicy) {
+        self.encryption = policy;
+    }
+
+    /// Reach peers over uTP from `socket`, falling back to TCP
+    pub fn set_utp(&mut self, socket: UtpSocket) {
+        self.utp = Some(socket);
+    }
+
+    /// Cap this torrent together with others holding the same limits
+    pub fn set_global_limits(&mut self, limits: Limits) {
+        self.global_limits = limits;
+    }
+
+    /// This torrent's own caps, which can be changed while it runs
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Caps for each peer connection in bytes per second, 0 for none
+    pub fn set_peer_limits(&mut self, upload: u64, download: u64) {
+        self.peer_rates = (upload, download);
+        for limits in self
+            .connected
+            .iter()
+            .filter_map(|peer| peer.limits.as_ref())
+        {
+            limits.upload.set_rate(upload);
+            limits.download.set_rate(download);
+        }
+    }
+
+    /// Whether peers on the local network skip the caps, on by default
+    pub fn set_lan_exempt(&mut self, exempt: bool) {
+        self.lan_exempt = exempt;
+    }
+
+    /// Store data somewhere other than plain files in the download directory
+    pub fn set_storage(&mut self, storage: Box<dyn Storage>) {
+        self.storage = storage;
+    }
+
+    /// One priority per file, in the torrent's order. Skipped files aren't
+    /// downloaded, though pieces they share with wanted files are, with
+    /// their share going to a partfile.
+    pub fn set_file_priorities(
+        &mut self,
+        priorities: Vec<FilePriority>,
+    ) -> Result<(), Box<dyn Error>> {
+        if priorities.len() != self.file_priorities.len() {
+            return Err(format!(
+                "ERR: {} priorities for {} files",
+                priorities.len(),
+                self.file_priorities.len()
+            )
+            .into());
+        }
+        if let Err(e) = self.storage.set_file_priorities(&priorities) {
+            return Err(self.storage_error(e));
+        }
+        let files = storage::layout(&self.download_dir, &self.torrent_file.files);
+        self.piece_priorities = storage::piece_priorities(
+            &files,
+            &priorities,
+            self.torrent_file.piece_length(),
+            self.torrent_file.num_pieces(),
+        );
+        self.file_priorities = priorities;
+        Ok(())
+    }
+
+    pub fn file_priorities(&self) -> &[FilePriority] {
+        &self.file_priorities
+    }
+
+    /// Download pieces in order, e.g. to play a file while it downloads.
+    /// Deadlines and file priorities still come first, the order only
+    /// decides between equally wanted pieces.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Have the piece picked ahead of others, earliest deadline first, and
+    /// fetched from the fastest source
+    pub fn set_piece_deadline(
+        &mut self,
+        index: usize,
+        deadline: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        if index >= self.torrent_file.num_pieces() {
+            return Err(format!("ERR: No piece {}", index).into());
+        }
+        self.deadlines.insert(index, Instant::now() + deadline);
+        Ok(())
+    }
+
+    pub fn reset_piece_deadline(&mut self, index: usize) {
+        self.deadlines.remove(&index);
+    }
+
+    /// Where a reader is in the torrent's data, None once it's done. The
+    /// readahead window past it is fetched like pieces with deadlines, the
+    /// nearest first.
+    pub fn set_read_position(&mut self, offset: Option<u64>) {
+        self.reader = offset.map(|offset| (offset, Instant::now()));
+    }
+
+    /// How many bytes past the read position to fetch early
+    pub fn set_readahead(&mut self, window: u64) {
+        self.readahead = window;
+    }
+
+    /// Share a peer id, e.g. across a Session's torrents
+    pub fn set_peer_id(&mut self, peer_id: Vec<u8>) {
+        self.peer_id = peer_id;
+    }
+
+    /// The port announced to trackers
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = port;
+    }
+
+    /// Post to `alerts` from now on
+    pub fn set_alerts(&mut self, alerts: Alerts) {
+        self.alerts = alerts;
+    }
+
+    /// Hand steering to whoever holds `control`
+    pub fn set_control(&mut self, control: Arc<Control>) {
+        self.control = control;
+    }
+
+    /// Only dial out while `limit` has room
+    pub fn set_connection_limit(&mut self, limit: ConnectionLimit) {
+        self.connection_limit = Some(limit);
+    }
+
+    /// Alerts this torrent posts to
+    pub fn alerts(&self) -> &Alerts {
+        &self.alerts
+    }
+
+    /// Get this torrent's alerts from now on
+    pub fn subscribe(&self) -> Receiver<Alert> {
+        self.alerts.subscribe()
+    }
+
+    fn post(&self, kind: AlertKind) {
+        self.alerts.post(&self.torrent_file.info_hash, kind);
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.storage
+            .write_block(index, begin, data)
+            .map_err(|e| self.storage_error(e))
+    }
+
+    // Report a failed read or write before handing the error on
+    fn storage_error(&self, e: io::Error) -> Box<dyn Error> {
+        self.post(AlertKind::StorageError {
+            error: e.to_string(),
+        });
+        Box::new(StorageFailed(e))
+    }
+
+    /// Transfer totals for every connected peer and web or HTTP seed
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        let seeds = self
+            .web_seeds
+            .iter()
+            .zip(&self.seed_meters)
+            .map(|(seed, rate)| PeerStats {
+                kind: match seed.kind {
+                    SeedKind::UrlList => PeerKind::WebSeed,
+                    SeedKind::HttpSeed => PeerKind::HttpSeed,
+                },
+                address: seed.url.clone(),
+                downloaded: seed.downloaded,
+                uploaded: 0,
+                protocol_downloaded: 0,
+                protocol_uploaded: 0,
+                download_rate: rate.rate(),
+                upload_rate: 0,
+                is_seed: true,
+            });
+        let num_pieces = self.torrent_file.num_pieces();
+        self.connected
+            .iter()
+            .map(|peer| peer.stats(num_pieces))
+            .chain(seeds)
+            .collect()
+    }
+
+    /// A snapshot of the torrent's progress and transfer statistics
+    pub fn stats(&self) -> TorrentStats {
+        let num_pieces = self.torrent_file.num_pieces();
+        let wanted = (0..num_pieces).filter(|&i| self.is_wanted(i));
+        let total_wanted = wanted
+            .clone()
+            .map(|i| self.torrent_file.piece_size(i))
+            .sum();
+        let missing = wanted.filter(|&i| !self.have.has_piece(i));
+        let left = missing.map(|i| self.torrent_file.piece_size(i)).sum();
+        let download_rate = self.download_rate.rate();
+        let history = self.history();
+        TorrentStats {
+            pieces: (0..num_pieces).filter(|&i| self.have.has_piece(i)).count(),
+            num_pieces,
+            total_size: self.torrent_file.length,
+            total_wanted,
+            left,
+            downloaded: self.totals.downloaded,
+            uploaded: self.totals.uploaded,
+            download_rate,
+            upload_rate: self.upload_rate.rate(),
+            eta: stats::eta(left, download_rate),
+            wasted: self.totals.wasted,
+            failed: self.totals.failed,
+            connected_peers: self.connected.len(),
+            seeds: self.swarm.0,
+            leechers: self.swarm.1,
+            peers: self.peer_stats(),
+            all_time_downloaded: history.downloaded,
+            all_time_uploaded: history.uploaded,
+            seeding_time: history.seeding_time,
+            idle_time: history.idle_time,
+        }
+    }
+
+    // Counters over every run including this one
+    fn history(&self) -> History {
+        let seeding = self.seeding_since.map(|since| since.elapsed());
+        // Only seeding counts as idle, and any upload starts it over
+        let idle_time = match (self.last_upload, seeding) {
+            (Some(last), _) => last.elapsed(),
+            (None, Some(seeding)) => self.history.idle_time + seeding,
+            (None, None) => self.history.idle_time,
+        };
+        History {
+            downloaded: self.history.downloaded + self.totals.downloaded.payload,
+            uploaded: self.history.uploaded + self.totals.uploaded.payload,
+            seeding_time: self.history.seeding_time + seeding.unwrap_or_default(),
+            idle_time,
+        }
+    }
+
+    // Let whoever holds the control see how we're doing
+    fn publish(&mut self) {
+        *self.control.stats.lock().unwrap() = Some(self.stats());
+        self.last_publish = Some(Instant::now());
+    }
+
+    fn update_rates(&mut self) {
+        let now = Instant::now();
+        self.download_rate
+            .update(now, self.totals.downloaded.payload);
+        self.upload_rate.update(now, self.totals.uploaded.payload);
+        for (seed, rate) in self.web_seeds.iter().zip(&mut self.seed_meters) {
+            rate.update(now, seed.downloaded);
+        }
+        if self.totals.uploaded.payload > self.uploaded_seen {
+            self.uploaded_seen = self.totals.uploaded.payload;
+            if self.seeding_since.is_some() {
+                self.last_upload = Some(now);
+            }
+        }
+        if self
+            .last_publish
+            .is_none_or(|last| now.duration_since(last) >= TICK_INTERVAL)
+        {
+            self.publish();
+        }
+    }
+
+    /// Every piece is there
+    pub fn is_complete(&self) -> bool {
+        (0..self.torrent_file.num_pieces()).all(|i| self.have.has_piece(i))
+    }
+
+    /// Every piece of the files we want is there
+    pub fn is_finished(&self) -> bool {
+        (0..self.torrent_file.num_pieces()).all(|i| !self.is_wanted(i) || self.have.has_piece(i))
+    }
+
+    // Peers we can't tell about a change of mind are dropped
+    fn rechoke(&mut self) {
+        let now = Instant::now();
+        let peers: Vec<PeerInfo> = self
+            .connected
+            .iter()
+            .map(|peer| PeerInfo {
+                id: peer.id,
+                interested: peer.conn.peer_interested,
+                snubbed: peer.conn.is_snubbed(now),
+                downloaded: peer.conn.downloaded,
+                uploaded: peer.conn.uploaded,
+                connected_at: peer.conn.connected_at,
+            })
+            .collect();
+        let seeding = self.is_finished();
+        let unchoked = match self.choker.tick(now, &peers, seeding) {
+            Some(unchoked) => unchoked,
+            None => return,
+        };
+
+        for mut peer in std::mem::take(&mut self.connected) {
+            let unchoke = unchoked.contains(&peer.id);
+            let result = if unchoke && peer.conn.am_choking {
+                peer.conn.send_unchoke()
+            } else if !unchoke && !peer.conn.am_choking {
+                peer.conn.send_choke()
+            } else {
+                Ok(())
+            };
+            match result {
+                Ok(()) => self.connected.push(peer),
+                Err(e) => self.drop_peer(peer, e.to_string()),
+            }
+        }
+    }
+
+    fn handle_message(&mut self, peer: &mut PeerConn, msg: Message) -> Result<(), Box<dyn Error>> {
+        let conn = &mut peer.conn;
+        // Connection state was already updated by read_message
+        match msg {
+            Message::Choke => {
+                // Outstanding requests are discarded by the peer, ask again
+                // for whatever is still missing once unchoked
+                if let Some(progress) = peer.progress.as_mut() {
+                    progress.requested = 0;
+                    progress.backlog = 0;
+                }
+            }
+            Message::Unchoke => debug!("Unchoked"),
+            // Don't make the peer wait for the next round if a slot is free
+            Message::Interested if self.choker.unchoked().len() < UPLOAD_SLOTS => {
+                self.choker.force_rechoke()
+            }
+            Message::Request(index, begin, length) => {
+                if length > MAX_BLOCK_SIZE {
+                    return Err(Box::new(DisconnectReason::ProtocolViolation(format!(
+                        "requested {} byte block",
+                        length
+                    ))));
+                }
+                if !self.torrent_file.is_valid_block(index, begin, length)
+                    || !self.have.has_piece(index as usize)
+                {
+                    debug!("rejecting request: {} {} {}", index, begin, length);
+                    return Ok(());
+                }
+                conn.queue_request(BlockRequest {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+            Message::Piece(index, begin, data) => {
+                trace!("got piece: {} {} {}", index, begin, data.len());
+                // Out of range blocks would only fail in storage, which is
+                // no reason to stop the download
+                if !self
+                    .torrent_file
+                    .is_valid_block(index, begin, data.len() as u32)
+                {
+                    return Err(Box::new(DisconnectReason::ProtocolViolation(format!(
+                        "block of {} bytes at {} in piece {}",
+                        data.len(),
+                        begin,
+                        index
+                    ))));
+                }
+                let size = self.torrent_file.piece_size(index as usize);
+                let block = (begin / MAX_BLOCK_SIZE) as usize;
+                let expected = (MAX_BLOCK_SIZE as u64).min(size.saturating_sub(begin as u64));
+                let progress = peer.progress.as_mut();
+                let (progress, blocks) = match (progress, self.partial.get(&index)) {
+                    (Some(progress), Some(blocks)) if index as u64 == progress.index => {
+                        (progress, blocks)
+                    }
+                    _ => {
+                        debug!("ignoring unexpected block");
+                        self.totals.wasted += data.len() as u64;
+                        return Ok(());
+                    }
+                };
+                if begin % MAX_BLOCK_SIZE != 0
+                    || data.len() as u64 != expected
+                    || blocks.has_piece(block)
+                {
+                    debug!("ignoring unexpected block");
+                    self.totals.wasted += data.len() as u64;
+                    return Ok(());
+                }
+                self.write_block(index, begin, &data)?;
+                self.partial.get_mut(&index).unwrap().set_piece(block);
+                progress.downloaded += data.len() as u64;
+                progress.backlog = progress.backlog.saturating_sub(1);
+                if progress.downloaded >= size {
+                    self.piece_received(peer)?;
+                }
+            }
+            Message::HashRequest(req) => match self.torrent_file.hashes_for(&req) {
+                Some(hashes) => conn.send_hashes(&req, &hashes)?,
+                None => conn.send_hash_reject(&req)?,
+            },
+            Message::Hashes(req, hashes) if !self.torrent_file.add_hashes(&req, &hashes) => {
+                return Err(Box::new(DisconnectReason::ProtocolViolation(
+                    "hashes don't match the pieces root".to_string(),
+                )));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn serve_request(
+        &mut self,
+        conn: &mut Connection,
+        request: BlockRequest,
+    ) -> Result<(), Box<dyn Error>> {
+        let block = match self
+            .storage
+            .read_block(request.index, request.begin, request.length)
+        {
+            Ok(block) => block,
+            Err(e) => return Err(self.storage_error(e)),
+        };
+        conn.send_piece(request.index, request.begin, &block)
+    }
 }
@@ -0,0 +1,186 @@
+// Where data of skipped files goes. Pieces straddle file boundaries, so
+// downloading a wanted file can mean getting bits of the skipped files next
+// to it. Those bits are kept here instead of creating the skipped files.
+//
+// The file starts with a table of one u32 per piece, its slot plus one or 0
+// for none, followed by piece sized slots. Bytes sit at their offset within
+// the piece.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Where a torrent's partfile lives in its download directory
+pub fn part_path(download_dir: &Path, info_hash: &[u8]) -> PathBuf {
+    let hex_hash: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    download_dir.join(format!(".{}.parts", hex_hash))
+}
+
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    piece_length: u64,
+    // Slot of each piece
+    slots: Vec<Option<u32>>,
+    // Only created once something is written
+    file: Option<File>,
+}
+
+impl PartFile {
+    /// Open the partfile at `path`, with the slots of an earlier run if
+    /// there is one
+    pub fn open(path: &Path, num_pieces: usize, piece_length: u64) -> io::Result<PartFile> {
+        let mut slots = vec![None; num_pieces];
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(mut file) => {
+                let mut table = vec![0; num_pieces * 4];
+                file.read_exact(&mut table)?;
+                for (slot, entry) in slots.iter_mut().zip(table.chunks(4)) {
+                    *slot = BigEndian::read_u32(entry).checked_sub(1);
+                }
+                Some(file)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(PartFile {
+            path: path.to_path_buf(),
+            piece_length,
+            slots,
+            file,
+        })
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.slots[index as usize].is_some()
+    }
+
+    /// Pieces with a slot
+    pub fn pieces(&self) -> Vec<u32> {
+        (0..self.slots.len() as u32)
+            .filter(|&i| self.has_piece(i))
+            .collect()
+    }
+
+    fn slot_offset(&self, slot: u32) -> u64 {
+        (self.slots.len() * 4) as u64 + slot as u64 * self.piece_length
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)?;
+            file.set_len((self.slots.len() * 4) as u64)?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn set_slot(&mut self, index: u32, slot: Option<u32>) -> io::Result<()> {
+        self.slots[index as usize] = slot;
+        let mut entry = [0; 4];
+        BigEndian::write_u32(&mut entry, slot.map_or(0, |slot| slot + 1));
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(index as u64 * 4))?;
+        file.write_all(&entry)
+    }
+
+    /// Store `data` at `offset` within piece `index`
+    pub fn write(&mut self, index: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let slot = match self.slots[index as usize] {
+            Some(slot) => slot,
+            None => {
+                // The lowest free one, so the file only grows when it has to
+                let used: HashSet<u32> = self.slots.iter().flatten().cloned().collect();
+                let slot = (0..).find(|slot| !used.contains(slot)).unwrap();
+                self.set_slot(index, Some(slot))?;
+                slot
+            }
+        };
+        let position = self.slot_offset(slot) + offset;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(data)
+    }
+
+    /// Read from `offset` within piece `index`, which must have a slot
+    pub fn read(&mut self, index: u32, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let slot = self.slots[index as usize].ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("piece {} not in partfile", index),
+            )
+        })?;
+        let position = self.slot_offset(slot) + offset;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(buf)
+    }
+
+    /// Give up the piece's slot. The file goes when the last one does.
+    pub fn free(&mut self, index: u32) -> io::Result<()> {
+        if !self.has_piece(index) {
+            return Ok(());
+        }
+        self.set_slot(index, None)?;
+        if self.slots.iter().all(Option::is_none) {
+            self.file = None;
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_part_file() {
+        let dir = env::temp_dir().join(format!("bittorrent-partfile-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("parts");
+        let mut part = PartFile::open(&path, 10, 16).unwrap();
+        assert!(!path.exists());
+        let mut buf = [0; 4];
+        assert!(part.read(3, 0, &mut buf).is_err());
+
+        part.write(7, 4, b"abcd").unwrap();
+        part.write(3, 12, b"wxyz").unwrap();
+        part.flush().unwrap();
+        assert_eq!(part.pieces(), vec![3, 7]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 40 + 32);
+
+        // Slots survive reopening
+        let mut part = PartFile::open(&path, 10, 16).unwrap();
+        part.read(7, 4, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        part.read(3, 12, &mut buf).unwrap();
+        assert_eq!(&buf, b"wxyz");
+
+        // Freed slots get reused, and the file goes with the last one
+        part.free(7).unwrap();
+        part.write(0, 0, b"1234").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 40 + 32);
+        part.free(0).unwrap();
+        part.free(3).unwrap();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
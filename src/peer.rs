@@ -0,0 +1,582 @@
+// Async peer connections on tokio: one task per peer, coordinated through
+// channels by a Swarm. Blocking code gets the same through PeerStream, a
+// TCP connection driven by a task on a shared runtime, which is what
+// Connection runs TCP peers over.
+
+use crate::connection::Handshake;
+use crate::message::{Message, MessageCodec};
+use crate::tracker::Peer;
+use crate::transport::Transport;
+use futures::future::{self, Future};
+use futures::stream::{self, StreamExt};
+use futures::SinkExt;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self as std_net, IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{self, Runtime};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+use tokio_util::codec::Framed;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest read a PeerStream task does at once, and how many it gets ahead
+// of the reader before it waits. Past that the socket fills up and the peer
+// slows down, which is how download limits reach it.
+const CHUNK_LEN: usize = 16 * 1024;
+const CHUNKS_AHEAD: usize = 16;
+
+/// What the peer tasks report back
+#[derive(Debug)]
+pub enum Event {
+    // Handshake done, messages can be sent
+    Connected(SocketAddr),
+    Message(SocketAddr, Message),
+    // Why the peer went away
+    Closed(SocketAddr, String),
+}
+
+#[derive(Debug)]
+enum Command {
+    Send(Message),
+    Close,
+}
+
+// Everything a peer task waits on
+enum Input {
+    Received(io::Result<Message>),
+    Eof,
+    Command(Command),
+}
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Command>>>>;
+
+/// Peer tasks for one torrent. Methods that spawn need to be called from
+/// within a tokio runtime; see BlockingSwarm for using it without one.
+#[derive(Debug)]
+pub struct Swarm {
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    peers: Peers,
+    events_tx: UnboundedSender<Event>,
+    events: UnboundedReceiver<Event>,
+}
+
+impl Swarm {
+    pub fn new(info_hash: Vec<u8>, peer_id: Vec<u8>) -> Swarm {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        Swarm {
+            info_hash,
+            peer_id,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            events_tx,
+            events,
+        }
+    }
+
+    /// Dial `peer` in the background, the outcome comes as an event
+    pub fn connect(&self, peer: &Peer) {
+        let addr = SocketAddr::new(IpAddr::from(peer.ip), peer.port);
+        let commands = register(&self.peers, addr);
+        let (info_hash, peer_id) = (self.info_hash.clone(), self.peer_id.clone());
+        let events = self.events_tx.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return closed(&events, addr, e.to_string()),
+                Err(_) => return closed(&events, addr, "connect timed out".to_string()),
+            };
+            run(stream, addr, info_hash, peer_id, commands, events).await
+        });
+    }
+
+    /// Accept peers on `addr` in the background. Returns the bound address.
+    pub async fn listen(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let mut listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        let peers = self.peers.clone();
+        let (info_hash, peer_id) = (self.info_hash.clone(), self.peer_id.clone());
+        let events = self.events_tx.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let commands = register(&peers, addr);
+                let task = run(
+                    stream,
+                    addr,
+                    info_hash.clone(),
+                    peer_id.clone(),
+                    commands,
+                    events.clone(),
+                );
+                tokio::spawn(task);
+            }
+        });
+        Ok(local)
+    }
+
+    pub fn send(&self, addr: SocketAddr, msg: Message) -> Result<(), Box<dyn Error>> {
+        let peers = self.peers.lock().unwrap();
+        let commands = peers
+            .get(&addr)
+            .ok_or(format!("ERR: Not connected to {}", addr))?;
+        commands
+            .send(Command::Send(msg))
+            .map_err(|_| format!("ERR: {} is closing", addr))?;
+        Ok(())
+    }
+
+    pub fn close(&self, addr: SocketAddr) {
+        if let Some(commands) = self.peers.lock().unwrap().get(&addr) {
+            let _ = commands.send(Command::Close);
+        }
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Wait for the next thing to happen on any peer
+    pub async fn next_event(&mut self) -> Option<Event> {
+        let event = self.events.recv().await;
+        if let Some(Event::Closed(addr, _)) = &event {
+            self.peers.lock().unwrap().remove(addr);
+        }
+        event
+    }
+}
+
+fn register(peers: &Peers, addr: SocketAddr) -> UnboundedReceiver<Command> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    peers.lock().unwrap().insert(addr, tx);
+    rx
+}
+
+fn closed(events: &UnboundedSender<Event>, addr: SocketAddr, reason: String) {
+    // Nobody left to tell if the swarm is gone
+    let _ = events.send(Event::Closed(addr, reason));
+}
+
+// The peer task: runs until either side closes, then reports why
+async fn run(
+    stream: TcpStream,
+    addr: SocketAddr,
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    commands: UnboundedReceiver<Command>,
+    events: UnboundedSender<Event>,
+) {
+    let reason = match drive(stream, addr, info_hash, peer_id, commands, &events).await {
+        Ok(()) => "closed".to_string(),
+        Err(e) => e.to_string(),
+    };
+    closed(&events, addr, reason);
+}
+
+async fn drive(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    commands: UnboundedReceiver<Command>,
+    events: &UnboundedSender<Event>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let handshake = Handshake::new(info_hash, peer_id);
+    time::timeout(HANDSHAKE_TIMEOUT, handshake.exchange(&mut stream))
+        .await
+        .map_err(|_| "handshake timed out")??;
+    if events.send(Event::Connected(addr)).is_err() {
+        return Ok(());
+    }
+
+    let (mut sink, received) = Framed::new(stream, MessageCodec).split();
+    let received = received
+        .map(Input::Received)
+        .chain(stream::once(future::ready(Input::Eof)));
+    let mut inputs = stream::select(received, commands.map(Input::Command));
+    while let Some(input) = inputs.next().await {
+        match input {
+            Input::Received(msg) => {
+                if events.send(Event::Message(addr, msg?)).is_err() {
+                    break;
+                }
+            }
+            Input::Eof => return Err("peer hung up".into()),
+            Input::Command(Command::Send(msg)) => sink.send(msg).await?,
+            Input::Command(Command::Close) => break,
+        }
+    }
+    Ok(())
+}
+
+/// A Swarm driven from plain blocking code, on a runtime of its own
+#[derive(Debug)]
+pub struct BlockingSwarm {
+    runtime: Runtime,
+    swarm: Swarm,
+}
+
+impl BlockingSwarm {
+    pub fn new(info_hash: Vec<u8>, peer_id: Vec<u8>) -> io::Result<BlockingSwarm> {
+        Ok(BlockingSwarm {
+            runtime: Runtime::new()?,
+            swarm: Swarm::new(info_hash, peer_id),
+        })
+    }
+
+    pub fn connect(&self, peer: &Peer) {
+        let swarm = &self.swarm;
+        self.runtime.enter(|| swarm.connect(peer));
+    }
+
+    pub fn listen(&mut self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let swarm = &self.swarm;
+        self.runtime.block_on(swarm.listen(addr))
+    }
+
+    pub fn send(&self, addr: SocketAddr, msg: Message) -> Result<(), Box<dyn Error>> {
+        self.swarm.send(addr, msg)
+    }
+
+    pub fn close(&self, addr: SocketAddr) {
+        self.swarm.close(addr)
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.swarm.num_peers()
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event> {
+        let swarm = &mut self.swarm;
+        // The timer has to be made inside the runtime
+        self.runtime
+            .block_on(async { time::timeout(timeout, swarm.next_event()).await })
+            .ok()
+            .flatten()
+    }
+}
+
+// The runtime PeerStream tasks and blocking announces share
+fn shared_runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .thread_name("peer-io")
+            .build()
+            .expect("ERR: Could not start the peer runtime")
+    })
+}
+
+/// Run `future` to completion from blocking code, on the shared runtime
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    shared_runtime().enter(|| futures::executor::block_on(future))
+}
+
+/// A TCP connection whose socket a task on the shared runtime reads and
+/// writes, used from blocking code like a TcpStream
+pub struct PeerStream {
+    addr: SocketAddr,
+    chunks: RefCell<Receiver<io::Result<Vec<u8>>>>,
+    // Read from the task but not yet by us
+    pending: RefCell<Vec<u8>>,
+    // Set once the task is done, with the error it ended on if any
+    closed: Cell<Option<Option<io::ErrorKind>>>,
+    writes: Sender<Vec<u8>>,
+    read_timeout: Cell<Option<Duration>>,
+}
+
+impl fmt::Debug for PeerStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerStream({})", self.addr)
+    }
+}
+
+impl PeerStream {
+    pub fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<PeerStream> {
+        // The timer has to be made inside the runtime
+        let stream = block_on(async { time::timeout(timeout, TcpStream::connect(addr)).await })
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        PeerStream::spawn(stream)
+    }
+
+    /// Take over an accepted connection
+    pub fn from_std(stream: std_net::TcpStream) -> io::Result<PeerStream> {
+        let stream = shared_runtime().enter(|| TcpStream::from_std(stream))?;
+        PeerStream::spawn(stream)
+    }
+
+    fn spawn(stream: TcpStream) -> io::Result<PeerStream> {
+        let addr = stream.peer_addr()?;
+        let (chunks_tx, chunks) = mpsc::channel(CHUNKS_AHEAD);
+        let (writes, writes_rx) = mpsc::channel(CHUNKS_AHEAD);
+        shared_runtime().spawn(pump(stream, chunks_tx, writes_rx));
+        Ok(PeerStream {
+            addr,
+            chunks: RefCell::new(chunks),
+            pending: RefCell::new(vec![]),
+            closed: Cell::new(None),
+            writes,
+            read_timeout: Cell::new(None),
+        })
+    }
+
+    // Wait up to `timeout` (forever for None) for something to read, or
+    // for the task to be done. False if that took too long.
+    fn fill(&self, timeout: Option<Duration>) -> bool {
+        if !self.pending.borrow().is_empty() || self.closed.get().is_some() {
+            return true;
+        }
+        let mut chunks = self.chunks.borrow_mut();
+        let received = match timeout {
+            Some(timeout) if timeout == Duration::from_secs(0) => match chunks.try_recv() {
+                Ok(chunk) => Some(chunk),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Closed) => None,
+            },
+            Some(timeout) => {
+                match block_on(async { time::timeout(timeout, chunks.recv()).await }) {
+                    Ok(received) => received,
+                    Err(_) => return false,
+                }
+            }
+            None => block_on(chunks.recv()),
+        };
+        match received {
+            Some(Ok(chunk)) => *self.pending.borrow_mut() = chunk,
+            Some(Err(e)) => self.closed.set(Some(Some(e.kind()))),
+            None => self.closed.set(Some(None)),
+        }
+        true
+    }
+
+    fn read_or_peek(&self, buf: &mut [u8], consume: bool) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.fill(self.read_timeout.get()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_empty() {
+            return match self.closed.get() {
+                Some(Some(kind)) => Err(kind.into()),
+                _ => Ok(0),
+            };
+        }
+        let n = buf.len().min(pending.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        if consume {
+            pending.drain(..n);
+        }
+        Ok(n)
+    }
+}
+
+// The PeerStream task: copies the socket into `chunks` and `writes` into
+// the socket until either side is done
+async fn pump(
+    mut stream: TcpStream,
+    mut chunks: Sender<io::Result<Vec<u8>>>,
+    mut writes: Receiver<Vec<u8>>,
+) {
+    let (mut reader, mut writer) = stream.split();
+    let mut errors = chunks.clone();
+    let read = async {
+        loop {
+            let mut chunk = vec![0; CHUNK_LEN];
+            let result = reader.read(&mut chunk).await;
+            let done = !matches!(result, Ok(n) if n > 0);
+            let result = result.map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            // Nobody reading any more, or nothing left to read
+            if done || chunks.send(result).await.is_err() {
+                return;
+            }
+        }
+    };
+    let write = async {
+        // Ends when the PeerStream is dropped
+        while let Some(data) = writes.recv().await {
+            if let Err(e) = writer.write_all(&data).await {
+                let _ = errors.send(Err(e)).await;
+                return;
+            }
+        }
+    };
+    future::select(Box::pin(read), Box::pin(write)).await;
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_or_peek(buf, true)
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Blocks while the task is behind on writing
+        block_on(self.writes.send(buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for PeerStream {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        Ok(self.fill(Some(timeout)))
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_or_peek(buf, false)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_peer(addr: SocketAddr) -> Peer {
+        Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port: addr.port(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swarm() {
+        let mut seeder = Swarm::new(vec![3; 20], vec![1; 20]);
+        let addr = seeder.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut leecher = Swarm::new(vec![3; 20], vec![2; 20]);
+        leecher.connect(&local_peer(addr));
+
+        assert!(matches!(
+            leecher.next_event().await,
+            Some(Event::Connected(a)) if a == addr
+        ));
+        let remote = match seeder.next_event().await {
+            Some(Event::Connected(remote)) => remote,
+            event => panic!("unexpected {:?}", event),
+        };
+
+        leecher.send(addr, Message::Interested).unwrap();
+        leecher.send(addr, Message::Request(0, 0, 16384)).unwrap();
+        assert!(matches!(
+            seeder.next_event().await,
+            Some(Event::Message(_, Message::Interested))
+        ));
+        assert!(matches!(
+            seeder.next_event().await,
+            Some(Event::Message(_, Message::Request(0, 0, 16384)))
+        ));
+        seeder
+            .send(remote, Message::Piece(0, 0, vec![7; 16384]))
+            .unwrap();
+        match leecher.next_event().await {
+            Some(Event::Message(_, Message::Piece(0, 0, block))) => assert_eq!(block.len(), 16384),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // Closing one side is seen by the other
+        leecher.close(addr);
+        assert!(matches!(
+            leecher.next_event().await,
+            Some(Event::Closed(..))
+        ));
+        assert!(matches!(seeder.next_event().await, Some(Event::Closed(..))));
+        assert_eq!(leecher.num_peers() + seeder.num_peers(), 0);
+    }
+
+    #[test]
+    fn test_peer_stream() {
+        let listener = std_net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut stream = PeerStream::connect(addr, Duration::from_secs(5)).unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        // Nothing yet, then reads through the task
+        assert!(!stream.wait_readable(Duration::from_millis(50)).unwrap());
+        remote.write_all(b"hello").unwrap();
+        assert!(stream.wait_readable(Duration::from_secs(5)).unwrap());
+        let mut buf = [0; 5];
+        assert_eq!(stream.peek(&mut buf[..2]).unwrap(), 2);
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.write_all(&[7; 100_000]).unwrap();
+        let mut got = vec![0; 100_000];
+        remote.read_exact(&mut got).unwrap();
+        assert!(got.iter().all(|&b| b == 7));
+
+        // Timeouts, then the peer hanging up
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(remote);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        // Incoming ones too
+        let client = std_net::TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let mut stream = PeerStream::from_std(accepted).unwrap();
+        drop(client);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_blocking_swarm() {
+        let wait = Duration::from_secs(5);
+        let mut seeder = BlockingSwarm::new(vec![3; 20], vec![1; 20]).unwrap();
+        let addr = seeder.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        // Wrong torrent
+        let mut stranger = BlockingSwarm::new(vec![4; 20], vec![2; 20]).unwrap();
+        stranger.connect(&local_peer(addr));
+        assert!(matches!(stranger.next_event(wait), Some(Event::Closed(..))));
+        assert!(matches!(seeder.next_event(wait), Some(Event::Closed(..))));
+
+        let mut leecher = BlockingSwarm::new(vec![3; 20], vec![2; 20]).unwrap();
+        leecher.connect(&local_peer(addr));
+        assert!(matches!(
+            leecher.next_event(wait),
+            Some(Event::Connected(_))
+        ));
+        assert!(matches!(seeder.next_event(wait), Some(Event::Connected(_))));
+        leecher.send(addr, Message::Have(3)).unwrap();
+        assert!(matches!(
+            seeder.next_event(wait),
+            Some(Event::Message(_, Message::Have(3)))
+        ));
+        assert!(seeder.next_event(Duration::from_millis(50)).is_none());
+        assert!(leecher
+            .send("127.0.0.1:1".parse().unwrap(), Message::Choke)
+            .is_err());
+    }
+}
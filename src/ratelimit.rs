@@ -0,0 +1,283 @@
+// Bandwidth caps. A RateLimit is a token bucket shared by every connection
+// it covers; Throttled charges a stream's reads and writes to a chain of them
+// (global, torrent, peer) and sleeps off whatever they say.
+
+use crate::transport::Transport;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Largest read or write charged at once, so connections sharing a bucket
+// take turns instead of one of them draining it
+pub const QUANTUM: usize = 16 * 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second, 0 for no limit
+    rate: u64,
+    // Goes negative when reserved ahead, later callers queue up behind that
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    // A tenth of a second's worth, but enough for a whole quantum
+    fn burst(&self) -> f64 {
+        (self.rate as f64 / 10.0).max(QUANTUM as f64)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst());
+        self.last = now;
+    }
+}
+
+/// A token bucket. Clones share it, so one handle can cap many connections
+/// and be changed while they run.
+#[derive(Clone)]
+pub struct RateLimit(Arc<Mutex<Bucket>>);
+
+impl RateLimit {
+    /// `rate` in bytes per second, 0 for unlimited
+    pub fn new(rate: u64) -> RateLimit {
+        let mut bucket = Bucket {
+            rate,
+            tokens: 0.0,
+            last: Instant::now(),
+        };
+        bucket.tokens = bucket.burst();
+        RateLimit(Arc::new(Mutex::new(bucket)))
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.0.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        if rate == 0 {
+            // Nobody should stay queued behind an old deficit
+            bucket.tokens = 0.0;
+        }
+        bucket.tokens = bucket.tokens.min(bucket.burst());
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// using them
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        self.reserve_at(bytes, Instant::now())
+    }
+
+    fn reserve_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.0.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::from_secs(0);
+        }
+        bucket.refill(now);
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit::new(0)
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RateLimit({})", self.rate())
+    }
+}
+
+/// Upload and download caps for one scope
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub upload: RateLimit,
+    pub download: RateLimit,
+}
+
+impl Limits {
+    pub fn new(upload: u64, download: u64) -> Limits {
+        Limits {
+            upload: RateLimit::new(upload),
+            download: RateLimit::new(download),
+        }
+    }
+}
+
+/// Peers on the local network aren't worth capping
+pub fn is_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Unique local fc00::/7 and link local fe80::/10
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// A stream whose traffic is charged to `scopes`
+#[derive(Debug)]
+pub struct Throttled<T> {
+    inner: T,
+    scopes: Vec<Limits>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, scopes: Vec<Limits>) -> Throttled<T> {
+        Throttled { inner, scopes }
+    }
+
+    fn charge<F: Fn(&Limits) -> &RateLimit>(&self, bytes: usize, limit: F) {
+        // Reserve in every scope up front so the waits overlap
+        let wait = self
+            .scopes
+            .iter()
+            .map(|scope| limit(scope).reserve(bytes))
+            .max()
+            .unwrap_or_default();
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Charged after the fact, which holds back the next read
+        let len = buf.len().min(QUANTUM);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.charge(n, |scope| &scope.download);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(QUANTUM);
+        self.charge(len, |scope| &scope.upload);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Throttled<T> {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        self.inner.wait_readable(timeout)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limit = RateLimit::new(100_000);
+        let start = Instant::now();
+
+        // The burst is free, then callers queue up in order
+        assert_eq!(limit.reserve_at(QUANTUM, start), Duration::from_secs(0));
+        let first = limit.reserve_at(10_000, start);
+        let second = limit.reserve_at(10_000, start);
+        assert!(first > Duration::from_millis(50));
+        assert!(second >= first + Duration::from_millis(99));
+
+        // Refills over time
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limit.reserve_at(1000, later), Duration::from_secs(0));
+
+        // Unlimited, and adjustable on the fly
+        limit.set_rate(0);
+        assert_eq!(limit.reserve(1 << 30), Duration::from_secs(0));
+        limit.set_rate(1000);
+        assert_eq!(limit.rate(), 1000);
+        assert!(limit.reserve(2 * QUANTUM) > Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_fair_share() {
+        // Two writers through one 400 KB/s cap, plus an unlimited scope
+        let global = Limits::new(400_000, 0);
+        let start = Instant::now();
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let scopes = vec![global.clone(), Limits::default()];
+                thread::spawn(move || {
+                    let mut stream = Throttled::new(io::sink(), scopes);
+                    for _ in 0..10 {
+                        stream.write_all(&[0; 20_000]).unwrap();
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let done: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+
+        // 400 KB less the burst takes about 900ms
+        let (first, last) = (done[0].min(done[1]), done[0].max(done[1]));
+        assert!(last > Duration::from_millis(800), "{:?}", done);
+        assert!(last < Duration::from_millis(1200), "{:?}", done);
+        // Sharing evenly they finish close together, where one hogging the
+        // cap would be done in half the time
+        assert!(last - first < Duration::from_millis(300), "{:?}", done);
+    }
+
+    #[test]
+    fn test_read_limit() {
+        let limit = Limits::new(0, 100_000);
+        let data = vec![1; 40_000];
+        let mut stream = Throttled::new(&data[..], vec![limit.clone()]);
+        let start = Instant::now();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert!(start.elapsed() > Duration::from_millis(200));
+
+        // Lifting the cap takes effect right away
+        limit.download.set_rate(0);
+        let mut stream = Throttled::new(&data[..], vec![limit]);
+        let start = Instant::now();
+        stream.read_to_end(&mut buf).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_is_lan() {
+        assert!(is_lan("192.168.1.4".parse().unwrap()));
+        assert!(is_lan("10.0.0.1".parse().unwrap()));
+        assert!(is_lan("127.0.0.1".parse().unwrap()));
+        assert!(is_lan("fd00::1".parse().unwrap()));
+        assert!(is_lan("fe80::1".parse().unwrap()));
+        assert!(!is_lan("8.8.8.8".parse().unwrap()));
+        assert!(!is_lan("2001:db8::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,238 @@
+// Resume data, so a restarted client doesn't have to download or hash
+// everything again.
+//
+// Stored as bencode next to the download. Pieces are only trusted if the
+// files they live in, and the partfile for skipped files, still have the
+// size and mtime recorded here, anything else has to be rechecked.
+
+use crate::bitfield::Bitfield;
+use crate::storage::{split_range, StorageFile};
+use crate::torrent::TorrentFile;
+use crate::tracker::Peer;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: u64,
+    // So a rewrite within the same second still counts as a change
+    #[serde(default, rename = "mtime nsec")]
+    pub mtime_nsec: u32,
+}
+
+/// A piece we have some blocks of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub index: u32,
+    pub blocks: ByteBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash")]
+    pub info_hash: ByteBuf,
+    // Bitfield of verified pieces
+    pub pieces: ByteBuf,
+    #[serde(rename = "partial pieces")]
+    pub partial_pieces: Vec<PartialPiece>,
+    pub files: Vec<FileState>,
+    // Compact peer list, like trackers send
+    pub peers: ByteBuf,
+    #[serde(rename = "tracker interval")]
+    pub tracker_interval: u32,
+    // Unix time of the last successful announce
+    #[serde(rename = "last announce")]
+    pub last_announce: u64,
+    // Payload bytes over every run so far, missing from older files
+    #[serde(default)]
+    pub downloaded: u64,
+    #[serde(default)]
+    pub uploaded: u64,
+    // Seconds spent seeding, and of those the ones since the last upload
+    #[serde(default, rename = "seeding time")]
+    pub seeding_time: u64,
+    #[serde(default, rename = "idle time")]
+    pub idle_time: u64,
+    // One per file, see FilePriority::to_u8. Empty means all normal.
+    #[serde(default, rename = "file priorities")]
+    pub file_priorities: Vec<u8>,
+    // Zeros when there is no partfile
+    #[serde(default, rename = "part file")]
+    pub part_file: FileState,
+}
+
+/// Where a torrent's resume data lives in its download directory
+pub fn resume_path(download_dir: &Path, info_hash: &[u8]) -> PathBuf {
+    let hex_hash: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    download_dir.join(format!(".{}.resume", hex_hash))
+}
+
+/// Size and mtime of the file at `path`, or zeros if it doesn't exist
+pub fn file_state(path: &Path) -> FileState {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            FileState {
+                size: metadata.len(),
+                mtime: mtime.as_secs(),
+                mtime_nsec: mtime.subsec_nanos(),
+            }
+        }
+        Err(_) => FileState::default(),
+    }
+}
+
+/// State of each file, see file_state
+pub fn file_states(files: &[StorageFile]) -> Vec<FileState> {
+    files.iter().map(|f| file_state(&f.path)).collect()
+}
+
+pub fn peers_to_bytes(peers: &[Peer]) -> Vec<u8> {
+    peers.iter().flat_map(|p| p.to_bytes()).collect()
+}
+
+impl ResumeData {
+    pub fn load(path: &Path) -> Result<ResumeData, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        Ok(serde_bencode::from_bytes(&bytes)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        // Write to the side and rename so a crash never leaves half a file
+        let bytes = serde_bencode::to_bytes(self)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.chunks_exact(6).map(Peer::from_bytes).collect()
+    }
+
+    /// Pieces that may no longer match what's on disk because a file they
+    /// touch changed since this was written. Data of `skipped` files lives
+    /// in the partfile at `part_path`, so those count as changed with it.
+    pub fn stale_pieces(
+        &self,
+        torrent: &TorrentFile,
+        files: &[StorageFile],
+        part_path: &Path,
+        skipped: &[bool],
+    ) -> Bitfield {
+        let current = file_states(files);
+        let part_changed = file_state(part_path) != self.part_file;
+        let changed: Vec<bool> = current
+            .iter()
+            .enumerate()
+            .map(|(i, state)| {
+                self.files.get(i) != Some(state)
+                    || (part_changed && skipped.get(i).copied().unwrap_or(false))
+            })
+            .collect();
+
+        let mut stale = Bitfield::new(torrent.num_pieces());
+        for index in 0..torrent.num_pieces() {
+            let offset = torrent.piece_offset(index);
+            let size = torrent.piece_size(index);
+            if split_range(files, offset, size)
+                .iter()
+                .any(|(file, _, _)| changed[*file])
+            {
+                stale.set_piece(index);
+            }
+        }
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::layout;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_round_trip_and_stale_pieces() {
+        let dir = env::temp_dir().join(format!("bittorrent-resume-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let torrent = TorrentFile::open(Path::new("data/bitcoin-0.20.0.torrent")).unwrap();
+        let files = layout(&dir, &torrent.files);
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(torrent.info_hash.clone()),
+            pieces: ByteBuf::from(vec![0xff; torrent.num_pieces().div_ceil(8)]),
+            partial_pieces: vec![PartialPiece {
+                index: 3,
+                blocks: ByteBuf::from(vec![0b1010_0000]),
+            }],
+            files: file_states(&files),
+            peers: ByteBuf::from(peers_to_bytes(&[Peer {
+                ip: "10.0.0.1".parse().unwrap(),
+                port: 6881,
+            }])),
+            tracker_interval: 1800,
+            last_announce: 1_600_000_000,
+            downloaded: 1 << 30,
+            uploaded: 3 << 30,
+            seeding_time: 86400,
+            idle_time: 600,
+            file_priorities: vec![0, 2, 3],
+            part_file: FileState::default(),
+        };
+
+        let path = dir.join("resume");
+        resume.save(&path).unwrap();
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded.info_hash, resume.info_hash);
+        assert_eq!(
+            loaded.partial_pieces[0].blocks,
+            resume.partial_pieces[0].blocks
+        );
+        assert_eq!(loaded.peers()[0].port, 6881);
+        assert_eq!(loaded.tracker_interval, 1800);
+        assert_eq!(loaded.uploaded, 3 << 30);
+        assert_eq!(loaded.seeding_time, 86400);
+        assert_eq!(loaded.idle_time, 600);
+        assert_eq!(loaded.file_priorities, vec![0, 2, 3]);
+
+        // Nothing changed
+        let part_path = dir.join("parts");
+        let skipped = vec![false; files.len()];
+        let stale = loaded.stale_pieces(&torrent, &files, &part_path, &skipped);
+        assert!((0..torrent.num_pieces()).all(|i| !stale.has_piece(i)));
+
+        // Only pieces overlapping the second file need a recheck
+        let second = &files[1];
+        fs::create_dir_all(second.path.parent().unwrap()).unwrap();
+        fs::write(&second.path, b"changed").unwrap();
+        let stale = loaded.stale_pieces(&torrent, &files, &part_path, &skipped);
+        let first = (second.offset / torrent.piece_length()) as usize;
+        let last = ((second.offset + second.length - 1) / torrent.piece_length()) as usize;
+        for i in 0..torrent.num_pieces() {
+            assert_eq!(stale.has_piece(i), i >= first && i <= last, "piece {}", i);
+        }
+
+        // A changed partfile only matters for skipped files
+        fs::write(&part_path, b"parts").unwrap();
+        let stale = loaded.stale_pieces(&torrent, &files, &part_path, &skipped);
+        assert!(!stale.has_piece(0));
+        let mut skipped = skipped;
+        skipped[0] = true;
+        let stale = loaded.stale_pieces(&torrent, &files, &part_path, &skipped);
+        assert!(stale.has_piece(0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1404 @@
+// Many torrents at once. A Session runs each active torrent on a thread of
+// its own and gives them one peer id, one set of rate limits, one listen
+// port for TCP and uTP, and one DHT node. The node searches for every
+// running public torrent now and then and hands it the peers it finds;
+// whatever else finds peers hands them in through add_peers.
+
+use crate::alert::{Alert, AlertKind, Alerts};
+use crate::connection::Connection;
+use crate::dht::Dht;
+use crate::mse::EncryptionPolicy;
+use crate::p2p::{Control, Torrent};
+use crate::partfile;
+use crate::peer::PeerStream;
+use crate::ratelimit::Limits;
+use crate::resume;
+use crate::stats::TorrentStats;
+use crate::storage::{self, FilePriority};
+use crate::torrent::TorrentFile;
+use crate::tracker::{Peer, PeerSource};
+use crate::transport::Transport;
+use crate::utp::UtpSocket;
+use log::{debug, info};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the listener checks whether the session is gone
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// How often the queue is looked at again, for torrents that got slow,
+// finished or reached their goals
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+// How often each running torrent is searched for on the DHT
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Caps the number of open connections. Clones share the count.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit(Arc<Mutex<(usize, usize)>>);
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit(Arc::new(Mutex::new((0, max))))
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.0.lock().unwrap().1 = max;
+    }
+
+    pub fn open(&self) -> usize {
+        self.0.lock().unwrap().0
+    }
+
+    /// A slot for one more connection, None when all are taken
+    pub fn try_acquire(&self) -> Option<Slot> {
+        let mut count = self.0.lock().unwrap();
+        if count.0 >= count.1 {
+            return None;
+        }
+        count.0 += 1;
+        Some(Slot(self.clone()))
+    }
+}
+
+/// Held for as long as a connection is open, gives its place back on drop
+#[derive(Debug)]
+pub struct Slot(ConnectionLimit);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        (self.0).0.lock().unwrap().0 -= 1;
+    }
+}
+
+/// How many auto managed torrents may run. The rest wait in the queue,
+/// the ones nearest the front start first.
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    pub max_active: usize,
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    // Torrents moving less than both rates (bytes per second) don't count
+    // against the limits, once they've had `startup_grace` to get going
+    pub dont_count_slow: bool,
+    pub slow_download_rate: u64,
+    pub slow_upload_rate: u64,
+    pub startup_grace: Duration,
+}
+
+impl Default for QueueSettings {
+    fn default() -> QueueSettings {
+        QueueSettings {
+            max_active: 8,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            dont_count_slow: true,
+            slow_download_rate: 2048,
+            slow_upload_rate: 2048,
+            startup_grace: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do with a seed that reached its goal
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GoalAction {
+    #[default]
+    Pause,
+    Remove,
+    // Remove and delete the downloaded files and resume data
+    RemoveWithData,
+}
+
+/// When a seed has done enough. Any goal reached is enough, None never is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeedGoals {
+    pub share_ratio: Option<f64>,
+    pub seed_time: Option<Duration>,
+    // Seeding without anyone downloading
+    pub idle_time: Option<Duration>,
+    pub action: GoalAction,
+}
+
+impl SeedGoals {
+    pub fn met(&self, stats: &TorrentStats) -> bool {
+        stats.left == 0
+            && (self.share_ratio.is_some_and(|ratio| stats.ratio() >= ratio)
+                || self
+                    .seed_time
+                    .is_some_and(|time| stats.seeding_time >= time)
+                || self.idle_time.is_some_and(|time| stats.idle_time >= time))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    pub download_dir: PathBuf,
+    pub listen_addr: SocketAddr,
+    // Across all torrents
+    pub max_connections: usize,
+    pub queue: QueueSettings,
+    // For torrents without goals of their own
+    pub goals: SeedGoals,
+    // Bytes per second, 0 for unlimited
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    // Bytes past a reader's position to fetch early
+    pub readahead: u64,
+    // For connections both ways, see mse
+    pub encryption: EncryptionPolicy,
+    // Reach and accept peers over uTP too, on the listen port
+    pub utp: bool,
+    // Where the DHT node listens, None for no DHT
+    pub dht_addr: Option<SocketAddr>,
+    // Nodes to join the DHT through, as host:port
+    pub dht_routers: Vec<String>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> SessionSettings {
+        SessionSettings {
+            download_dir: PathBuf::from("."),
+            listen_addr: "0.0.0.0:6881".parse().unwrap(),
+            max_connections: 200,
+            queue: QueueSettings::default(),
+            goals: SeedGoals::default(),
+            upload_rate: 0,
+            download_rate: 0,
+            readahead: 4 * 1024 * 1024,
+            encryption: EncryptionPolicy::Prefer,
+            utp: true,
+            dht_addr: Some("0.0.0.0:6882".parse().unwrap()),
+            dht_routers: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    // Waiting for an active slot. Torrents that aren't auto managed start
+    // as soon as they can.
+    Queued,
+    Running,
+    Paused,
+    // download gave up, the torrent stays paused until resumed
+    Error(String),
+}
+
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    num_pieces: usize,
+    num_files: usize,
+    state: TorrentState,
+    // The current run's, replaced on every start
+    control: Arc<Control>,
+    // Started and stopped by the queue
+    auto_managed: bool,
+    // A worker thread is still running the torrent
+    busy: bool,
+    started: Instant,
+    // As last published, kept while the torrent isn't running
+    stats: Option<TorrentStats>,
+    // Instead of the session's
+    goals: Option<SeedGoals>,
+    // Handed to every run, None to keep what the resume data has
+    file_priorities: Option<Vec<FilePriority>>,
+    sequential: bool,
+    // Not private, so it may be searched for on the DHT
+    dht: bool,
+    // Last DHT search of the current run
+    searched: Option<Instant>,
+}
+
+impl Entry {
+    fn new(path: PathBuf, num_pieces: usize, num_files: usize) -> Entry {
+        Entry {
+            path,
+            num_pieces,
+            num_files,
+            state: TorrentState::Queued,
+            control: Arc::new(Control::default()),
+            auto_managed: true,
+            busy: false,
+            started: Instant::now(),
+            stats: None,
+            goals: None,
+            file_priorities: None,
+            sequential: false,
+            dht: false,
+            searched: None,
+        }
+    }
+
+    // Unknown until the torrent has run once
+    fn is_seed(&self) -> bool {
+        self.stats.as_ref().is_some_and(|stats| stats.left == 0)
+    }
+
+    fn is_slow(&self, queue: &QueueSettings, now: Instant) -> bool {
+        match &self.stats {
+            Some(stats) if queue.dont_count_slow => {
+                now.duration_since(self.started) >= queue.startup_grace
+                    && stats.download_rate < queue.slow_download_rate
+                    && stats.upload_rate < queue.slow_upload_rate
+            }
+            _ => false,
+        }
+    }
+
+    fn refresh(&mut self) {
+        if let Some(stats) = self.control.stats() {
+            self.stats = Some(stats);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Torrents {
+    entries: HashMap<Vec<u8>, Entry>,
+    // Info hashes by queue position
+    order: Vec<Vec<u8>>,
+    queue: QueueSettings,
+    goals: SeedGoals,
+    // Removed torrents whose data goes once their worker is done with it
+    deleting: HashSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    peer_id: Vec<u8>,
+    download_dir: PathBuf,
+    listen_port: u16,
+    limits: Limits,
+    connections: ConnectionLimit,
+    readahead: u64,
+    encryption: EncryptionPolicy,
+    utp: Option<UtpSocket>,
+    dht: Option<Dht>,
+    alerts: Alerts,
+    torrents: Mutex<Torrents>,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    inner: Arc<Inner>,
+    local_addr: SocketAddr,
+}
+
+impl Session {
+    /// Start listening on `settings.listen_addr`, and on the DHT
+    pub fn new(settings: SessionSettings) -> io::Result<Session> {
+        let listener = TcpListener::bind(settings.listen_addr)?;
+        let local_addr = listener.local_addr()?;
+        // The same port as TCP, which is where peers look for it
+        let utp = match settings.utp {
+            true => Some(UtpSocket::bind(local_addr)?),
+            false => None,
+        };
+        let dht = settings.dht_addr.map(Dht::bind).transpose()?;
+        let inner = Arc::new(Inner {
+            peer_id: rand::thread_rng().gen::<[u8; 20]>().to_vec(),
+            download_dir: settings.download_dir,
+            listen_port: local_addr.port(),
+            limits: Limits::new(settings.upload_rate, settings.download_rate),
+            connections: ConnectionLimit::new(settings.max_connections),
+            readahead: settings.readahead,
+            encryption: settings.encryption,
+            utp: utp.clone(),
+            dht,
+            alerts: Alerts::new(),
+            torrents: Mutex::new(Torrents {
+                entries: HashMap::new(),
+                order: vec![],
+                queue: settings.queue,
+                goals: settings.goals,
+                deleting: HashSet::new(),
+            }),
+        });
+
+        // Polls so it can notice the session being dropped
+        listener.set_nonblocking(true)?;
+        let session = Arc::downgrade(&inner);
+        thread::spawn(move || listen(listener, session));
+        if let Some(utp) = utp {
+            let session = Arc::downgrade(&inner);
+            thread::spawn(move || listen_utp(utp, session));
+        }
+        if inner.dht.is_some() {
+            let session = Arc::downgrade(&inner);
+            let routers = settings.dht_routers;
+            thread::spawn(move || bootstrap(routers, session));
+        }
+        let session = Arc::downgrade(&inner);
+        thread::spawn(move || manage(session));
+
+        Ok(Session { inner, local_addr })
+    }
+
+    /// Add the torrent at `path`, returning its info hash. It starts right
+    /// away if there's an active slot free, otherwise it's queued.
+    pub fn add(&self, path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+        let torrent_file = TorrentFile::open(path)?;
+        let info_hash = torrent_file.info_hash.clone();
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        if torrents.entries.contains_key(&info_hash) {
+            return Err("ERR: Torrent already added".into());
+        }
+        let mut entry = Entry::new(
+            path.to_path_buf(),
+            torrent_file.num_pieces(),
+            torrent_file.files.len(),
+        );
+        entry.dht = torrent_file.allows_peer_source(PeerSource::Dht);
+        torrents.entries.insert(info_hash.clone(), entry);
+        torrents.order.push(info_hash.clone());
+
+        let name = torrent_file.name().to_string();
+        self.inner
+            .alerts
+            .post(&info_hash, AlertKind::TorrentAdded { name });
+        self.inner
+            .alerts
+            .post(&info_hash, AlertKind::MetadataReceived);
+        schedule(&self.inner, &mut torrents);
+        Ok(info_hash)
+    }
+
+    /// Stop the torrent and forget about it. Downloaded data stays on disk.
+    pub fn remove(&self, info_hash: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        remove_entry(&self.inner, &mut torrents, info_hash, false)?;
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// Remove the torrent along with its files and resume data
+    pub fn remove_with_data(&self, info_hash: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        remove_entry(&self.inner, &mut torrents, info_hash, true)?;
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// Stop the torrent, saving its progress, until it's resumed
+    pub fn pause(&self, info_hash: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.control.stop();
+        entry.state = TorrentState::Paused;
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// Queue a paused or failed torrent again. Seeds that reached a goal
+    /// are paused again until the goal is raised.
+    pub fn resume(&self, info_hash: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        match entry.state {
+            TorrentState::Paused | TorrentState::Error(_) => entry.state = TorrentState::Queued,
+            _ => return Ok(()),
+        }
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    pub fn state(&self, info_hash: &[u8]) -> Option<TorrentState> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        torrents
+            .entries
+            .get(info_hash)
+            .map(|entry| entry.state.clone())
+    }
+
+    /// The torrent's latest stats, None until it has run
+    pub fn stats(&self, info_hash: &[u8]) -> Option<TorrentStats> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents.entries.get_mut(info_hash)?;
+        entry.refresh();
+        entry.stats.clone()
+    }
+
+    /// Info hashes by queue position
+    pub fn torrents(&self) -> Vec<Vec<u8>> {
+        self.inner.torrents.lock().unwrap().order.clone()
+    }
+
+    pub fn queue_position(&self, info_hash: &[u8]) -> Option<usize> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        torrents.order.iter().position(|hash| hash == info_hash)
+    }
+
+    /// Move the torrent to `position`, 0 being the front of the queue
+    pub fn set_queue_position(
+        &self,
+        info_hash: &[u8],
+        position: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let current = torrents
+            .order
+            .iter()
+            .position(|hash| hash == info_hash)
+            .ok_or("ERR: No such torrent")?;
+        let info_hash = torrents.order.remove(current);
+        let position = position.min(torrents.order.len());
+        torrents.order.insert(position, info_hash);
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// Let the queue start and stop the torrent, or take it out of the
+    /// queue's hands
+    pub fn set_auto_managed(&self, info_hash: &[u8], auto: bool) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.auto_managed = auto;
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// Hand a running torrent peers found elsewhere, e.g. by a DHT
+    pub fn add_peers(&self, info_hash: &[u8], source: PeerSource, peers: Vec<Peer>) {
+        let torrents = self.inner.torrents.lock().unwrap();
+        if let Some(entry) = torrents.entries.get(info_hash) {
+            entry.control.add_peers(source, peers);
+        }
+    }
+
+    pub fn queue_settings(&self) -> QueueSettings {
+        self.inner.torrents.lock().unwrap().queue.clone()
+    }
+
+    pub fn set_queue_settings(&self, queue: QueueSettings) {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        torrents.queue = queue;
+        schedule(&self.inner, &mut torrents);
+    }
+
+    pub fn seed_goals(&self) -> SeedGoals {
+        self.inner.torrents.lock().unwrap().goals.clone()
+    }
+
+    /// Goals for every torrent without its own
+    pub fn set_seed_goals(&self, goals: SeedGoals) {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        torrents.goals = goals;
+        schedule(&self.inner, &mut torrents);
+    }
+
+    /// Goals for just this torrent, None to go by the session's
+    pub fn set_torrent_seed_goals(
+        &self,
+        info_hash: &[u8],
+        goals: Option<SeedGoals>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.goals = goals;
+        schedule(&self.inner, &mut torrents);
+        Ok(())
+    }
+
+    /// One priority per file, in the torrent's order. Takes effect right
+    /// away if the torrent is running, a finished torrent goes back to
+    /// downloading when more files are wanted.
+    pub fn set_file_priorities(
+        &self,
+        info_hash: &[u8],
+        priorities: Vec<FilePriority>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        if priorities.len() != entry.num_files {
+            return Err(format!(
+                "ERR: {} priorities for {} files",
+                priorities.len(),
+                entry.num_files
+            )
+            .into());
+        }
+        entry.control.set_file_priorities(priorities.clone());
+        entry.file_priorities = Some(priorities);
+        Ok(())
+    }
+
+    /// Download the torrent's pieces in order, for playing files as they
+    /// come in
+    pub fn set_sequential(&self, info_hash: &[u8], sequential: bool) -> Result<(), Box<dyn Error>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get_mut(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.control.set_sequential(sequential);
+        entry.sequential = sequential;
+        Ok(())
+    }
+
+    /// Have the piece fetched ahead of others, by `deadline` from now if it
+    /// can be. Deadlines only last while the torrent runs.
+    pub fn set_piece_deadline(
+        &self,
+        info_hash: &[u8],
+        index: usize,
+        deadline: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        if index >= entry.num_pieces {
+            return Err(format!("ERR: No piece {}", index).into());
+        }
+        entry
+            .control
+            .set_piece_deadline(index, Instant::now() + deadline);
+        Ok(())
+    }
+
+    pub fn reset_piece_deadline(
+        &self,
+        info_hash: &[u8],
+        index: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.control.reset_piece_deadline(index);
+        Ok(())
+    }
+
+    /// Where a reader is in the torrent's data, None once it's done. The
+    /// readahead window past it is fetched first.
+    pub fn set_read_position(
+        &self,
+        info_hash: &[u8],
+        offset: Option<u64>,
+    ) -> Result<(), Box<dyn Error>> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .entries
+            .get(info_hash)
+            .ok_or("ERR: No such torrent")?;
+        entry.control.set_read_position(offset);
+        Ok(())
+    }
+
+    pub fn set_max_connections(&self, max: usize) {
+        self.inner.connections.set_max(max);
+    }
+
+    /// Caps shared by all torrents, adjustable while they run
+    pub fn limits(&self) -> &Limits {
+        &self.inner.limits
+    }
+
+    pub fn peer_id(&self) -> &[u8] {
+        &self.inner.peer_id
+    }
+
+    /// Where the session accepts peers
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The DHT node, e.g. for adding nodes met elsewhere
+    pub fn dht(&self) -> Option<&Dht> {
+        self.inner.dht.as_ref()
+    }
+
+    /// Alerts from every torrent in the session
+    pub fn alerts(&self) -> &Alerts {
+        &self.inner.alerts
+    }
+
+    pub fn subscribe(&self) -> Receiver<Alert> {
+        self.inner.alerts.subscribe()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let torrents = self.inner.torrents.lock().unwrap();
+        for entry in torrents.entries.values() {
+            entry.control.stop();
+        }
+    }
+}
+
+// Which auto managed torrents should be running, going by queue position
+fn plan(torrents: &Torrents, now: Instant) -> HashSet<Vec<u8>> {
+    let queue = &torrents.queue;
+    let (mut active, mut downloads, mut seeds) = (0, 0, 0);
+    let mut want = HashSet::new();
+    for info_hash in &torrents.order {
+        let entry = &torrents.entries[info_hash];
+        let waiting = matches!(entry.state, TorrentState::Queued | TorrentState::Running);
+        if !entry.auto_managed || !waiting {
+            continue;
+        }
+        // Slow torrents keep running without taking up a slot
+        if entry.state == TorrentState::Running && entry.is_slow(queue, now) {
+            want.insert(info_hash.clone());
+            continue;
+        }
+        let (count, max) = match entry.is_seed() {
+            true => (&mut seeds, queue.max_active_seeds),
+            false => (&mut downloads, queue.max_active_downloads),
+        };
+        if *count < max && active < queue.max_active {
+            *count += 1;
+            active += 1;
+            want.insert(info_hash.clone());
+        }
+    }
+    want
+}
+
+// Seeds that reached their goals, and what to do with them
+fn goals_reached(torrents: &Torrents) -> Vec<(Vec<u8>, GoalAction)> {
+    let mut reached = vec![];
+    for info_hash in &torrents.order {
+        let entry = &torrents.entries[info_hash];
+        let goals = entry.goals.as_ref().unwrap_or(&torrents.goals);
+        let waiting = matches!(entry.state, TorrentState::Queued | TorrentState::Running);
+        match &entry.stats {
+            Some(stats) if waiting && goals.met(stats) => {
+                reached.push((info_hash.clone(), goals.action))
+            }
+            _ => {}
+        }
+    }
+    reached
+}
+
+// Act on goals, then start and stop torrents to match the plan
+fn schedule(inner: &Arc<Inner>, torrents: &mut Torrents) {
+    for (info_hash, action) in goals_reached(torrents) {
+        inner
+            .alerts
+            .post(&info_hash, AlertKind::SeedGoalReached { action });
+        match action {
+            GoalAction::Pause => {
+                let entry = torrents.entries.get_mut(&info_hash).unwrap();
+                entry.control.stop();
+                entry.state = TorrentState::Paused;
+            }
+            GoalAction::Remove => remove_entry(inner, torrents, &info_hash, false).unwrap(),
+            GoalAction::RemoveWithData => remove_entry(inner, torrents, &info_hash, true).unwrap(),
+        }
+    }
+
+    let want = plan(torrents, Instant::now());
+    for info_hash in &torrents.order {
+        let entry = torrents.entries.get_mut(info_hash).unwrap();
+        let run = want.contains(info_hash) || !entry.auto_managed;
+        match entry.state {
+            TorrentState::Running if !run => {
+                entry.control.stop();
+                entry.state = TorrentState::Queued;
+            }
+            // A stopped run has to wind down before the next one starts
+            TorrentState::Queued if run && !entry.busy => {
+                start(inner, info_hash, entry);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn remove_entry(
+    inner: &Inner,
+    torrents: &mut Torrents,
+    info_hash: &[u8],
+    delete: bool,
+) -> Result<(), Box<dyn Error>> {
+    let entry = torrents
+        .entries
+        .remove(info_hash)
+        .ok_or("ERR: No such torrent")?;
+    entry.control.stop();
+    torrents.order.retain(|hash| hash != info_hash);
+    if delete {
+        match entry.busy {
+            true => {
+                torrents.deleting.insert(info_hash.to_vec());
+            }
+            false => delete_data(inner, info_hash, &entry.path),
+        }
+    }
+    Ok(())
+}
+
+fn delete_data(inner: &Inner, info_hash: &[u8], path: &Path) {
+    let dir = &inner.download_dir;
+    let result = TorrentFile::open(path).and_then(|torrent_file| {
+        let files = storage::layout(dir, &torrent_file.files);
+        storage::delete_files(dir, &files)?;
+        for path in [
+            resume::resume_path(dir, info_hash),
+            partfile::part_path(dir, info_hash),
+        ] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let error = format!("deleting {}: {}", path.display(), e);
+        inner
+            .alerts
+            .post(info_hash, AlertKind::StorageError { error });
+    }
+}
+
+fn start(inner: &Arc<Inner>, info_hash: &[u8], entry: &mut Entry) {
+    entry.state = TorrentState::Running;
+    entry.control = Arc::new(Control::default());
+    if let Some(priorities) = &entry.file_priorities {
+        entry.control.set_file_priorities(priorities.clone());
+    }
+    entry.control.set_sequential(entry.sequential);
+    entry.busy = true;
+    entry.started = Instant::now();
+    entry.searched = None;
+
+    let inner = inner.clone();
+    let (info_hash, path) = (info_hash.to_vec(), entry.path.clone());
+    let control = entry.control.clone();
+    thread::spawn(move || work(inner, info_hash, path, control));
+}
+
+fn work(inner: Arc<Inner>, info_hash: Vec<u8>, path: PathBuf, control: Arc<Control>) {
+    let result = run_torrent(&inner, &path, control);
+    if let Err(e) = &result {
+        info!("{} stopped: {}", path.display(), e);
+    }
+    // The torrent has let go of its files now
+    if inner.torrents.lock().unwrap().deleting.remove(&info_hash) {
+        delete_data(&inner, &info_hash, &path);
+    }
+
+    let mut torrents = inner.torrents.lock().unwrap();
+    if let Some(entry) = torrents.entries.get_mut(&info_hash) {
+        entry.refresh();
+        entry.busy = false;
+        // Otherwise pausing or the queue stopped it and already said why
+        if entry.state == TorrentState::Running {
+            entry.state = match result {
+                Ok(()) => TorrentState::Paused,
+                Err(e) => TorrentState::Error(e.to_string()),
+            };
+        }
+    }
+    schedule(&inner, &mut torrents);
+}
+
+// Keeps the queue in step with the torrents' progress and rates
+fn manage(session: Weak<Inner>) {
+    loop {
+        thread::sleep(SCHEDULE_INTERVAL);
+        let inner = match session.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut torrents = inner.torrents.lock().unwrap();
+        for entry in torrents.entries.values_mut() {
+            entry.refresh();
+        }
+        search_dht(&inner, &mut torrents, Instant::now());
+        schedule(&inner, &mut torrents);
+    }
+}
+
+// Hand running torrents what the DHT found for them, and search for each
+// again now and then
+fn search_dht(inner: &Inner, torrents: &mut Torrents, now: Instant) {
+    let dht = match &inner.dht {
+        Some(dht) => dht,
+        None => return,
+    };
+    // Nobody to ask until joining got somewhere
+    let joined = dht.num_nodes() > 0;
+    for (info_hash, entry) in torrents.entries.iter_mut() {
+        if entry.state != TorrentState::Running || !entry.dht {
+            continue;
+        }
+        let peers = dht.take_peers(info_hash);
+        if !peers.is_empty() {
+            entry.control.add_peers(PeerSource::Dht, peers);
+        }
+        let due = entry
+            .searched
+            .is_none_or(|at| now.duration_since(at) >= DHT_INTERVAL);
+        if joined && due {
+            dht.search(info_hash, Some(inner.listen_port));
+            entry.searched = Some(now);
+        }
+    }
+}
+
+// Join the DHT through the routers that resolve
+fn bootstrap(routers: Vec<String>, session: Weak<Inner>) {
+    for router in routers {
+        let addrs = match router.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                debug!("DHT router {}: {}", router, e);
+                continue;
+            }
+        };
+        let inner = match session.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if let Some(dht) = &inner.dht {
+            addrs.for_each(|addr| dht.add_router(addr));
+        }
+    }
+}
+
+fn run_torrent(inner: &Inner, path: &Path, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    let mut torrent = Torrent::new(path, &inner.download_dir)?;
+    torrent.set_alerts(inner.alerts.clone());
+    torrent.set_peer_id(inner.peer_id.clone());
+    torrent.set_listen_port(inner.listen_port);
+    torrent.set_global_limits(inner.limits.clone());
+    torrent.set_connection_limit(inner.connections.clone());
+    torrent.set_readahead(inner.readahead);
+    torrent.set_encryption(inner.encryption);
+    if let Some(utp) = &inner.utp {
+        torrent.set_utp(utp.clone());
+    }
+    torrent.set_control(control);
+    torrent.download()
+}
+
+fn listen(listener: TcpListener, session: Weak<Inner>) {
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => match PeerStream::from_std(stream) {
+                Ok(stream) => spawn_route(Box::new(stream), addr, &session),
+                Err(e) => debug!("dropped {}: {}", addr, e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if session.upgrade().is_none() {
+                    return;
+                }
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(e) => debug!("accept failed: {}", e),
+        }
+    }
+}
+
+fn listen_utp(socket: UtpSocket, session: Weak<Inner>) {
+    loop {
+        match socket.accept_timeout(ACCEPT_INTERVAL) {
+            Ok(Some(stream)) => {
+                let addr = stream.peer_addr().unwrap();
+                spawn_route(Box::new(stream), addr, &session);
+            }
+            Ok(None) => {
+                if session.upgrade().is_none() {
+                    return;
+                }
+            }
+            Err(e) => debug!("uTP accept failed: {}", e),
+        }
+    }
+}
+
+fn spawn_route(stream: Box<dyn Transport>, addr: SocketAddr, session: &Weak<Inner>) {
+    let session = session.clone();
+    thread::spawn(move || {
+        if let Err(e) = route(stream, session) {
+            debug!("dropped {}: {}", addr, e);
+        }
+    });
+}
+
+// Hand an incoming connection to the torrent it's for. The handshake says
+// which, or for encrypted ones the key exchange, which is answered for
+// every running torrent.
+fn route(stream: Box<dyn Transport>, session: Weak<Inner>) -> Result<(), Box<dyn Error>> {
+    let inner = session.upgrade().ok_or("ERR: Session is gone")?;
+    let (torrents, controls): (Vec<_>, Vec<_>) = {
+        let torrents = inner.torrents.lock().unwrap();
+        torrents
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.state == TorrentState::Running)
+            .map(|(info_hash, entry)| {
+                ((info_hash.clone(), entry.num_pieces), entry.control.clone())
+            })
+            .unzip()
+    };
+    let slot = inner
+        .connections
+        .try_acquire()
+        .ok_or("ERR: Connection limit reached")?;
+    let (index, conn) =
+        Connection::accept_any(stream, &torrents, inner.peer_id.clone(), inner.encryption)?;
+    controls[index].add_incoming(conn, Some(slot));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create, CreateOptions};
+    use crate::message::Message;
+    use std::{env, fs, process};
+
+    // A complete torrent of one small file, with a tracker nobody answers
+    fn make_torrent(dir: &Path, name: &str) -> PathBuf {
+        let data = dir.join(name);
+        fs::write(&data, vec![name.len() as u8; 40_000]).unwrap();
+        let options = CreateOptions {
+            trackers: vec![vec!["http://127.0.0.1:1/announce".to_string()]],
+            ..CreateOptions::default()
+        };
+        let path = dir.join(format!("{}.torrent", name));
+        fs::write(&path, create(&data, &options).unwrap()).unwrap();
+        path
+    }
+
+    fn wait_for(session: &Session, info_hash: &[u8], state: TorrentState) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.state(info_hash) != Some(state.clone()) {
+            assert!(Instant::now() < deadline, "{:?}", session.state(info_hash));
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // Connect like a remote peer and expect the seed's bitfield
+    fn expect_seed(session: &Session, path: &Path, encryption: EncryptionPolicy) -> Connection {
+        let torrent_file = TorrentFile::open(path).unwrap();
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port: session.local_addr().port(),
+        };
+        let mut conn = Connection::connect(
+            peer,
+            torrent_file.info_hash.clone(),
+            vec![9; 20],
+            torrent_file.num_pieces(),
+            encryption,
+        )
+        .unwrap();
+        assert_eq!(conn.is_encrypted(), encryption == EncryptionPolicy::Require);
+        conn.wait_for_input(Duration::from_secs(5)).unwrap();
+        match conn.read_message().unwrap() {
+            Some(Message::Bitfield(_)) => {}
+            msg => panic!("unexpected {:?}", msg),
+        }
+        conn
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = env::temp_dir().join(format!("bittorrent-session-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first_path = make_torrent(&dir, "first");
+        let second_path = make_torrent(&dir, "second.bin");
+
+        let session = Session::new(SessionSettings {
+            download_dir: dir.clone(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_addr: None,
+            queue: QueueSettings {
+                max_active: 1,
+                ..QueueSettings::default()
+            },
+            ..SessionSettings::default()
+        })
+        .unwrap();
+        let alerts = session.subscribe();
+        let first = session.add(&first_path).unwrap();
+        let second = session.add(&second_path).unwrap();
+        assert!(session.add(&first_path).is_err());
+        assert!(session.set_file_priorities(&first, vec![]).is_err());
+        session
+            .set_file_priorities(&first, vec![FilePriority::High])
+            .unwrap();
+        session.set_sequential(&first, true).unwrap();
+        assert!(session
+            .set_piece_deadline(&first, 100, Duration::from_secs(1))
+            .is_err());
+        session
+            .set_piece_deadline(&first, 0, Duration::from_secs(1))
+            .unwrap();
+        session.set_read_position(&first, Some(0)).unwrap();
+        assert_eq!(session.torrents(), vec![first.clone(), second.clone()]);
+        assert_eq!(
+            alerts.recv().unwrap().kind,
+            AlertKind::TorrentAdded {
+                name: "first".to_string()
+            }
+        );
+
+        // Only one runs at a time
+        assert_eq!(session.state(&first), Some(TorrentState::Running));
+        assert_eq!(session.state(&second), Some(TorrentState::Queued));
+        let conn = expect_seed(&session, &first_path, EncryptionPolicy::Disabled);
+        // Another peer is served alongside, encrypted connections find
+        // their torrent too
+        let other = expect_seed(&session, &first_path, EncryptionPolicy::Require);
+        drop((conn, other));
+
+        // Pausing frees the slot for the queue
+        session.pause(&first).unwrap();
+        assert_eq!(session.state(&first), Some(TorrentState::Paused));
+        wait_for(&session, &second, TorrentState::Running);
+        expect_seed(&session, &second_path, EncryptionPolicy::Disabled);
+
+        // Resumed, it's ahead in the queue again and takes the slot back
+        session.resume(&first).unwrap();
+        wait_for(&session, &first, TorrentState::Running);
+        assert_eq!(session.state(&second), Some(TorrentState::Queued));
+        session.remove(&second).unwrap();
+        assert_eq!(session.state(&second), None);
+        assert!(session.pause(&second).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_queue() {
+        let dir = env::temp_dir().join(format!("bittorrent-queue-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first_path = make_torrent(&dir, "first");
+        let second_path = make_torrent(&dir, "second.bin");
+        let queue = QueueSettings {
+            max_active: 1,
+            ..QueueSettings::default()
+        };
+        let session = Session::new(SessionSettings {
+            download_dir: dir.clone(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_addr: None,
+            queue: queue.clone(),
+            ..SessionSettings::default()
+        })
+        .unwrap();
+        let first = session.add(&first_path).unwrap();
+        let second = session.add(&second_path).unwrap();
+        assert_eq!(session.state(&second), Some(TorrentState::Queued));
+
+        // Moving to the front takes the slot over
+        session.set_queue_position(&second, 0).unwrap();
+        assert_eq!(session.queue_position(&second), Some(0));
+        assert_eq!(session.torrents(), vec![second.clone(), first.clone()]);
+        wait_for(&session, &second, TorrentState::Running);
+        assert_eq!(session.state(&first), Some(TorrentState::Queued));
+
+        // Torrents out of the queue's hands run regardless
+        session.set_auto_managed(&first, false).unwrap();
+        wait_for(&session, &first, TorrentState::Running);
+        expect_seed(&session, &first_path, EncryptionPolicy::Disabled);
+
+        // A goal of its own only pauses that one
+        let goals = SeedGoals {
+            seed_time: Some(Duration::from_secs(0)),
+            ..SeedGoals::default()
+        };
+        session
+            .set_torrent_seed_goals(&second, Some(goals))
+            .unwrap();
+        wait_for(&session, &second, TorrentState::Paused);
+        assert_eq!(session.stats(&second).unwrap().left, 0);
+        assert_eq!(session.state(&first), Some(TorrentState::Running));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_network_settings() {
+        let dir = env::temp_dir().join(format!("bittorrent-network-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = make_torrent(&dir, "shared");
+        let torrent_file = TorrentFile::open(&path).unwrap();
+        let router = Dht::bind("127.0.0.1:0").unwrap();
+        let session = Session::new(SessionSettings {
+            download_dir: dir.clone(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            encryption: EncryptionPolicy::Require,
+            dht_addr: Some("127.0.0.1:0".parse().unwrap()),
+            dht_routers: vec![router.local_addr().unwrap().to_string()],
+            ..SessionSettings::default()
+        })
+        .unwrap();
+        let info_hash = session.add(&path).unwrap();
+        let port = session.local_addr().port();
+        let peer = Peer {
+            ip: "127.0.0.1".parse().unwrap(),
+            port,
+        };
+        let connect = |utp: Option<&UtpSocket>, encryption| {
+            let (info_hash, num_pieces) = (info_hash.clone(), torrent_file.num_pieces());
+            match utp {
+                Some(socket) => Connection::connect_utp(
+                    socket,
+                    peer.clone(),
+                    info_hash,
+                    vec![9; 20],
+                    num_pieces,
+                    encryption,
+                ),
+                None => Connection::connect(
+                    peer.clone(),
+                    info_hash,
+                    vec![9; 20],
+                    num_pieces,
+                    encryption,
+                ),
+            }
+        };
+
+        // Plaintext is refused, encrypted uTP gets the seed's bitfield
+        assert!(connect(None, EncryptionPolicy::Disabled).is_err());
+        let socket = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let mut conn = connect(Some(&socket), EncryptionPolicy::Require).unwrap();
+        assert!(conn.is_encrypted());
+        conn.wait_for_input(Duration::from_secs(5)).unwrap();
+        assert!(matches!(
+            conn.read_message(),
+            Ok(Some(Message::Bitfield(_)))
+        ));
+
+        // The session announces the torrent on the DHT, where others find it
+        let other = Dht::bind("127.0.0.1:0").unwrap();
+        other.add_router(router.local_addr().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let peers = loop {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(200));
+            if !other.is_searching(&info_hash) {
+                other.search(&info_hash, None);
+            }
+            let peers = other.take_peers(&info_hash);
+            if !peers.is_empty() {
+                break peers;
+            }
+        };
+        assert_eq!(peers[0].port, port);
+        assert!(session.dht().is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plan() {
+        let now = Instant::now();
+        let stats = |left, upload_rate| TorrentStats {
+            left,
+            total_size: 1000,
+            upload_rate,
+            ..TorrentStats::default()
+        };
+        let mut torrents = Torrents {
+            entries: HashMap::new(),
+            order: vec![],
+            queue: QueueSettings {
+                max_active: 2,
+                max_active_downloads: 1,
+                max_active_seeds: 1,
+                startup_grace: Duration::from_secs(0),
+                ..QueueSettings::default()
+            },
+            goals: SeedGoals::default(),
+            deleting: HashSet::new(),
+        };
+        let mut add = |name: u8, stats: Option<TorrentStats>, state| {
+            let mut entry = Entry::new(PathBuf::new(), 1, 1);
+            entry.stats = stats;
+            entry.state = state;
+            torrents.entries.insert(vec![name], entry);
+            torrents.order.push(vec![name]);
+        };
+        add(0, None, TorrentState::Queued);
+        add(1, Some(stats(10, 0)), TorrentState::Queued);
+        add(2, Some(stats(0, 0)), TorrentState::Queued);
+        add(3, Some(stats(0, 0)), TorrentState::Paused);
+        add(4, Some(stats(10, 0)), TorrentState::Running);
+        add(
+            5,
+            Some(stats(0, 0)),
+            TorrentState::Error("full".to_string()),
+        );
+        add(6, Some(stats(0, 0)), TorrentState::Queued);
+
+        // One download and one seed by position, the slow one runs on the
+        // side
+        let want = plan(&torrents, now);
+        let expected: HashSet<_> = vec![vec![0], vec![2], vec![4]].into_iter().collect();
+        assert_eq!(want, expected);
+
+        // Once it picks up it has to queue like everyone else
+        torrents.entries.get_mut(&vec![4]).unwrap().stats = Some(stats(10, 5000));
+        assert!(!plan(&torrents, now).contains(&vec![4]));
+        torrents.queue.dont_count_slow = false;
+        torrents.entries.get_mut(&vec![0]).unwrap().auto_managed = false;
+        let want = plan(&torrents, now);
+        let expected: HashSet<_> = vec![vec![1], vec![2]].into_iter().collect();
+        assert_eq!(want, expected);
+    }
+
+    #[test]
+    fn test_goals_reached() {
+        let mut seed = TorrentStats {
+            total_size: 1000,
+            all_time_uploaded: 1500,
+            seeding_time: Duration::from_secs(60),
+            idle_time: Duration::from_secs(10),
+            ..TorrentStats::default()
+        };
+        let ratio = SeedGoals {
+            share_ratio: Some(2.0),
+            ..SeedGoals::default()
+        };
+        let idle = SeedGoals {
+            idle_time: Some(Duration::from_secs(10)),
+            action: GoalAction::RemoveWithData,
+            ..SeedGoals::default()
+        };
+        assert!(!ratio.met(&seed));
+        assert!(idle.met(&seed));
+        assert!(!SeedGoals::default().met(&seed));
+        seed.all_time_uploaded = 2000;
+        assert!(ratio.met(&seed));
+        // Still downloading
+        seed.left = 1;
+        assert!(!ratio.met(&seed));
+        seed.left = 0;
+
+        let mut torrents = Torrents {
+            entries: HashMap::new(),
+            order: vec![],
+            queue: QueueSettings::default(),
+            goals: ratio,
+            deleting: HashSet::new(),
+        };
+        for (name, state) in [
+            (0, TorrentState::Running),
+            (1, TorrentState::Queued),
+            (2, TorrentState::Paused),
+            (3, TorrentState::Running),
+        ] {
+            let mut entry = Entry::new(PathBuf::new(), 1, 1);
+            entry.stats = Some(seed.clone());
+            entry.state = state;
+            torrents.entries.insert(vec![name], entry);
+            torrents.order.push(vec![name]);
+        }
+        torrents.entries.get_mut(&vec![1]).unwrap().goals = Some(idle);
+        torrents.entries.get_mut(&vec![3]).unwrap().goals = Some(SeedGoals::default());
+        assert_eq!(
+            goals_reached(&torrents),
+            vec![
+                (vec![0], GoalAction::Pause),
+                (vec![1], GoalAction::RemoveWithData)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seed_goals() {
+        let dir = env::temp_dir().join(format!("bittorrent-goals-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = make_torrent(&dir, "kept");
+        let settings = SessionSettings {
+            download_dir: dir.clone(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_addr: None,
+            ..SessionSettings::default()
+        };
+        let goals = SeedGoals {
+            seed_time: Some(Duration::from_secs(2)),
+            ..SeedGoals::default()
+        };
+
+        let session = Session::new(settings.clone()).unwrap();
+        let alerts = session.subscribe();
+        session.set_seed_goals(goals.clone());
+        let info_hash = session.add(&path).unwrap();
+        assert_eq!(session.state(&info_hash), Some(TorrentState::Running));
+        wait_for(&session, &info_hash, TorrentState::Paused);
+        let reached = AlertKind::SeedGoalReached {
+            action: GoalAction::Pause,
+        };
+        assert!(alerts.iter().any(|alert| alert.kind == reached));
+        drop(session);
+
+        // The seeding time carries over to the next session, once the
+        // torrent has wound down and saved it
+        thread::sleep(Duration::from_millis(1500));
+        let session = Session::new(settings).unwrap();
+        session.set_seed_goals(SeedGoals {
+            action: GoalAction::RemoveWithData,
+            ..goals
+        });
+        let info_hash = session.add(&path).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while session.state(&info_hash).is_some() {
+            assert!(Instant::now() < deadline, "{:?}", session.stats(&info_hash));
+            thread::sleep(Duration::from_millis(20));
+        }
+        let data = dir.join("kept");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while data.exists() || resume::resume_path(&dir, &info_hash).exists() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.clone().try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.open(), 2);
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+        limit.set_max(0);
+        assert!(limit.try_acquire().is_none());
+    }
+}
@@ -0,0 +1,217 @@
+// Transfer statistics. Torrent::stats() hands out a TorrentStats snapshot
+// for UIs and metrics exporters to poll.
+
+use crate::connection::Connection;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// How far back rates are averaged
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Where data comes from, so stats can tell HTTP servers from BitTorrent
+/// peers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerKind {
+    BitTorrent,
+    WebSeed,
+    HttpSeed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub kind: PeerKind,
+    // ip:port for peers, the URL for seeds
+    pub address: String,
+    // Payload bytes
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Message overhead, always 0 for web and HTTP seeds
+    pub protocol_downloaded: u64,
+    pub protocol_uploaded: u64,
+    // Payload bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // Has every piece
+    pub is_seed: bool,
+}
+
+/// Bytes moved in one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Transfer {
+    // Piece data
+    pub payload: u64,
+    // Messages and their headers
+    pub protocol: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TorrentStats {
+    pub pieces: usize,
+    pub num_pieces: usize,
+    pub total_size: u64,
+    // Bytes in pieces of files that aren't skipped
+    pub total_wanted: u64,
+    // Wanted bytes still missing
+    pub left: u64,
+    pub downloaded: Transfer,
+    pub uploaded: Transfer,
+    // Payload bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // None while nothing is coming in
+    pub eta: Option<Duration>,
+    // Blocks we didn't ask for or already had
+    pub wasted: u64,
+    // Pieces that failed the hash check
+    pub failed: u64,
+    pub connected_peers: usize,
+    // Swarm size as last reported by the tracker
+    pub seeds: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<PeerStats>,
+    // Payload bytes over every run, kept in the resume data
+    pub all_time_downloaded: u64,
+    pub all_time_uploaded: u64,
+    // Over every run as well
+    pub seeding_time: Duration,
+    // Seeding without uploading anything
+    pub idle_time: Duration,
+}
+
+impl TorrentStats {
+    /// Uploaded over downloaded, or over the size for data we had already,
+    /// over every run
+    pub fn ratio(&self) -> f64 {
+        let downloaded = self.all_time_downloaded.max(self.total_size);
+        match downloaded {
+            0 => 0.0,
+            _ => self.all_time_uploaded as f64 / downloaded as f64,
+        }
+    }
+}
+
+/// Counters carried from run to run through the resume data
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct History {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub seeding_time: Duration,
+    pub idle_time: Duration,
+}
+
+/// How long `left` bytes take at `rate` bytes per second
+pub fn eta(left: u64, rate: u64) -> Option<Duration> {
+    match (left, rate) {
+        (0, _) => Some(Duration::from_secs(0)),
+        (_, 0) => None,
+        _ => Some(Duration::from_secs(left.div_ceil(rate))),
+    }
+}
+
+/// Rolling rate of a byte counter over the last few seconds
+#[derive(Debug, Clone, Default)]
+pub struct RateMeter {
+    // (when, counter) with the oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    pub fn new() -> RateMeter {
+        RateMeter::default()
+    }
+
+    /// Record the counter's current `total`
+    pub fn update(&mut self, now: Instant, total: u64) {
+        self.samples.push_back((now, total));
+        // Keep one sample from before the window to measure from
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(start, from)), Some(&(end, to))) if end > start => {
+                ((to - from) as f64 / end.duration_since(start).as_secs_f64()) as u64
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// A torrent's running totals across all its connections
+#[derive(Debug, Default)]
+pub struct Totals {
+    pub downloaded: Transfer,
+    pub uploaded: Transfer,
+    pub wasted: u64,
+    pub failed: u64,
+}
+
+impl Totals {
+    /// Add whatever `conn` transferred since the last count. `seen` holds
+    /// its counters as of then, zeros for a new connection.
+    pub fn count(&mut self, conn: &Connection, seen: &mut [u64; 5]) {
+        let now = [
+            conn.downloaded,
+            conn.received_bytes,
+            conn.uploaded,
+            conn.sent_bytes,
+            conn.wasted,
+        ];
+        let delta: Vec<u64> = now
+            .iter()
+            .zip(seen.iter())
+            .map(|(now, seen)| now - seen)
+            .collect();
+        self.downloaded.payload += delta[0];
+        self.downloaded.protocol += delta[1] - delta[0];
+        self.uploaded.payload += delta[2];
+        self.uploaded.protocol += delta[3] - delta[2];
+        self.wasted += delta[4];
+        *seen = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let mut meter = RateMeter::new();
+        let start = Instant::now();
+        assert_eq!(meter.rate(), 0);
+        for secs in 0..=10 {
+            meter.update(start + Duration::from_secs(secs), secs * 1000);
+        }
+        assert_eq!(meter.rate(), 1000);
+
+        // Only the last few seconds count
+        meter.update(start + Duration::from_secs(11), 10_000);
+        meter.update(start + Duration::from_secs(16), 10_000);
+        assert_eq!(meter.rate(), 0);
+    }
+
+    #[test]
+    fn test_eta() {
+        assert_eq!(eta(0, 0), Some(Duration::from_secs(0)));
+        assert_eq!(eta(100, 0), None);
+        assert_eq!(eta(1000, 300), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_ratio() {
+        let mut stats = TorrentStats {
+            total_size: 1000,
+            ..TorrentStats::default()
+        };
+        stats.all_time_uploaded = 500;
+        // Seeding what was already on disk
+        assert_eq!(stats.ratio(), 0.5);
+        stats.all_time_downloaded = 2000;
+        assert_eq!(stats.ratio(), 0.25);
+        assert_eq!(TorrentStats::default().ratio(), 0.0);
+    }
+}
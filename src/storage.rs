@@ -0,0 +1,745 @@
+// Maps the torrent's linear byte space onto files on disk.
+//
+// Pieces are laid out back to back across the torrent's files, so a single
+// block may start in one file and end in the next ones.
+
+use crate::mmap_storage::MmapStorage;
+use crate::partfile::{self, PartFile};
+use crate::torrent::{FileAttr, FileInfo, TorrentFile};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Read size when hashing pieces
+const HASH_CHUNK: usize = 1 << 16;
+
+// Names Windows won't let us create, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+pub trait Storage: fmt::Debug {
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>>;
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> io::Result<()>;
+
+    /// Make sure everything written so far is on disk
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// SHA-1 of the piece as currently stored
+    fn hash_piece(&mut self, index: u32) -> io::Result<[u8; 20]>;
+
+    /// Keep skipped files' share of pieces out of those files. Storages
+    /// without a partfile create them anyway.
+    fn set_file_priorities(&mut self, _priorities: &[FilePriority]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How much a file is wanted. Skipped files aren't downloaded, higher
+/// priorities are downloaded first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    pub fn from_u8(n: u8) -> Option<FilePriority> {
+        match n {
+            0 => Some(FilePriority::Skip),
+            1 => Some(FilePriority::Low),
+            2 => Some(FilePriority::Normal),
+            3 => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Each piece gets the highest priority of the files it overlaps, so only
+/// pieces entirely in skipped files are skipped
+pub fn piece_priorities(
+    files: &[StorageFile],
+    priorities: &[FilePriority],
+    piece_length: u64,
+    num_pieces: usize,
+) -> Vec<FilePriority> {
+    let length = files.iter().map(|f| f.length).sum::<u64>();
+    (0..num_pieces as u64)
+        .map(|index| {
+            let begin = index * piece_length;
+            let size = piece_length.min(length.saturating_sub(begin));
+            split_range(files, begin, size)
+                .into_iter()
+                .filter(|&(i, _, _)| files[i].on_disk())
+                .map(|(i, _, _)| priorities[i])
+                .max()
+                .unwrap_or(FilePriority::Skip)
+        })
+        .collect()
+}
+
+/// The storage implementations to choose from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    File,
+    Mmap,
+}
+
+impl Backend {
+    pub fn open(self, torrent: &TorrentFile, dir: &Path) -> Box<dyn Storage> {
+        match self {
+            Backend::File => Box::new(FileStorage::new(torrent, dir)),
+            Backend::Mmap => Box::new(MmapStorage::new(torrent, dir)),
+        }
+    }
+}
+
+/// A file as laid out in the torrent's byte space
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    pub attr: FileAttr,
+}
+
+impl StorageFile {
+    // Padding reads as zeros and symlinks are only metadata, neither gets
+    // created on disk
+    pub fn on_disk(&self) -> bool {
+        !self.attr.padding && !self.attr.symlink
+    }
+}
+
+/// Stores pieces in plain files under a download directory
+#[derive(Debug)]
+pub struct FileStorage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    length: u64,
+    // Open files, and whether they were opened for writing
+    handles: HashMap<usize, (File, bool)>,
+    priorities: Vec<FilePriority>,
+    // Skipped files' share of pieces goes here, when there is one
+    part_path: Option<PathBuf>,
+    part_file: Option<PartFile>,
+}
+
+/// Turn one path component from a torrent into something safe to create
+/// inside the download directory. Returns None for components that should be
+/// dropped entirely, like "." and "..".
+pub fn sanitize_component(component: &str) -> Option<String> {
+    let mut name: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently strips these, which could make two files collide
+    while name.ends_with('.') || name.ends_with(' ') {
+        name.pop();
+    }
+    if name.is_empty() {
+        return None;
+    }
+
+    let stem = name.split('.').next().unwrap().to_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        name.insert(0, '_');
+    }
+
+    Some(name)
+}
+
+/// Relative path for a file's components, guaranteed to stay inside the
+/// directory it is joined to
+pub fn sanitize_path(components: &[String]) -> PathBuf {
+    let path: PathBuf = components
+        .iter()
+        .filter_map(|c| sanitize_component(c))
+        .collect();
+
+    if path.as_os_str().is_empty() {
+        PathBuf::from("_")
+    } else {
+        path
+    }
+}
+
+/// Lay out files back to back, with sanitized paths under `dir`
+pub fn layout(dir: &Path, files: &[FileInfo]) -> Vec<StorageFile> {
+    let mut offset = 0;
+    files
+        .iter()
+        .map(|f| {
+            let file = StorageFile {
+                path: dir.join(sanitize_path(&f.path)),
+                offset,
+                length: f.length,
+                attr: f.attr,
+            };
+            offset += f.length;
+            file
+        })
+        .collect()
+}
+
+/// Delete the files laid out under `dir`, and the directories that leaves
+/// empty. Files that are already gone are fine.
+pub fn delete_files(dir: &Path, files: &[StorageFile]) -> io::Result<()> {
+    for file in files {
+        match fs::remove_file(&file.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Stops at the first directory that still has something in it
+        for parent in file.path.ancestors().skip(1) {
+            if parent == dir || !parent.starts_with(dir) || fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The pieces of the range [offset, offset + length) that fall in each file,
+/// as (file index, offset in file, length)
+pub fn split_range(files: &[StorageFile], offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+    let end = offset + length;
+    files
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.length > 0 && f.offset < end && offset < f.offset + f.length)
+        .map(|(i, f)| {
+            let begin = offset.max(f.offset);
+            let stop = end.min(f.offset + f.length);
+            (i, begin - f.offset, stop - begin)
+        })
+        .collect()
+}
+
+/// Offset of a block in the torrent's byte space, checking it lies within a
+/// single piece
+pub fn block_offset(
+    piece_length: u64,
+    total_length: u64,
+    index: u32,
+    begin: u32,
+    length: u64,
+) -> io::Result<u64> {
+    let offset = index as u64 * piece_length + begin as u64;
+    if begin as u64 + length > piece_length || offset + length > total_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("block {}:{}+{} out of range", index, begin, length),
+        ));
+    }
+    Ok(offset)
+}
+
+impl FileStorage {
+    pub fn new(torrent: &TorrentFile, dir: &Path) -> FileStorage {
+        FileStorage::from_files(dir, &torrent.files, torrent.piece_length())
+            .with_part_file(partfile::part_path(dir, &torrent.info_hash))
+    }
+
+    pub fn from_files(dir: &Path, files: &[FileInfo], piece_length: u64) -> FileStorage {
+        FileStorage::from_layout(layout(dir, files), piece_length)
+    }
+
+    /// Storage over files that are already laid out, used as is
+    pub fn from_layout(files: Vec<StorageFile>, piece_length: u64) -> FileStorage {
+        let length = files.iter().map(|f| f.length).sum();
+        FileStorage {
+            priorities: vec![FilePriority::Normal; files.len()],
+            files,
+            piece_length,
+            length,
+            handles: HashMap::new(),
+            part_path: None,
+            part_file: None,
+        }
+    }
+
+    /// Keep data of skipped files in a partfile at `path`
+    pub fn with_part_file(mut self, path: PathBuf) -> FileStorage {
+        self.part_path = Some(path);
+        self
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    fn piece_range(&self, index: u32) -> (u64, u64) {
+        let begin = index as u64 * self.piece_length;
+        let end = (begin + self.piece_length).min(self.length);
+        (begin, end.saturating_sub(begin))
+    }
+
+    fn block_range(&self, index: u32, begin: u32, length: u64) -> io::Result<u64> {
+        block_offset(self.piece_length, self.length, index, begin, length)
+    }
+
+    fn open(&mut self, i: usize, write: bool) -> io::Result<&mut File> {
+        // A handle opened for reading can't be written to
+        if let Some((_, false)) = self.handles.get(&i) {
+            if write {
+                self.handles.remove(&i);
+            }
+        }
+        if !self.handles.contains_key(&i) {
+            let path = &self.files[i].path;
+            let file = if write {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?
+            } else {
+                File::open(path)?
+            };
+            self.handles.insert(i, (file, write));
+        }
+        Ok(&mut self.handles.get_mut(&i).unwrap().0)
+    }
+
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+        for (i, file_offset, length) in split_range(&self.files, offset, buf.len() as u64) {
+            let chunk = &mut buf[pos..pos + length as usize];
+            pos += length as usize;
+            if !self.files[i].on_disk() {
+                chunk.fill(0);
+                continue;
+            }
+            self.read_file(i, file_offset, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn is_skipped(&self, i: usize) -> bool {
+        self.part_path.is_some() && self.priorities[i] == FilePriority::Skip
+    }
+
+    fn part(&mut self) -> io::Result<&mut PartFile> {
+        if self.part_file.is_none() {
+            let num_pieces = self.length.div_ceil(self.piece_length) as usize;
+            let path = self.part_path.as_ref().unwrap();
+            self.part_file = Some(PartFile::open(path, num_pieces, self.piece_length)?);
+        }
+        Ok(self.part_file.as_mut().unwrap())
+    }
+
+    // Piece and offset within it of a file offset
+    fn piece_of(&self, i: usize, file_offset: u64) -> (u32, u64) {
+        let offset = self.files[i].offset + file_offset;
+        (
+            (offset / self.piece_length) as u32,
+            offset % self.piece_length,
+        )
+    }
+
+    // Skipped files are read from the partfile where it has their piece,
+    // from what's left of the file otherwise
+    fn read_file(&mut self, i: usize, file_offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (index, begin) = self.piece_of(i, file_offset);
+        if self.is_skipped(i) && self.part()?.has_piece(index) {
+            return self.part()?.read(index, begin, buf);
+        }
+        let file = self.open(i, false)?;
+        file.seek(SeekFrom::Start(file_offset))?;
+        file.read_exact(buf)
+    }
+
+    fn write_file(&mut self, i: usize, file_offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.is_skipped(i) {
+            let (index, begin) = self.piece_of(i, file_offset);
+            return self.part()?.write(index, begin, data);
+        }
+        let file = self.open(i, true)?;
+        file.seek(SeekFrom::Start(file_offset))?;
+        file.write_all(data)
+    }
+
+    // The parts of file i in each piece, as (file offset, length)
+    fn file_pieces(&self, i: usize) -> Vec<(u64, u64)> {
+        let file = &self.files[i];
+        let mut pieces = vec![];
+        let mut offset = 0;
+        while offset < file.length {
+            let in_piece = (file.offset + offset) % self.piece_length;
+            let length = (self.piece_length - in_piece).min(file.length - offset);
+            pieces.push((offset, length));
+            offset += length;
+        }
+        pieces
+    }
+
+    // Whether piece `index` overlaps an on disk file that is (not) skipped
+    fn piece_overlaps(&self, index: u32, skipped: bool) -> bool {
+        let (begin, length) = self.piece_range(index);
+        split_range(&self.files, begin, length)
+            .into_iter()
+            .any(|(i, _, _)| self.files[i].on_disk() && self.is_skipped(i) == skipped)
+    }
+
+    // Copy file i's share of pieces between the file and the partfile, for
+    // the pieces that need it
+    fn move_file(&mut self, i: usize, to_part: bool) -> io::Result<()> {
+        for (file_offset, length) in self.file_pieces(i) {
+            let (index, begin) = self.piece_of(i, file_offset);
+            let mut buf = vec![0; length as usize];
+            if to_part {
+                // Pieces entirely in skipped files without a slot are read
+                // from the files they're in
+                if !self.part()?.has_piece(index) && !self.piece_overlaps(index, false) {
+                    continue;
+                }
+                match self.open(i, false) {
+                    Ok(file) => {
+                        file.seek(SeekFrom::Start(file_offset))?;
+                        file.read_exact(&mut buf)?;
+                    }
+                    // Nothing there to keep
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+                self.part()?.write(index, begin, &buf)?;
+            } else if self.part()?.has_piece(index) {
+                self.part()?.read(index, begin, &mut buf)?;
+                let file = self.open(i, true)?;
+                file.seek(SeekFrom::Start(file_offset))?;
+                file.write_all(&buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let offset = self.block_range(index, begin, length as u64)?;
+        let mut block = vec![0; length as usize];
+        self.read_range(offset, &mut block)?;
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let offset = self.block_range(index, begin, data.len() as u64)?;
+        let mut pos = 0;
+        for (i, file_offset, length) in split_range(&self.files, offset, data.len() as u64) {
+            let chunk = &data[pos..pos + length as usize];
+            pos += length as usize;
+            if !self.files[i].on_disk() {
+                continue;
+            }
+            self.write_file(i, file_offset, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (file, writable) in self.handles.values() {
+            if *writable {
+                file.sync_data()?;
+            }
+        }
+        if let Some(part) = &mut self.part_file {
+            part.flush()?;
+        }
+        // Empty files never get a block written to them
+        for i in 0..self.files.len() {
+            let file = &self.files[i];
+            if file.length == 0 && file.on_disk() && !file.path.exists() && !self.is_skipped(i) {
+                self.open(i, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn hash_piece(&mut self, index: u32) -> io::Result<[u8; 20]> {
+        let (mut offset, length) = self.piece_range(index);
+        let end = offset + length;
+        let mut hasher = Sha1::new();
+        let mut buf = vec![0; HASH_CHUNK];
+
+        while offset < end {
+            let n = ((end - offset) as usize).min(HASH_CHUNK);
+            self.read_range(offset, &mut buf[..n])?;
+            hasher.input(&buf[..n]);
+            offset += n as u64;
+        }
+
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&hasher.result());
+        Ok(hash)
+    }
+
+    fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> io::Result<()> {
+        if priorities.len() != self.files.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} priorities for {} files",
+                    priorities.len(),
+                    self.files.len()
+                ),
+            ));
+        }
+        let was_skipped: Vec<bool> = (0..self.files.len()).map(|i| self.is_skipped(i)).collect();
+        self.priorities = priorities.to_vec();
+        if self.part_path.is_none() {
+            return Ok(());
+        }
+
+        for (i, was_skipped) in was_skipped.into_iter().enumerate() {
+            if !self.files[i].on_disk() || was_skipped == self.is_skipped(i) {
+                continue;
+            }
+            self.move_file(i, self.is_skipped(i))?;
+        }
+        // Pieces no longer touching a skipped file don't need their slots
+        for index in self.part()?.pieces() {
+            if !self.piece_overlaps(index, true) {
+                self.part()?.free(index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bittorrent-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file(path: &[&str], length: u64) -> FileInfo {
+        FileInfo {
+            path: path.iter().map(|c| c.to_string()).collect(),
+            length,
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let path = |c: &[&str]| sanitize_path(&c.iter().map(|c| c.to_string()).collect::<Vec<_>>());
+
+        assert_eq!(path(&["a", "b.txt"]), PathBuf::from("a/b.txt"));
+        assert_eq!(
+            path(&["..", "..", "etc", "passwd"]),
+            PathBuf::from("etc/passwd")
+        );
+        assert_eq!(path(&["/etc", "passwd"]), PathBuf::from("_etc/passwd"));
+        assert_eq!(path(&["a/../../b"]), PathBuf::from("a_.._.._b"));
+        assert_eq!(path(&["CON"]), PathBuf::from("_CON"));
+        assert_eq!(path(&["nul.txt"]), PathBuf::from("_nul.txt"));
+        assert_eq!(path(&["file. "]), PathBuf::from("file"));
+        assert_eq!(path(&["a:b*c?"]), PathBuf::from("a_b_c_"));
+        assert_eq!(path(&["", "."]), PathBuf::from("_"));
+    }
+
+    #[test]
+    fn test_split_range() {
+        let files = layout(
+            Path::new(""),
+            &[
+                file(&["a"], 10),
+                file(&["b"], 0),
+                file(&["c"], 5),
+                file(&["d"], 10),
+            ],
+        );
+
+        assert_eq!(split_range(&files, 0, 10), vec![(0, 0, 10)]);
+        assert_eq!(
+            split_range(&files, 8, 10),
+            vec![(0, 8, 2), (2, 0, 5), (3, 0, 3)]
+        );
+        assert_eq!(split_range(&files, 24, 1), vec![(3, 9, 1)]);
+    }
+
+    #[test]
+    fn test_pieces_spanning_files() {
+        let dir = test_dir("storage");
+        // Piece 1 covers the end of a, all of b and the start of c
+        let files = [
+            file(&["t", "a"], 20),
+            file(&["t", "sub", "b"], 8),
+            file(&["t", "..", "c"], 30),
+            file(&["t", "empty"], 0),
+        ];
+        let mut storage = FileStorage::from_files(&dir, &files, 16);
+        let data: Vec<u8> = (0..58).collect();
+
+        for (index, piece) in data.chunks(16).enumerate() {
+            // Write in two blocks so one of them straddles the files
+            storage.write_block(index as u32, 0, &piece[..6]).unwrap();
+            storage.write_block(index as u32, 6, &piece[6..]).unwrap();
+        }
+        storage.flush().unwrap();
+
+        assert_eq!(fs::read(dir.join("t/a")).unwrap(), &data[..20]);
+        assert_eq!(fs::read(dir.join("t/sub/b")).unwrap(), &data[20..28]);
+        assert_eq!(fs::read(dir.join("t/c")).unwrap(), &data[28..]);
+        assert_eq!(fs::read(dir.join("t/empty")).unwrap().len(), 0);
+
+        assert_eq!(storage.read_block(1, 2, 14).unwrap(), &data[18..32]);
+        assert_eq!(storage.read_block(3, 0, 10).unwrap(), &data[48..]);
+        assert!(storage.read_block(3, 0, 11).is_err());
+        assert!(storage.write_block(0, 10, &[0; 7]).is_err());
+
+        let expected = Sha1::digest(&data[16..32]);
+        assert_eq!(&storage.hash_piece(1).unwrap()[..], expected.as_slice());
+        let expected = Sha1::digest(&data[48..]);
+        assert_eq!(&storage.hash_piece(3).unwrap()[..], expected.as_slice());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_padding_files() {
+        let dir = test_dir("storage-padding");
+        let mut pad = file(&["t", ".pad", "6"], 6);
+        pad.attr.padding = true;
+        let files = [file(&["t", "a"], 10), pad, file(&["t", "b"], 16)];
+        let mut storage = FileStorage::from_files(&dir, &files, 16);
+
+        let mut data = [0; 32];
+        data[..10].copy_from_slice(&[1; 10]);
+        data[16..].copy_from_slice(&[2; 16]);
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 0, &data[16..]).unwrap();
+        storage.flush().unwrap();
+
+        // Padding never hits the disk but still hashes as zeros
+        assert!(!dir.join("t/.pad").exists());
+        assert_eq!(storage.read_block(0, 8, 8).unwrap(), &data[8..16]);
+        let expected = Sha1::digest(&data[..16]);
+        assert_eq!(&storage.hash_piece(0).unwrap()[..], expected.as_slice());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_data() {
+        let dir = test_dir("storage-missing");
+        let mut storage = FileStorage::from_files(&dir, &[file(&["x"], 10)], 16);
+
+        assert!(storage.read_block(0, 0, 10).is_err());
+        assert!(storage.hash_piece(0).is_err());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        use FilePriority::*;
+        let files = layout(
+            Path::new("."),
+            &[file(&["a"], 10), file(&["b"], 20), file(&["c"], 2)],
+        );
+        // Pieces [0, 8) [8, 16) [16, 24) [24, 32)
+        let pieces = piece_priorities(&files, &[Skip, High, Skip], 8, 4);
+        assert_eq!(pieces, vec![Skip, High, High, High]);
+        let pieces = piece_priorities(&files, &[Low, Skip, Skip], 8, 4);
+        assert_eq!(pieces, vec![Low, Low, Skip, Skip]);
+        assert_eq!(FilePriority::from_u8(High.to_u8()), Some(High));
+        assert_eq!(FilePriority::from_u8(4), None);
+    }
+
+    #[test]
+    fn test_skipped_files() {
+        use FilePriority::*;
+        let dir = test_dir("storage-skipped");
+        let files = [
+            file(&["t", "a"], 10),
+            file(&["t", "b"], 10),
+            file(&["t", "c"], 10),
+        ];
+        let part_path = dir.join("parts");
+        let mut storage =
+            FileStorage::from_files(&dir, &files, 16).with_part_file(part_path.clone());
+        storage
+            .set_file_priorities(&[Normal, Skip, Normal])
+            .unwrap();
+
+        // Pieces [0, 16) and [16, 30) both straddle b
+        let data: Vec<u8> = (0..30).collect();
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 0, &data[16..]).unwrap();
+        storage.flush().unwrap();
+        assert!(!dir.join("t/b").exists());
+        assert!(part_path.exists());
+        assert_eq!(fs::read(dir.join("t/a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(dir.join("t/c")).unwrap(), &data[20..]);
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &data[..16]);
+        assert_eq!(storage.read_block(1, 0, 14).unwrap(), &data[16..]);
+
+        // Wanted after all, b gets its data and the partfile goes
+        storage.set_file_priorities(&[Normal, Low, Normal]).unwrap();
+        assert_eq!(fs::read(dir.join("t/b")).unwrap(), &data[10..20]);
+        assert!(!part_path.exists());
+
+        // Skipping a moves its share of the straddling piece aside
+        storage
+            .set_file_priorities(&[Skip, Normal, Normal])
+            .unwrap();
+        fs::remove_file(dir.join("t/a")).unwrap();
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &data[..16]);
+        let mut hasher = Sha1::new();
+        hasher.input(&data[..16]);
+        assert_eq!(&storage.hash_piece(0).unwrap()[..], &hasher.result()[..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_delete_files() {
+        let dir = test_dir("storage-delete");
+        let files = layout(
+            &dir,
+            &[
+                file(&["t", "a", "1"], 1),
+                file(&["t", "b"], 1),
+                file(&["t", "c"], 1),
+            ],
+        );
+        for f in &files[..2] {
+            fs::create_dir_all(f.path.parent().unwrap()).unwrap();
+            fs::write(&f.path, b"x").unwrap();
+        }
+        fs::write(dir.join("other"), b"x").unwrap();
+
+        delete_files(&dir, &files).unwrap();
+        assert!(!dir.join("t").exists());
+        assert!(dir.join("other").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
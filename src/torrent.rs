@@ -1,66 +1,455 @@
+use crate::merkle::{self, Hash, BLOCK_SIZE};
+use crate::message::HashRequest;
+use crate::tracker::PeerSource;
 use serde::{Deserialize, Serialize};
 use serde_bencode;
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
 
 #[derive(Debug, Deserialize, Serialize)]
-struct BencodeInfo {
-    name: String,
-    length: u64,
+pub(crate) struct BencodeFile {
+    pub(crate) length: u64,
+    pub(crate) path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) attr: Option<String>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) symlink_path: Option<Vec<String>>,
+}
+
+// Keys we don't know about are dropped, so the info hash is taken over the
+// dict as it was read, see raw_info. Optional keys stay out of created
+// torrents when absent.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct BencodeInfo {
+    pub(crate) name: String,
+    // Single file torrents have a length, multi file torrents a file list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) files: Option<Vec<BencodeFile>>,
+    // Attributes of the file in single file torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) attr: Option<String>,
     #[serde(rename = "piece length")]
-    piece_length: u64,
-    pieces: ByteBuf,
+    pub(crate) piece_length: u64,
+    // SHA-1 piece hashes, only v1 and hybrid torrents have them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pieces: Option<ByteBuf>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) meta_version: Option<u64>,
+    // Nested dicts of names, files are marked by an empty key. Kept as a
+    // plain value since the keys are the file names.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_tree: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) private: Option<u8>,
+    // Tags torrents made for a particular tracker so the info hash differs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct BencodeTorrent {
+    // Trackerless torrents have no announce
+    #[serde(default)]
     announce: String,
     info: BencodeInfo,
+    // Pieces root -> concatenated piece hashes, for v2 files over a piece
+    #[serde(rename = "piece layers", default)]
+    piece_layers: Option<Value>,
+    // Web seeds (BEP 19), either one URL or a list of them
+    #[serde(rename = "url-list", default)]
+    url_list: Option<Value>,
+    // HTTP seeds (BEP 17), always a list
+    #[serde(default)]
+    httpseeds: Option<Value>,
+}
+
+/// File attributes from BEP 47
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct FileAttr {
+    // Padding files only exist to align the next file to a piece, they
+    // are all zeros and never go on disk
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttr {
+    // Unknown flags are ignored, as the BEP asks
+    pub fn parse(attr: &str) -> FileAttr {
+        FileAttr {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+/// A file inside the torrent. Pieces run through the files back to back.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FileInfo {
+    // Path components as given by the torrent, starting with the torrent
+    // name for multi file torrents. Not sanitized.
+    pub path: Vec<String>,
+    pub length: u64,
+    pub attr: FileAttr,
+    // Target of a symlink, relative to the torrent's root
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// A file from a v2 file tree
+#[derive(Debug, Clone, Deserialize)]
+pub struct V2File {
+    // Same convention as FileInfo::path
+    pub path: Vec<String>,
+    pub length: u64,
+    // Merkle root of the file, empty files have none
+    pub pieces_root: Option<Hash>,
+}
+
+/// The v2 (BEP 52) parts of a torrent
+#[derive(Debug, Deserialize)]
+pub struct V2Info {
+    // Full SHA-256 of the info dict
+    pub info_hash: Hash,
+    pub files: Vec<V2File>,
+    pub piece_layers: HashMap<Hash, Vec<Hash>>,
+    // Piece layers still being filled in from peers' hashes messages
+    pub pending_layers: HashMap<Hash, Vec<Option<Hash>>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TorrentFile {
     name: String,
     pub announce: String,
+    // What goes in handshakes and announces: the SHA-1 info hash, or the
+    // v2 one truncated to 20 bytes for v2 only torrents
     pub info_hash: Vec<u8>,
     pub length: u64,
     piece_length: u64,
+    // Empty for v2 only torrents, their pieces are checked against the
+    // piece layers
     pub piece_hashes: Vec<[u8; 20]>,
+    pub files: Vec<FileInfo>,
+    pub v2: Option<V2Info>,
+    // Web seed URLs
+    pub url_list: Vec<String>,
+    // HTTP seed URLs, these take the info hash and piece as query parameters
+    pub http_seeds: Vec<String>,
+    // Private torrents (BEP 27) only get peers from their own trackers
+    pub private: bool,
+}
+
+fn invalid(msg: String) -> serde_bencode::Error {
+    serde_bencode::Error::Custom(msg)
 }
 
-impl BencodeInfo {
-    fn hash(&self) -> Result<Vec<u8>, serde_bencode::Error> {
-        let bytes = serde_bencode::to_bytes(self)?;
-        let mut hasher = Sha1::new();
+// Walk a file tree in key order, which is also the order of the files
+fn walk_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> Result<(), serde_bencode::Error> {
+    let dict = match node {
+        Value::Dict(dict) => dict,
+        _ => return Err(invalid("file tree node is not a dict".to_string())),
+    };
 
-        hasher.input(bytes);
+    if let Some(file) = dict.get(&b""[..]) {
+        let file = match file {
+            Value::Dict(file) if !path.is_empty() => file,
+            _ => return Err(invalid("invalid file in file tree".to_string())),
+        };
+        let length = match file.get(&b"length"[..]) {
+            Some(Value::Int(length)) if *length >= 0 => *length as u64,
+            _ => return Err(invalid(format!("{} has no length", path.join("/")))),
+        };
+        let pieces_root = match file.get(&b"pieces root"[..]) {
+            Some(Value::Bytes(root)) if root.len() == 32 => Some(root[..].try_into().unwrap()),
+            None => None,
+            _ => return Err(invalid(format!("{} has a bad pieces root", path.join("/")))),
+        };
+        if length > 0 && pieces_root.is_none() {
+            return Err(invalid(format!("{} has no pieces root", path.join("/"))));
+        }
+        files.push(V2File {
+            path: path.clone(),
+            length,
+            pieces_root,
+        });
+        return Ok(());
+    }
 
-        Ok(hasher.result().to_vec())
+    let mut names: Vec<&Vec<u8>> = dict.keys().collect();
+    names.sort();
+    for name in names {
+        let name_str = String::from_utf8(name.clone())
+            .map_err(|_| invalid("file name is not valid UTF-8".to_string()))?;
+        path.push(name_str);
+        walk_file_tree(&dict[name], path, files)?;
+        path.pop();
     }
+    Ok(())
+}
+
+// Seeds that aren't valid UTF-8 are skipped rather than failing the whole
+// torrent
+fn parse_url_list(value: Option<&Value>) -> Vec<String> {
+    let urls = match value {
+        Some(Value::Bytes(url)) => vec![url],
+        Some(Value::List(list)) => list
+            .iter()
+            .filter_map(|v| match v {
+                Value::Bytes(url) => Some(url),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    urls.into_iter()
+        .filter_map(|url| String::from_utf8(url.clone()).ok())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+fn parse_piece_layers(
+    value: Option<&Value>,
+) -> Result<HashMap<Hash, Vec<Hash>>, serde_bencode::Error> {
+    let dict = match value {
+        Some(Value::Dict(dict)) => dict,
+        None => return Ok(HashMap::new()),
+        _ => return Err(invalid("piece layers is not a dict".to_string())),
+    };
+    let mut layers = HashMap::new();
+    for (root, layer) in dict {
+        match layer {
+            Value::Bytes(layer) if root.len() == 32 && layer.len().is_multiple_of(32) => {
+                let hashes = layer.chunks(32).map(|h| h.try_into().unwrap()).collect();
+                layers.insert(root[..].try_into().unwrap(), hashes);
+            }
+            _ => return Err(invalid("invalid piece layer".to_string())),
+        }
+    }
+    Ok(layers)
+}
+
+// Length of the bencoded value at the start of `buf`
+fn value_len(buf: &[u8]) -> Option<usize> {
+    match *buf.first()? {
+        b'i' => Some(buf.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *buf.get(pos)? != b'e' {
+                pos += value_len(&buf[pos..])?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = buf.iter().position(|&b| b == b':')?;
+            let length: usize = std::str::from_utf8(&buf[..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + length;
+            if end > buf.len() {
+                return None;
+            }
+            Some(end)
+        }
+        _ => None,
+    }
+}
+
+// The info dict of a torrent exactly as written, which is what the info
+// hash is taken over
+fn raw_info(torrent: &[u8]) -> Option<&[u8]> {
+    if torrent.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while *torrent.get(pos)? != b'e' {
+        let key = &torrent[pos..pos + value_len(&torrent[pos..])?];
+        pos += key.len();
+        let length = value_len(&torrent[pos..])?;
+        if key == b"4:info" {
+            return Some(&torrent[pos..pos + length]);
+        }
+        pos += length;
+    }
+    None
 }
 
 impl BencodeTorrent {
-    fn to_torrent_file(self) -> Result<TorrentFile, serde_bencode::Error> {
-        // Check valid number of pieces
-        assert!(self.info.pieces.len() % 20 == 0);
+    // `info` is the raw info dict, for the info hashes
+    fn into_torrent_file(self, info: &[u8]) -> Result<TorrentFile, serde_bencode::Error> {
+        let v2 = match self.info.meta_version {
+            None => None,
+            Some(2) => Some(self.to_v2_info(info)?),
+            Some(version) => return Err(invalid(format!("unsupported meta version {}", version))),
+        };
 
-        // Convert into hashes
-        let mut piece_hashes: Vec<[u8; 20]> = vec![];
-        for chunk in self.info.pieces.chunks(20) {
-            piece_hashes.push(chunk.try_into().unwrap())
+        let (piece_hashes, files) = match (&self.info.pieces, &v2) {
+            (Some(pieces), _) => (self.v1_piece_hashes(pieces)?, self.v1_files()?),
+            (None, Some(v2)) => (vec![], self.padded_files(v2)),
+            (None, None) => return Err(invalid("info has no pieces".to_string())),
+        };
+
+        // Hybrid torrents describe the same files twice, the v1 list just
+        // has padding in between
+        if let (Some(_), Some(v2)) = (&self.info.pieces, &v2) {
+            let v1_files: Vec<&FileInfo> = files.iter().filter(|f| !f.attr.padding).collect();
+            if v1_files.len() != v2.files.len()
+                || !v1_files
+                    .iter()
+                    .zip(&v2.files)
+                    .all(|(a, b)| a.path == b.path && a.length == b.length)
+            {
+                return Err(invalid("v1 and v2 file lists differ".to_string()));
+            }
         }
 
+        let info_hash = match (&self.info.pieces, &v2) {
+            (None, Some(v2)) => v2.info_hash[..20].to_vec(),
+            _ => Sha1::digest(info).to_vec(),
+        };
+
         Ok(TorrentFile {
-            info_hash: self.info.hash()?,
+            info_hash,
             name: self.info.name,
             announce: self.announce,
-            length: self.info.length,
+            length: files.iter().map(|f| f.length).sum(),
             piece_length: self.info.piece_length,
             piece_hashes,
+            files,
+            v2,
+            url_list: parse_url_list(self.url_list.as_ref()),
+            http_seeds: parse_url_list(self.httpseeds.as_ref()),
+            private: self.info.private == Some(1),
+        })
+    }
+
+    fn v1_piece_hashes(&self, pieces: &ByteBuf) -> Result<Vec<[u8; 20]>, serde_bencode::Error> {
+        // Check valid number of pieces
+        if !pieces.len().is_multiple_of(20) {
+            return Err(invalid("pieces is not a multiple of 20 bytes".to_string()));
+        }
+
+        // Convert into hashes
+        Ok(pieces.chunks(20).map(|h| h.try_into().unwrap()).collect())
+    }
+
+    fn v1_files(&self) -> Result<Vec<FileInfo>, serde_bencode::Error> {
+        match (&self.info.length, &self.info.files) {
+            (Some(length), None) => Ok(vec![FileInfo {
+                path: vec![self.info.name.clone()],
+                length: *length,
+                attr: FileAttr::parse(self.info.attr.as_deref().unwrap_or("")),
+                symlink_path: None,
+            }]),
+            (None, Some(files)) => Ok(files
+                .iter()
+                .map(|f| FileInfo {
+                    path: std::iter::once(self.info.name.clone())
+                        .chain(f.path.iter().cloned())
+                        .collect(),
+                    length: f.length,
+                    attr: FileAttr::parse(f.attr.as_deref().unwrap_or("")),
+                    symlink_path: f.symlink_path.clone(),
+                })
+                .collect()),
+            _ => Err(invalid(
+                "info must have exactly one of length and files".to_string(),
+            )),
+        }
+    }
+
+    // v2 pieces never span files. Padding like hybrid torrents have lets
+    // pieces run through the files back to back all the same.
+    fn padded_files(&self, v2: &V2Info) -> Vec<FileInfo> {
+        let piece_length = self.info.piece_length;
+        let mut files = vec![];
+        for (i, file) in v2.files.iter().enumerate() {
+            files.push(FileInfo {
+                path: file.path.clone(),
+                length: file.length,
+                ..FileInfo::default()
+            });
+            let pad = (piece_length - file.length % piece_length) % piece_length;
+            if pad > 0 && i + 1 < v2.files.len() {
+                files.push(FileInfo {
+                    path: vec![self.info.name.clone(), ".pad".to_string(), pad.to_string()],
+                    length: pad,
+                    attr: FileAttr {
+                        padding: true,
+                        ..FileAttr::default()
+                    },
+                    symlink_path: None,
+                });
+            }
+        }
+        files
+    }
+
+    fn to_v2_info(&self, info: &[u8]) -> Result<V2Info, serde_bencode::Error> {
+        let piece_length = self.info.piece_length;
+        if piece_length < BLOCK_SIZE as u64 || !piece_length.is_power_of_two() {
+            return Err(invalid(format!("bad v2 piece length {}", piece_length)));
+        }
+
+        let tree = self
+            .info
+            .file_tree
+            .as_ref()
+            .ok_or_else(|| invalid("v2 info has no file tree".to_string()))?;
+        let mut files = vec![];
+        walk_file_tree(tree, &mut vec![], &mut files)?;
+        // A lone file at the top of the tree makes a single file torrent,
+        // anything else goes in a directory named after the torrent
+        if !(files.len() == 1 && files[0].path.len() == 1) {
+            for file in &mut files {
+                file.path.insert(0, self.info.name.clone());
+            }
+        }
+
+        // Files over a piece need a layer that adds up to their root
+        let piece_layers = parse_piece_layers(self.piece_layers.as_ref())?;
+        for file in files.iter().filter(|f| f.length > piece_length) {
+            let root = file.pieces_root.unwrap();
+            let layer = piece_layers
+                .get(&root)
+                .ok_or_else(|| invalid(format!("no piece layer for {}", file.path.join("/"))))?;
+            if layer.len() as u64 != file.length.div_ceil(piece_length)
+                || merkle::root_from_layer(layer, piece_length as usize) != root
+            {
+                return Err(invalid(format!(
+                    "bad piece layer for {}",
+                    file.path.join("/")
+                )));
+            }
+        }
+
+        let mut info_hash = [0; 32];
+        info_hash.copy_from_slice(&Sha256::digest(info));
+        Ok(V2Info {
+            info_hash,
+            files,
+            piece_layers,
+            pending_layers: HashMap::new(),
         })
     }
 }
@@ -68,18 +457,213 @@ impl BencodeTorrent {
 impl TorrentFile {
     pub fn open(path: &Path) -> Result<TorrentFile, Box<dyn Error>> {
         let file = fs::read(path)?;
-        let bencode_torrent = serde_bencode::from_bytes::<BencodeTorrent>(&file)?;
-        let torrent = bencode_torrent.to_torrent_file()?;
+        TorrentFile::from_bytes(&file)
+    }
+
+    /// Parse a bencoded torrent
+    pub fn from_bytes(bytes: &[u8]) -> Result<TorrentFile, Box<dyn Error>> {
+        let bencode_torrent = serde_bencode::from_bytes::<BencodeTorrent>(bytes)?;
+        let info = raw_info(bytes).ok_or("ERR: Torrent has no info dict")?;
+        let torrent = bencode_torrent.into_torrent_file(info)?;
 
         Ok(torrent)
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        match (&self.v2, self.piece_hashes.is_empty()) {
+            (Some(v2), true) => v2
+                .files
+                .iter()
+                .map(|f| f.length.div_ceil(self.piece_length) as usize)
+                .sum(),
+            _ => self.piece_hashes.len(),
+        }
+    }
+
+    /// Whether the torrent has no v1 piece hashes, only merkle trees
+    pub fn is_v2_only(&self) -> bool {
+        self.v2.is_some() && self.piece_hashes.is_empty()
+    }
+
+    /// Whether peers from `source` may be used, and the info hash given out
+    /// there. Private torrents stick to their trackers.
+    pub fn allows_peer_source(&self, source: PeerSource) -> bool {
+        !self.private || source == PeerSource::Tracker
+    }
+
+    // The last piece is usually shorter than piece_length
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let begin = index as u64 * self.piece_length;
+        let end = (begin + self.piece_length).min(self.length);
+
+        end.saturating_sub(begin)
+    }
+
+    pub fn piece_offset(&self, index: usize) -> u64 {
+        index as u64 * self.piece_length
+    }
+
+    /// Check piece `piece` of v2 file `file` against its merkle hashes.
+    /// False while the file's piece layer hasn't arrived.
+    pub fn verify_piece_v2(&self, file: usize, piece: usize, data: &[u8]) -> bool {
+        let v2 = match &self.v2 {
+            Some(v2) => v2,
+            None => return false,
+        };
+        let (length, root) = match v2.files.get(file) {
+            Some(V2File {
+                length,
+                pieces_root: Some(root),
+                ..
+            }) => (*length, *root),
+            _ => return false,
+        };
+
+        let piece_length = self.piece_length;
+        let offset = piece as u64 * piece_length;
+        if offset >= length || data.len() as u64 != piece_length.min(length - offset) {
+            return false;
+        }
+        // Files of up to a piece have no layer, the root covers them
+        if length <= piece_length {
+            return merkle::file_root(data) == root;
+        }
+        match v2.piece_layers.get(&root) {
+            Some(layer) => merkle::piece_layer(data, piece_length as usize)[0] == layer[piece],
+            None => false,
+        }
+    }
+
+    /// Where piece `index` lies in the v2 files: the file, its piece there
+    /// and how many bytes of the piece are file data rather than padding.
+    /// None for v1 only torrents.
+    pub fn v2_piece(&self, index: usize) -> Option<(usize, usize, u64)> {
+        self.v2.as_ref()?;
+        // Padding aligns every file to a piece, so a piece never starts in
+        // padding and holds data of one file only
+        let offset = self.piece_offset(index);
+        let mut end = 0;
+        let mut v2_index = 0;
+        for file in &self.files {
+            let start = end;
+            end += file.length;
+            if file.attr.padding {
+                continue;
+            }
+            if (start..end).contains(&offset) {
+                let within = offset - start;
+                let length = self.piece_length.min(file.length - within);
+                return Some((v2_index, (within / self.piece_length) as usize, length));
+            }
+            v2_index += 1;
+        }
+        None
+    }
+
+    // The layer of the merkle trees with one hash per piece
+    fn piece_layer_level(&self) -> u32 {
+        (self.piece_length / BLOCK_SIZE as u64).trailing_zeros()
+    }
+
+    /// Answer a peer's hash request from our piece layers, with the uncle
+    /// hashes it asked for. Other layers would need the data, so those get
+    /// None like anything we don't have.
+    pub fn hashes_for(&self, req: &HashRequest) -> Option<Vec<Hash>> {
+        let v2 = self.v2.as_ref()?;
+        if !req.is_valid() || req.base_layer != self.piece_layer_level() {
+            return None;
+        }
+        let layer = v2.piece_layers.get(&req.pieces_root)?;
+        let pad = merkle::pad_hash((self.piece_length / BLOCK_SIZE as u64) as usize);
+        let tree = merkle::tree(layer, layer.len().next_power_of_two(), pad);
+        merkle::proof(
+            &tree,
+            req.index as usize,
+            req.length as usize,
+            req.proof_layers as usize,
+        )
+    }
+
+    /// Take piece layer hashes a peer sent, for torrents that started out
+    /// without them. Returns false if they don't add up to the file's root.
+    pub fn add_hashes(&mut self, req: &HashRequest, hashes: &[Hash]) -> bool {
+        let level = self.piece_layer_level();
+        let piece_length = self.piece_length;
+        let v2 = match self.v2.as_mut() {
+            Some(v2) => v2,
+            None => return false,
+        };
+        let length = match v2
+            .files
+            .iter()
+            .find(|f| f.pieces_root == Some(req.pieces_root))
+        {
+            Some(file) => file.length,
+            None => return false,
+        };
+        if !req.is_valid()
+            || req.base_layer != level
+            || length <= piece_length
+            || hashes.len() < req.length as usize
+        {
+            return false;
+        }
+
+        let (layer_hashes, uncles) = hashes.split_at(req.length as usize);
+        let index = req.index as usize;
+        if !merkle::verify_proof(&req.pieces_root, layer_hashes, index, uncles) {
+            return false;
+        }
+        if v2.piece_layers.contains_key(&req.pieces_root) {
+            return true;
+        }
+
+        let num_pieces = length.div_ceil(piece_length) as usize;
+        let pending = v2
+            .pending_layers
+            .entry(req.pieces_root)
+            .or_insert_with(|| vec![None; num_pieces]);
+        // Anything past the end of the file is padding
+        for (slot, hash) in pending.iter_mut().skip(index).zip(layer_hashes) {
+            *slot = Some(*hash);
+        }
+        if pending.iter().all(Option::is_some) {
+            let layer = v2.pending_layers.remove(&req.pieces_root).unwrap();
+            v2.piece_layers
+                .insert(req.pieces_root, layer.into_iter().flatten().collect());
+        }
+        true
+    }
+
+    // Whether [begin, begin + length) lies inside piece `index`
+    pub fn is_valid_block(&self, index: u32, begin: u32, length: u32) -> bool {
+        let index = index as usize;
+        if index >= self.num_pieces() || length == 0 {
+            return false;
+        }
+
+        begin as u64 + length as u64 <= self.piece_size(index)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{FileStorage, Storage};
+    use crate::verify::verify;
+    use serde_bencode::value::Value as BencodeValue;
     use serde_json::Value;
+    use std::env;
     use std::fs::File;
+    use std::process;
 
     #[test]
     pub fn test_it() {
@@ -94,6 +678,9 @@ mod tests {
         assert_eq!(json["Length"], torrent.length);
         assert_eq!(json["Name"], torrent.name);
         assert_eq!(*json["InfoHash"].as_array().unwrap(), torrent.info_hash);
+        assert!(torrent
+            .url_list
+            .contains(&"http://mirror.rackspace.com/archlinux/iso/2019.12.01/".to_string()));
 
         for (i, hash) in torrent.piece_hashes.iter().enumerate() {
             for (j, byte) in hash.iter().enumerate() {
@@ -101,4 +688,357 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    pub fn test_http_seeds() {
+        let path = Path::new("data/debian-10.4.0-i386-netinst.iso.torrent");
+        let torrent = TorrentFile::open(path).unwrap();
+        assert_eq!(torrent.http_seeds.len(), 2);
+        assert!(torrent
+            .http_seeds
+            .iter()
+            .all(|url| url.starts_with("https://cdimage.debian.org/")));
+        assert!(torrent.url_list.is_empty());
+    }
+
+    #[test]
+    pub fn test_piece_geometry() {
+        let ben_path = Path::new("data/archlinux-2019.12.01-x86_64.iso.torrent");
+        let torrent = TorrentFile::open(ben_path).unwrap();
+        let last = torrent.num_pieces() - 1;
+        let total: u64 = (0..torrent.num_pieces())
+            .map(|i| torrent.piece_size(i))
+            .sum();
+
+        assert_eq!(total, torrent.length);
+        assert_eq!(torrent.piece_size(0), torrent.piece_length);
+        assert!(torrent.piece_size(last) <= torrent.piece_length);
+
+        assert!(torrent.is_valid_block(0, 0, 16384));
+        assert!(!torrent.is_valid_block(0, 0, 0));
+        assert!(!torrent.is_valid_block(last as u32 + 1, 0, 1));
+        let last_size = torrent.piece_size(last) as u32;
+        assert!(torrent.is_valid_block(last as u32, last_size - 1, 1));
+        assert!(!torrent.is_valid_block(last as u32, last_size - 1, 2));
+    }
+
+    fn dict(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+        BencodeValue::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect(),
+        )
+    }
+
+    // Bencoded v2 torrent with a three piece file and a small one, plus
+    // whatever v1 keys are passed in
+    fn v2_torrent(
+        data: &[u8],
+        small: &[u8],
+        piece_length: usize,
+        v1: Vec<(&str, BencodeValue)>,
+    ) -> Vec<u8> {
+        fn file(data: &[u8]) -> BencodeValue {
+            let leaf = dict(vec![
+                ("length", BencodeValue::Int(data.len() as i64)),
+                (
+                    "pieces root",
+                    BencodeValue::Bytes(merkle::file_root(data).to_vec()),
+                ),
+            ]);
+            dict(vec![("", leaf)])
+        }
+
+        let layer = merkle::piece_layer(data, piece_length);
+        let tree = dict(vec![
+            ("big", file(data)),
+            ("docs", dict(vec![("small", file(small))])),
+        ]);
+        let mut info = vec![
+            ("name", BencodeValue::Bytes(b"v2".to_vec())),
+            ("meta version", BencodeValue::Int(2)),
+            ("piece length", BencodeValue::Int(piece_length as i64)),
+            ("file tree", tree),
+        ];
+        info.extend(v1);
+        let info = dict(info);
+        let layers = BencodeValue::Dict(
+            vec![(
+                merkle::file_root(data).to_vec(),
+                BencodeValue::Bytes(layer.concat()),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let torrent = dict(vec![
+            (
+                "announce",
+                BencodeValue::Bytes(b"http://t.example/a".to_vec()),
+            ),
+            ("info", info),
+            ("piece layers", layers),
+        ]);
+        serde_bencode::to_bytes(&torrent).unwrap()
+    }
+
+    #[test]
+    pub fn test_v2() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE + 7).map(|i| (i % 251) as u8).collect();
+        let small = b"tiny file";
+        let bytes = v2_torrent(&data, small, piece_length, vec![]);
+
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+        let v2 = torrent.v2.as_ref().unwrap();
+        let paths: Vec<String> = v2.files.iter().map(|f| f.path.join("/")).collect();
+        assert_eq!(paths, ["v2/big", "v2/docs/small"]);
+        assert!(torrent.piece_hashes.is_empty());
+        assert!(torrent.is_v2_only());
+
+        // Padding lines the small file up with the fourth piece
+        assert_eq!(torrent.num_pieces(), 4);
+        assert_eq!(torrent.files.len(), 3);
+        assert!(torrent.files[1].attr.padding);
+        assert_eq!(torrent.length, 3 * piece_length as u64 + small.len() as u64);
+        let tail = (data.len() - 2 * piece_length) as u64;
+        assert_eq!(torrent.v2_piece(2), Some((0, 2, tail)));
+        assert_eq!(torrent.v2_piece(3), Some((1, 0, small.len() as u64)));
+
+        // Data on disk is checked against the merkle trees
+        let dir = env::temp_dir().join(format!("bittorrent-v2-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let have = verify(&torrent, &dir, |_| {});
+        assert!((0..4).all(|i| !have.has_piece(i)));
+        fs::create_dir_all(dir.join("v2/docs")).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[piece_length] ^= 1;
+        fs::write(dir.join("v2/big"), &corrupt).unwrap();
+        fs::write(dir.join("v2/docs/small"), small).unwrap();
+        let have = verify(&torrent, &dir, |_| {});
+        let pieces: Vec<bool> = (0..4).map(|i| have.has_piece(i)).collect();
+        assert_eq!(pieces, [true, false, true, true]);
+        assert!(!dir.join("v2/.pad").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The info hash is the SHA-256 of the info dict as written, which
+        // comes right before the piece layers
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let end = bytes
+            .windows(15)
+            .position(|w| w == b"12:piece layers")
+            .unwrap();
+        let info = &bytes[start..end];
+        assert_eq!(v2.info_hash[..], Sha256::digest(info)[..]);
+        assert_eq!(torrent.info_hash, &v2.info_hash[..20]);
+
+        for piece in 0..3 {
+            let begin = piece * piece_length;
+            let end = (begin + piece_length).min(data.len());
+            assert!(torrent.verify_piece_v2(0, piece, &data[begin..end]));
+        }
+        let mut bad = data[..piece_length].to_vec();
+        bad[100] ^= 1;
+        assert!(!torrent.verify_piece_v2(0, 0, &bad));
+        assert!(torrent.verify_piece_v2(1, 0, small));
+        assert!(!torrent.verify_piece_v2(1, 0, b"tiny filf"));
+
+        // Serve the piece layer with proofs, and take it back from a peer
+        // as if we had started without it
+        let root = torrent.v2.as_ref().unwrap().files[0].pieces_root.unwrap();
+        let req = HashRequest {
+            pieces_root: root,
+            base_layer: 1,
+            index: 0,
+            length: 4,
+            proof_layers: 8,
+        };
+        let hashes = torrent.hashes_for(&req).unwrap();
+        assert_eq!(hashes.len(), 4);
+        assert!(torrent
+            .hashes_for(&HashRequest {
+                base_layer: 0,
+                ..req
+            })
+            .is_none());
+
+        let mut torrent = torrent;
+        let layer = torrent
+            .v2
+            .as_mut()
+            .unwrap()
+            .piece_layers
+            .remove(&root)
+            .unwrap();
+        assert!(!torrent.verify_piece_v2(0, 1, &data[piece_length..2 * piece_length]));
+        let mut bad = hashes.clone();
+        bad[0][0] ^= 1;
+        assert!(!torrent.add_hashes(&req, &bad));
+        assert!(torrent.add_hashes(&req, &hashes));
+        assert_eq!(torrent.v2.as_ref().unwrap().piece_layers[&root], layer);
+        assert!(torrent.verify_piece_v2(0, 1, &data[piece_length..2 * piece_length]));
+
+        // A piece layer that doesn't match the root is rejected
+        let mut other = data.clone();
+        other[0] ^= 1;
+        let mut bytes = v2_torrent(&data, small, piece_length, vec![]);
+        let good = merkle::piece_layer(&data, piece_length).concat();
+        let bad_layer = merkle::piece_layer(&other, piece_length).concat();
+        let at = bytes
+            .windows(good.len())
+            .position(|w| w == &good[..])
+            .unwrap();
+        bytes[at..at + good.len()].copy_from_slice(&bad_layer);
+        assert!(TorrentFile::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    pub fn test_hybrid() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data = vec![3; 5 * BLOCK_SIZE + 7];
+        let small = b"tiny file";
+
+        // The v1 side pads the big file out to a piece boundary
+        let pad = 3 * piece_length - data.len();
+        let mut v1_data = data.clone();
+        v1_data.extend(vec![0; pad]);
+        v1_data.extend(small);
+        let pieces: Vec<u8> = v1_data
+            .chunks(piece_length)
+            .flat_map(|p| Sha1::digest(p).to_vec())
+            .collect();
+        let v1_file = |path: &[&str], length: usize, attr: &str| {
+            let path = path
+                .iter()
+                .map(|c| BencodeValue::Bytes(c.as_bytes().to_vec()))
+                .collect();
+            let mut entries = vec![
+                ("length", BencodeValue::Int(length as i64)),
+                ("path", BencodeValue::List(path)),
+            ];
+            if !attr.is_empty() {
+                entries.push(("attr", BencodeValue::Bytes(attr.as_bytes().to_vec())));
+            }
+            dict(entries)
+        };
+        let v1 = |small_length: usize| {
+            vec![
+                ("pieces", BencodeValue::Bytes(pieces.clone())),
+                (
+                    "files",
+                    BencodeValue::List(vec![
+                        v1_file(&["big"], data.len(), "x"),
+                        v1_file(&[".pad", &pad.to_string()], pad, "p"),
+                        v1_file(&["docs", "small"], small_length, ""),
+                    ]),
+                ),
+            ]
+        };
+
+        let bytes = v2_torrent(&data, small, piece_length, v1(small.len()));
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.num_pieces(), 4);
+        assert!(torrent.files[0].attr.executable);
+        assert!(torrent.files[1].attr.padding);
+        assert_eq!(torrent.files[2].attr, FileAttr::default());
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let end = bytes
+            .windows(15)
+            .position(|w| w == b"12:piece layers")
+            .unwrap();
+        assert_eq!(torrent.info_hash, Sha1::digest(&bytes[start..end]).to_vec());
+        assert!(torrent.v2.is_some());
+
+        // v1 pieces map onto the v2 files, short of the padding
+        let tail = (data.len() - 2 * piece_length) as u64;
+        assert_eq!(torrent.v2_piece(0), Some((0, 0, piece_length as u64)));
+        assert_eq!(torrent.v2_piece(2), Some((0, 2, tail)));
+        assert_eq!(torrent.v2_piece(3), Some((1, 0, small.len() as u64)));
+        let piece = &v1_data[2 * piece_length..2 * piece_length + tail as usize];
+        assert!(torrent.verify_piece_v2(0, 2, piece));
+
+        // Storage fills in the padding, so v1 pieces check out without it
+        // ever being written
+        let dir = env::temp_dir().join(format!("bittorrent-hybrid-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut storage = FileStorage::new(&torrent, &dir);
+        for (index, piece) in v1_data.chunks(piece_length).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        for index in 0..torrent.num_pieces() {
+            assert_eq!(
+                storage.hash_piece(index as u32).unwrap(),
+                torrent.piece_hashes[index]
+            );
+        }
+        assert!(!dir.join("v2/.pad").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Both sides have to describe the same files
+        let bytes = v2_torrent(&data, small, piece_length, v1(small.len() + 1));
+        assert!(TorrentFile::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    pub fn test_info_hash_as_written() {
+        // BEP 47 file hashes and mtimes aren't parsed, they still count
+        let file = dict(vec![
+            ("length", BencodeValue::Int(5)),
+            ("mtime", BencodeValue::Int(1_590_000_000)),
+            (
+                "path",
+                BencodeValue::List(vec![BencodeValue::Bytes(b"a".to_vec())]),
+            ),
+            ("sha1", BencodeValue::Bytes(vec![7; 20])),
+        ]);
+        let info = dict(vec![
+            ("files", BencodeValue::List(vec![file])),
+            ("name", BencodeValue::Bytes(b"dir".to_vec())),
+            ("piece length", BencodeValue::Int(16384)),
+            ("pieces", BencodeValue::Bytes(vec![1; 20])),
+        ]);
+        let torrent = dict(vec![
+            (
+                "announce",
+                BencodeValue::Bytes(b"http://t.example/a".to_vec()),
+            ),
+            ("info", info.clone()),
+            (
+                "url-list",
+                BencodeValue::Bytes(b"http://w.example/".to_vec()),
+            ),
+        ]);
+        let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+        let info = serde_bencode::to_bytes(&info).unwrap();
+        assert_eq!(raw_info(&bytes), Some(&info[..]));
+
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info_hash, Sha1::digest(&info).to_vec());
+        assert_eq!(torrent.url_list, vec!["http://w.example/".to_string()]);
+        assert!(raw_info(b"d4:infoi1").is_none());
+    }
+
+    #[test]
+    pub fn test_multi_file() {
+        let ben_path = Path::new("data/bitcoin-0.20.0.torrent");
+        let torrent = TorrentFile::open(ben_path).unwrap();
+
+        assert_eq!(torrent.files.len(), 10);
+        assert_eq!(
+            torrent.files[0].path,
+            vec![
+                "bitcoin-core-0.20.0",
+                "bitcoin-0.20.0-aarch64-linux-gnu.tar.gz"
+            ]
+        );
+        assert_eq!(torrent.files[0].length, 28389954);
+        assert_eq!(
+            torrent.length,
+            torrent.files.iter().map(|f| f.length).sum::<u64>()
+        );
+        assert_eq!(
+            torrent.num_pieces() as u64,
+            torrent.length.div_ceil(torrent.piece_length)
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::peer;
 use crate::torrent::TorrentFile;
 use byteorder::{BigEndian, ByteOrder};
 use core::fmt;
@@ -11,6 +12,18 @@ use std::net::Ipv4Addr;
 use std::time::Duration;
 
 struct PeerVecVisitor;
+
+/// Where a peer address was learned from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    // Peer exchange
+    Pex,
+    // Local service discovery
+    Lsd,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Peer {
     pub ip: Ipv4Addr,
@@ -18,13 +31,16 @@ pub struct Peer {
 }
 #[derive(Debug, Deserialize)]
 pub struct TrackerResponse {
-    interval: u32,
+    pub interval: u32,
     #[serde(deserialize_with = "Peer::vec_from_bytes")]
     pub peers: Vec<Peer>,
+    // Seeds and leechers in the swarm, if the tracker says
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
 }
 
 impl Peer {
-    fn from_bytes(b: &[u8]) -> Peer {
+    pub fn from_bytes(b: &[u8]) -> Peer {
         let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
         //        let port = (b[4] as u16) * 256 + (b[5] as u16);
         let port = BigEndian::read_u16(&[b[4], b[5]]);
@@ -32,6 +48,13 @@ impl Peer {
         Peer { ip, port }
     }
 
+    pub fn to_bytes(&self) -> [u8; 6] {
+        let mut b = [0; 6];
+        b[..4].copy_from_slice(&self.ip.octets());
+        BigEndian::write_u16(&mut b[4..], self.port);
+        b
+    }
+
     fn vec_from_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Peer>, D::Error> {
         d.deserialize_byte_buf(PeerVecVisitor)
     }
@@ -49,17 +72,17 @@ impl<'de> Visitor<'de> for PeerVecVisitor {
     }
 }
 
-pub fn request_peers(
+fn announce_url(
     torrent: &TorrentFile,
-    peer_id: &Vec<u8>,
-    port: &u16,
-) -> Result<TrackerResponse, Box<dyn Error>> {
+    peer_id: &[u8],
+    port: u16,
+) -> Result<Url, Box<dyn Error + Send + Sync>> {
     let url_hash = (&torrent.info_hash)
         .into_iter()
         .map(|b| percent_encode_byte(*b))
         .collect::<String>();
     let peer_id_es = peer_id
-        .into_iter()
+        .iter()
         .map(|b| percent_encode_byte(*b))
         .collect::<String>();
     let base_url = format!(
@@ -76,26 +99,48 @@ pub fn request_peers(
             ("left", torrent.length.to_string()),
         ],
     )?;
-    let client = reqwest::blocking::Client::builder()
+    Ok(url)
+}
+
+/// Ask the tracker for peers without blocking the runtime
+pub async fn announce(
+    torrent: &TorrentFile,
+    peer_id: &[u8],
+    port: u16,
+) -> Result<TrackerResponse, Box<dyn Error + Send + Sync>> {
+    let url = announce_url(torrent, peer_id, port)?;
+    let client = reqwest::Client::builder()
         .timeout(Duration::from_secs(15))
         .build()?;
-    let mut res = client.get(url).send()?;
-    let mut buf = Vec::new();
+    let res = client.get(url).send().await?;
 
     // FIXME: this should check the status code
 
-    res.copy_to(&mut buf)?;
+    let buf = res.bytes().await?;
 
-    let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&buf.as_slice())?;
+    let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&buf)?;
 
     Ok(tracker_response)
 }
 
+/// Blocking announce, for callers without a runtime of their own
+pub fn request_peers(
+    torrent: &TorrentFile,
+    peer_id: &[u8],
+    port: &u16,
+) -> Result<TrackerResponse, Box<dyn Error>> {
+    peer::block_on(announce(torrent, peer_id, *port)).map_err(|e| e as Box<dyn Error>)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use rand::Rng;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
     use std::path::Path;
+    use std::thread;
+    use tokio::runtime::Runtime;
 
     #[test]
     pub fn test_request_peers() {
@@ -109,4 +154,43 @@ mod tests {
         // Check that we got some peers
         assert!(peers_response.peers.len() > 0);
     }
+
+    #[test]
+    pub fn test_announce_local() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut torrent =
+            TorrentFile::open(Path::new("data/ubuntu-18.04.4-desktop-amd64.iso.torrent")).unwrap();
+        torrent.announce = format!("http://{}/announce", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+
+                let mut body = b"d8:completei5e10:incompletei3e8:intervali900e5:peers6:".to_vec();
+                body.extend(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                body.push(b'e');
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        // Blocking facade
+        let response = request_peers(&torrent, &[1; 20], &6881).unwrap();
+        assert_eq!(response.interval, 900);
+        assert_eq!((response.complete, response.incomplete), (Some(5), Some(3)));
+        assert_eq!(response.peers[0].port, 6881);
+
+        // Straight from a runtime
+        let response = Runtime::new()
+            .unwrap()
+            .block_on(announce(&torrent, &[1; 20], 6881))
+            .unwrap();
+        assert_eq!(response.peers[0].ip, Ipv4Addr::new(127, 0, 0, 1));
+    }
 }
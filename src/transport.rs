@@ -0,0 +1,77 @@
+// What a peer connection runs over: TCP, through a PeerStream task (see
+// peer.rs), or uTP (see utp.rs).
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+pub trait Transport: Read + Write + Send + fmt::Debug {
+    /// Wait up to `timeout` for something to read. A closed stream counts
+    /// so the next read reports it. A zero timeout only checks.
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool>;
+
+    /// Read without consuming, blocking like a read does
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let result = if timeout == Duration::from_secs(0) {
+            self.set_nonblocking(true)?;
+            let result = TcpStream::peek(self, &mut [0; 1]);
+            self.set_nonblocking(false)?;
+            result
+        } else {
+            let old = self.read_timeout()?;
+            TcpStream::set_read_timeout(self, Some(timeout))?;
+            let result = TcpStream::peek(self, &mut [0; 1]);
+            TcpStream::set_read_timeout(self, old)?;
+            result
+        };
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        (**self).wait_readable(timeout)
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).peek(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
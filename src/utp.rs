@@ -0,0 +1,1232 @@
+// uTP (BEP 29): reliable, ordered streams over UDP.
+//
+// One UDP socket carries any number of connections, told apart by peer
+// address and connection id. A background thread reads packets and runs
+// the retransmit timers, streams block on a condvar until there's data to
+// read or window to write into.
+//
+// Congestion control is LEDBAT: the window grows while the one way delay
+// stays within 100 ms of the lowest seen lately and shrinks once it goes
+// over, so uTP backs off before other traffic suffers. Losses are found
+// from duplicate or selective acks, or a timeout, and cut the window.
+//
+// The path MTU is found by binary search with probes, data packets of the
+// size to try. A probe goes out alone with an empty data packet behind it,
+// so once that one is acked and the probe isn't, the probe is known to be
+// lost and its data can be resent in smaller packets. Congestion drops
+// probes too, so a size only counts as too big after a few losses.
+
+use crate::transport::Transport;
+use rand::{self, Rng};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+const EXT_SACK: u8 = 1;
+
+// UDP payload sizes that always get through, and that fill an ethernet
+// frame
+const MTU_FLOOR: usize = 548;
+const MTU_CEILING: usize = 1472;
+// Probing stops once floor and ceiling are this close
+const MTU_SEARCH_DONE: usize = 16;
+// Losses of a probe size before it's taken as over the MTU
+const MTU_PROBE_TRIES: u32 = 3;
+
+// LEDBAT, delays in microseconds and windows in bytes
+const TARGET_DELAY: f64 = 100_000.0;
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+const INITIAL_WINDOW: f64 = 3000.0;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+// The lowest delay over this long is taken as the delay without queueing
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(120);
+
+const RECV_WINDOW: usize = 1 << 20;
+// Packets further ahead than this are dropped rather than buffered
+const MAX_REORDER: u16 = 1024;
+const MAX_SACK_BYTES: usize = 32;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+// A connection fails once a packet went out this many times unanswered
+const MAX_TRANSMISSIONS: u32 = 8;
+// Duplicate acks, or packets acked past a gap, before resending
+const DUP_ACK_THRESHOLD: usize = 3;
+// How long the background thread waits for packets before running timers
+const TICK: Duration = Duration::from_millis(5);
+// Incoming connections waiting to be accepted, SYNs past this are dropped
+// so spoofed ones can't pile up
+const MAX_BACKLOG: usize = 64;
+// How long an incoming connection waits to be accepted before it's dropped
+const BACKLOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The clock in packet timestamps. Wall clock rather than Instant so that
+// sockets in one process agree on it.
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u32)
+        .unwrap_or(0)
+}
+
+// Sequence numbers wrap, compare them by distance
+fn seq_lt(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+fn seq_le(a: u16, b: u16) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    ty: u8,
+    conn_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    sack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(ty: u8, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            ty,
+            conn_id: 0,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr: 0,
+            sack: None,
+            payload,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut exts: Vec<(u8, Vec<u8>)> = vec![];
+        if let Some(sack) = &self.sack {
+            exts.push((EXT_SACK, sack.clone()));
+        }
+
+        let mut b = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        b.push(self.ty << 4 | VERSION);
+        b.push(exts.first().map_or(0, |e| e.0));
+        b.extend(&self.conn_id.to_be_bytes());
+        b.extend(&self.timestamp.to_be_bytes());
+        b.extend(&self.timestamp_diff.to_be_bytes());
+        b.extend(&self.wnd_size.to_be_bytes());
+        b.extend(&self.seq_nr.to_be_bytes());
+        b.extend(&self.ack_nr.to_be_bytes());
+        for (i, (_, data)) in exts.iter().enumerate() {
+            b.push(exts.get(i + 1).map_or(0, |e| e.0));
+            b.push(data.len() as u8);
+            b.extend(data);
+        }
+        b.extend(&self.payload);
+        b
+    }
+
+    fn decode(b: &[u8]) -> Option<Packet> {
+        if b.len() < HEADER_LEN || b[0] & 0xf != VERSION || b[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        let mut packet = Packet {
+            ty: b[0] >> 4,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack: None,
+            payload: vec![],
+        };
+
+        let mut ext = b[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            let next = *b.get(pos)?;
+            let len = *b.get(pos + 1)? as usize;
+            let data = b.get(pos + 2..pos + 2 + len)?;
+            // Extensions we don't know are skipped
+            if ext == EXT_SACK {
+                packet.sack = Some(data.to_vec());
+            }
+            ext = next;
+            pos += len + 2;
+        }
+        packet.payload = b[pos..].to_vec();
+        Some(packet)
+    }
+}
+
+/// Impairments applied to everything a socket sends, to see in process
+/// how uTP copes with the internet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConditions {
+    // Fraction of packets dropped at random
+    pub loss: f64,
+    pub delay: Duration,
+    // Larger packets are dropped, 0 for no limit
+    pub mtu: usize,
+}
+
+struct Link {
+    udp: UdpSocket,
+    conditions: LinkConditions,
+    delayed: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+}
+
+impl Link {
+    // Send errors are treated like loss, retransmits take care of them
+    fn send(&mut self, addr: SocketAddr, packet: &Packet) {
+        let bytes = packet.encode();
+        let c = self.conditions;
+        if (c.mtu > 0 && bytes.len() > c.mtu) || rand::thread_rng().gen::<f64>() < c.loss {
+            return;
+        }
+        if c.delay > Duration::from_secs(0) {
+            self.delayed
+                .push_back((Instant::now() + c.delay, addr, bytes));
+        } else {
+            let _ = self.udp.send_to(&bytes, addr);
+        }
+    }
+
+    fn flush(&mut self, now: Instant) {
+        while self.delayed.front().is_some_and(|d| d.0 <= now) {
+            let (_, addr, bytes) = self.delayed.pop_front().unwrap();
+            let _ = self.udp.send_to(&bytes, addr);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+// A packet that's out and not acked yet
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    fast_resent: bool,
+}
+
+struct Conn {
+    addr: SocketAddr,
+    send_id: u16,
+    recv_id: u16,
+    state: State,
+    // Next sequence number to send, last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+
+    outgoing: VecDeque<Sent>,
+    // Payload bytes in outgoing
+    in_flight: usize,
+    max_window: f64,
+    peer_window: usize,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    // One retransmit timer, restarted whenever acks make progress
+    timer: Instant,
+    last_ack: u16,
+    dup_acks: usize,
+    last_loss: Option<Instant>,
+    // Increasing delay samples, the first is the base delay
+    delays: VecDeque<(Instant, u32)>,
+    // Their timestamp against our clock, echoed back for their LEDBAT
+    reply_micro: u32,
+
+    mtu_floor: usize,
+    mtu_ceiling: usize,
+    // Sequence number and size of the outstanding probe
+    probe: Option<(u16, usize)>,
+    // Losses of probes of the current size
+    probe_losses: u32,
+
+    recv_buf: VecDeque<u8>,
+    reorder: HashMap<u16, Packet>,
+    advertised: usize,
+    eof: bool,
+    error: Option<io::ErrorKind>,
+    fin_sent: bool,
+    // The stream was dropped, the connection goes once the FIN is acked
+    dropped: bool,
+}
+
+impl Conn {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Conn {
+        Conn {
+            addr,
+            send_id,
+            recv_id,
+            state,
+            seq_nr: rand::thread_rng().gen(),
+            ack_nr: 0,
+            outgoing: VecDeque::new(),
+            in_flight: 0,
+            max_window: INITIAL_WINDOW,
+            peer_window: RECV_WINDOW,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+            timer: Instant::now(),
+            last_ack: 0,
+            dup_acks: 0,
+            last_loss: None,
+            delays: VecDeque::new(),
+            reply_micro: 0,
+            mtu_floor: MTU_FLOOR,
+            mtu_ceiling: MTU_CEILING,
+            probe: None,
+            probe_losses: 0,
+            recv_buf: VecDeque::new(),
+            reorder: HashMap::new(),
+            advertised: RECV_WINDOW,
+            eof: false,
+            error: None,
+            fin_sent: false,
+            dropped: false,
+        }
+    }
+
+    fn payload_size(&self) -> usize {
+        self.mtu_floor - HEADER_LEN
+    }
+
+    fn min_window(&self) -> f64 {
+        self.payload_size() as f64
+    }
+
+    // With nothing in flight one packet may always go, so a closed peer
+    // window gets probed. Nothing goes behind an MTU probe.
+    fn can_send(&self, len: usize) -> bool {
+        let window = (self.max_window as usize).min(self.peer_window);
+        self.probe.is_none() && (self.outgoing.is_empty() || self.in_flight + len <= window)
+    }
+
+    fn finished(&self) -> bool {
+        self.dropped && (self.state == State::Closed || (self.fin_sent && self.outgoing.is_empty()))
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.error = Some(kind);
+        self.state = State::Closed;
+        self.outgoing.clear();
+        self.in_flight = 0;
+    }
+
+    // Fill in the header fields that change between transmissions
+    fn stamp(&mut self, packet: &mut Packet) {
+        packet.conn_id = if packet.ty == ST_SYN {
+            self.recv_id
+        } else {
+            self.send_id
+        };
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micro;
+        self.advertised = RECV_WINDOW.saturating_sub(self.recv_buf.len());
+        packet.wnd_size = self.advertised as u32;
+        packet.ack_nr = self.ack_nr;
+    }
+
+    fn transmit(&mut self, link: &mut Link, index: usize, now: Instant) {
+        let mut packet = self.outgoing[index].packet.clone();
+        self.stamp(&mut packet);
+        link.send(self.addr, &packet);
+        let sent = &mut self.outgoing[index];
+        sent.packet = packet;
+        sent.sent_at = now;
+        sent.transmissions += 1;
+    }
+
+    // Send a packet that takes a sequence number and has to be acked
+    fn queue(&mut self, link: &mut Link, ty: u8, payload: Vec<u8>) -> u16 {
+        let seq = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight += payload.len();
+        if self.outgoing.is_empty() {
+            self.timer = Instant::now();
+        }
+        self.outgoing.push_back(Sent {
+            packet: Packet::new(ty, seq, payload),
+            sent_at: Instant::now(),
+            transmissions: 0,
+            fast_resent: false,
+        });
+        self.transmit(link, self.outgoing.len() - 1, Instant::now());
+        seq
+    }
+
+    // Ack what we have, with a selective ack for anything past a gap
+    fn send_state(&mut self, link: &mut Link) {
+        let mut packet = Packet::new(ST_STATE, self.seq_nr, vec![]);
+        if !self.reorder.is_empty() {
+            let mut mask = vec![0u8; MAX_SACK_BYTES];
+            let mut last = 0;
+            for seq in self.reorder.keys() {
+                let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+                if bit < MAX_SACK_BYTES * 8 {
+                    mask[bit / 8] |= 1 << (bit % 8);
+                    last = last.max(bit);
+                }
+            }
+            mask.truncate((last / 32 + 1) * 4);
+            packet.sack = Some(mask);
+        }
+        self.stamp(&mut packet);
+        link.send(self.addr, &packet);
+    }
+
+    // Packet size for the next binary search step towards the path MTU,
+    // if there's still anything to find out
+    fn probe_size(&self) -> Option<usize> {
+        if self.state != State::Connected
+            || self.probe.is_some()
+            || self.mtu_ceiling - self.mtu_floor <= MTU_SEARCH_DONE
+        {
+            return None;
+        }
+        Some((self.mtu_floor + self.mtu_ceiling).div_ceil(2))
+    }
+
+    // Send a probe with an empty packet behind it. `payload` fills the
+    // probe to its size.
+    fn probe(&mut self, link: &mut Link, payload: Vec<u8>) {
+        let size = payload.len() + HEADER_LEN;
+        let seq = self.queue(link, ST_DATA, payload);
+        self.queue(link, ST_DATA, vec![]);
+        self.probe = Some((seq, size));
+    }
+
+    // The packet behind the probe got through and the probe didn't. Its
+    // data goes again in packets that fit, the first one takes the probe's
+    // sequence number.
+    fn probe_lost(&mut self, link: &mut Link, now: Instant) {
+        let (_, size) = self.probe.take().unwrap();
+        self.probe_losses += 1;
+        if self.probe_losses >= MTU_PROBE_TRIES {
+            self.mtu_ceiling = size - 1;
+            self.probe_losses = 0;
+        }
+
+        let payload = std::mem::take(&mut self.outgoing[0].packet.payload);
+        let mut chunks = payload.chunks(self.payload_size());
+        let first = chunks.next().unwrap_or_default().to_vec();
+        self.in_flight -= payload.len() - first.len();
+        self.outgoing[0].packet.payload = first;
+        self.transmit(link, 0, now);
+        for chunk in chunks {
+            self.queue(link, ST_DATA, chunk.to_vec());
+        }
+    }
+
+    // Send the FIN once the stream is dropped, unless a probe is out that
+    // may still have to be split up
+    fn maybe_close(&mut self, link: &mut Link) {
+        if self.dropped && self.state == State::Connected && !self.fin_sent && self.probe.is_none()
+        {
+            self.fin_sent = true;
+            self.queue(link, ST_FIN, vec![]);
+        }
+    }
+
+    fn on_packet(&mut self, link: &mut Link, packet: Packet, now: Instant) {
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+        match packet.ty {
+            ST_RESET => return self.fail(io::ErrorKind::ConnectionReset),
+            ST_STATE if self.state == State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+        if self.state != State::Connected {
+            return;
+        }
+
+        self.on_ack(link, &packet, now);
+        if packet.ty == ST_DATA || packet.ty == ST_FIN {
+            self.on_data(packet);
+            self.send_state(link);
+        }
+    }
+
+    fn on_ack(&mut self, link: &mut Link, packet: &Packet, now: Instant) {
+        let ack = packet.ack_nr;
+        let mut acked = 0;
+        let mut progressed = false;
+        while self
+            .outgoing
+            .front()
+            .is_some_and(|s| seq_le(s.packet.seq_nr, ack))
+        {
+            let sent = self.outgoing.pop_front().unwrap();
+            acked += self.acked(sent, now);
+            progressed = true;
+        }
+
+        // Bit i of the selective ack stands for ack + 2 + i
+        let mut sacked = vec![];
+        if let Some(mask) = &packet.sack {
+            for i in 0..mask.len() * 8 {
+                if mask[i / 8] & (1 << (i % 8)) != 0 {
+                    sacked.push(ack.wrapping_add(2 + i as u16));
+                }
+            }
+            let mut i = 0;
+            while i < self.outgoing.len() {
+                if sacked.contains(&self.outgoing[i].packet.seq_nr) {
+                    let sent = self.outgoing.remove(i).unwrap();
+                    acked += self.acked(sent, now);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        if acked > 0 || progressed {
+            self.rto = self.base_rto();
+            self.timer = now;
+        }
+
+        // Pure acks repeating the last ack while packets are out, or
+        // packets acked past one, mean it was lost
+        if progressed {
+            self.dup_acks = 0;
+        } else if packet.ty == ST_STATE && ack == self.last_ack && !self.outgoing.is_empty() {
+            self.dup_acks += 1;
+        }
+        self.last_ack = ack;
+        let mut lost: Vec<usize> = self
+            .outgoing
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                sacked
+                    .iter()
+                    .filter(|&&seq| seq_lt(s.packet.seq_nr, seq))
+                    .count()
+                    >= DUP_ACK_THRESHOLD
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if self.dup_acks == DUP_ACK_THRESHOLD {
+            lost.insert(0, 0);
+        }
+        lost.dedup();
+        for i in lost {
+            if i < self.outgoing.len() && !self.outgoing[i].fast_resent {
+                self.outgoing[i].fast_resent = true;
+                self.lost(link, i, now);
+            }
+        }
+
+        if acked > 0 {
+            self.ledbat(packet.timestamp_diff, acked, now);
+        }
+    }
+
+    // Account for an acked packet, returning its payload size
+    fn acked(&mut self, sent: Sent, now: Instant) -> usize {
+        let len = sent.packet.payload.len();
+        self.in_flight -= len;
+        if sent.transmissions == 1 {
+            self.update_rtt(now.duration_since(sent.sent_at));
+        }
+        if let Some((seq, size)) = self.probe {
+            if seq == sent.packet.seq_nr {
+                self.mtu_floor = size;
+                self.probe = None;
+                self.probe_losses = 0;
+            }
+        }
+        len
+    }
+
+    // Resend a lost packet, the link is congested. A probe is only split
+    // up once the packet behind it was acked, until then it may just be
+    // late.
+    fn lost(&mut self, link: &mut Link, index: usize, now: Instant) {
+        let seq = self.outgoing[index].packet.seq_nr;
+        if self.probe.is_some_and(|(probe, _)| probe == seq) {
+            if self.outgoing.len() == 1 {
+                self.probe_lost(link, now);
+            }
+            return;
+        }
+        let rtt = self.srtt.unwrap_or(self.rto);
+        if self.last_loss.is_none_or(|at| now.duration_since(at) > rtt) {
+            self.max_window = (self.max_window / 2.0).max(self.min_window());
+            self.last_loss = Some(now);
+        }
+        self.transmit(link, index, now);
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        let ahead = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if seq_le(packet.seq_nr, self.ack_nr) || ahead > MAX_REORDER || self.eof {
+            return;
+        }
+        self.reorder.insert(packet.seq_nr, packet);
+        while let Some(next) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = next.seq_nr;
+            if next.ty == ST_FIN {
+                self.eof = true;
+                self.reorder.clear();
+                break;
+            }
+            if !self.dropped {
+                self.recv_buf.extend(&next.payload);
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.max(sample) - srtt.min(sample);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+
+    fn base_rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+
+    // Grow the window while queueing delay is under target, shrink it when
+    // over. `delay` is the peer's view of our one way delay plus a clock
+    // offset, which the base delay cancels out.
+    fn ledbat(&mut self, delay: u32, acked: usize, now: Instant) {
+        let mut queueing = 0.0;
+        if delay != 0 {
+            while self.delays.back().is_some_and(|d| d.1 >= delay) {
+                self.delays.pop_back();
+            }
+            self.delays.push_back((now, delay));
+            while self
+                .delays
+                .front()
+                .is_some_and(|d| now.duration_since(d.0) > BASE_DELAY_WINDOW)
+            {
+                self.delays.pop_front();
+            }
+            queueing = (delay - self.delays[0].1) as f64;
+        }
+        let off_target = (TARGET_DELAY - queueing) / TARGET_DELAY;
+        let window_factor = acked as f64 / self.max_window.max(acked as f64);
+        self.max_window = (self.max_window
+            + MAX_CWND_INCREASE_PER_RTT * off_target * window_factor)
+            .clamp(self.min_window(), MAX_WINDOW);
+    }
+
+    fn tick(&mut self, link: &mut Link, now: Instant) {
+        self.maybe_close(link);
+        if self.outgoing.is_empty() || now.duration_since(self.timer) < self.rto {
+            return;
+        }
+        // While neither the probe nor the packet behind it is acked, the
+        // one behind is resent to find out which got lost
+        let seq = self.outgoing[0].packet.seq_nr;
+        let mut index = 0;
+        if self.probe.is_some_and(|(probe, _)| probe == seq) {
+            if self.outgoing.len() == 1 {
+                self.timer = now;
+                return self.probe_lost(link, now);
+            }
+            index = 1;
+        }
+        if self.outgoing[index].transmissions >= MAX_TRANSMISSIONS {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.timer = now;
+        self.max_window = self.min_window();
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.transmit(link, index, now);
+    }
+
+    // Let a sender that filled our window know there's room again
+    fn maybe_update_window(&mut self, link: &mut Link) {
+        let free = RECV_WINDOW.saturating_sub(self.recv_buf.len());
+        if self.advertised < RECV_WINDOW / 2 && free >= RECV_WINDOW / 2 {
+            self.send_state(link);
+        }
+    }
+}
+
+type Key = (SocketAddr, u16);
+
+struct Shared {
+    conns: HashMap<Key, Conn>,
+    // Incoming connections not accepted yet, and when their SYN came in
+    backlog: VecDeque<(Key, Instant)>,
+    link: Link,
+}
+
+struct Inner {
+    udp: UdpSocket,
+    shared: Mutex<Shared>,
+    changed: Condvar,
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn handle(&self, bytes: &[u8], addr: SocketAddr) {
+        let packet = match Packet::decode(bytes) {
+            Some(packet) => packet,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut shared = self.lock();
+        let Shared {
+            conns,
+            backlog,
+            link,
+        } = &mut *shared;
+
+        if packet.ty == ST_SYN {
+            // Their receive id plus one is ours, a repeated SYN just gets
+            // acked again
+            let key = (addr, packet.conn_id.wrapping_add(1));
+            // Left unanswered, a real peer tries again later
+            if !conns.contains_key(&key) && backlog.len() >= MAX_BACKLOG {
+                return;
+            }
+            let conn = conns.entry(key).or_insert_with(|| {
+                backlog.push_back((key, now));
+                let mut conn = Conn::new(addr, key.1, packet.conn_id, State::Connected);
+                conn.ack_nr = packet.seq_nr;
+                conn
+            });
+            conn.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+            conn.peer_window = packet.wnd_size as usize;
+            conn.send_state(link);
+        } else if let Some(conn) = conns.get_mut(&(addr, packet.conn_id)) {
+            conn.on_packet(link, packet, now);
+        }
+        drop(shared);
+        self.changed.notify_all();
+    }
+
+    fn tick(&self, now: Instant) {
+        let mut shared = self.lock();
+        let Shared {
+            conns,
+            backlog,
+            link,
+        } = &mut *shared;
+        while let Some(&(key, at)) = backlog.front() {
+            if now.duration_since(at) < BACKLOG_TIMEOUT {
+                break;
+            }
+            backlog.pop_front();
+            conns.remove(&key);
+        }
+        link.flush(now);
+        let mut failed = false;
+        for conn in conns.values_mut() {
+            let before = conn.error;
+            conn.tick(link, now);
+            failed |= conn.error != before;
+        }
+        conns.retain(|_, conn| !conn.finished());
+        drop(shared);
+        if failed {
+            self.changed.notify_all();
+        }
+    }
+}
+
+// Read packets and run timers for as long as the socket or any of its
+// streams are around
+fn run(inner: Weak<Inner>) {
+    let mut buf = vec![0; 1 << 16];
+    while let Some(inner) = inner.upgrade() {
+        if let Ok((n, addr)) = inner.udp.recv_from(&mut buf) {
+            inner.handle(&buf[..n], addr);
+        }
+        inner.tick(Instant::now());
+    }
+}
+
+/// A UDP socket carrying uTP connections, both ways. Clones share it.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UtpSocket({:?})", self.inner.udp.local_addr())
+    }
+}
+
+impl UtpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UtpSocket> {
+        let udp = UdpSocket::bind(addr)?;
+        udp.set_read_timeout(Some(TICK))?;
+        let link = Link {
+            udp: udp.try_clone()?,
+            conditions: LinkConditions::default(),
+            delayed: VecDeque::new(),
+        };
+        let inner = Arc::new(Inner {
+            udp,
+            shared: Mutex::new(Shared {
+                conns: HashMap::new(),
+                backlog: VecDeque::new(),
+                link,
+            }),
+            changed: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || run(weak));
+        Ok(UtpSocket { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.udp.local_addr()
+    }
+
+    /// Drop, delay or size limit everything this socket sends from now on
+    pub fn simulate(&self, conditions: LinkConditions) {
+        self.inner.lock().link.conditions = conditions;
+    }
+
+    pub fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        let key = {
+            let mut shared = self.inner.lock();
+            let Shared { conns, link, .. } = &mut *shared;
+            let mut rng = rand::thread_rng();
+            let recv_id = loop {
+                let id: u16 = rng.gen();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut conn = Conn::new(addr, recv_id, recv_id.wrapping_add(1), State::SynSent);
+            conn.queue(link, ST_SYN, vec![]);
+            conns.insert((addr, recv_id), conn);
+            (addr, recv_id)
+        };
+
+        let stream = UtpStream::new(self.inner.clone(), key);
+        stream.wait(Some(timeout), |conn, _| match (conn.state, conn.error) {
+            (_, Some(kind)) => Some(Err(kind.into())),
+            (State::Connected, _) => Some(Ok(())),
+            _ => None,
+        })?;
+        Ok(stream)
+    }
+
+    /// Wait for an incoming connection
+    pub fn accept(&self) -> io::Result<UtpStream> {
+        let mut shared = self.inner.lock();
+        loop {
+            if let Some((key, _)) = shared.backlog.pop_front() {
+                return Ok(UtpStream::new(self.inner.clone(), key));
+            }
+            shared = self
+                .inner
+                .changed
+                .wait(shared)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Like accept, giving up after `timeout`
+    pub fn accept_timeout(&self, timeout: Duration) -> io::Result<Option<UtpStream>> {
+        let deadline = Instant::now() + timeout;
+        let mut shared = self.inner.lock();
+        loop {
+            if let Some((key, _)) = shared.backlog.pop_front() {
+                return Ok(Some(UtpStream::new(self.inner.clone(), key)));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            shared = self
+                .inner
+                .changed
+                .wait_timeout(shared, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+pub struct UtpStream {
+    inner: Arc<Inner>,
+    key: Key,
+    read_timeout: Cell<Option<Duration>>,
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UtpStream({})", self.key.0)
+    }
+}
+
+impl UtpStream {
+    fn new(inner: Arc<Inner>, key: Key) -> UtpStream {
+        UtpStream {
+            inner,
+            key,
+            read_timeout: Cell::new(None),
+        }
+    }
+
+    // Run `check` on the connection until it has an answer, waiting for
+    // packets in between
+    fn wait<T, F>(&self, timeout: Option<Duration>, mut check: F) -> io::Result<T>
+    where
+        F: FnMut(&mut Conn, &mut Link) -> Option<io::Result<T>>,
+    {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut shared = self.inner.lock();
+        loop {
+            let Shared { conns, link, .. } = &mut *shared;
+            let conn = conns
+                .get_mut(&self.key)
+                .ok_or(io::Error::from(io::ErrorKind::NotConnected))?;
+            if let Some(result) = check(conn, link) {
+                return result;
+            }
+            shared = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    self.inner
+                        .changed
+                        .wait_timeout(shared, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .inner
+                    .changed
+                    .wait(shared)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Largest UDP payload known to get through to the peer
+    pub fn mtu(&self) -> usize {
+        self.inner
+            .lock()
+            .conns
+            .get(&self.key)
+            .map_or(MTU_FLOOR, |conn| conn.mtu_floor)
+    }
+
+    fn read_or_peek(&self, buf: &mut [u8], consume: bool) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.wait(self.read_timeout.get(), |conn, link| {
+            if !conn.recv_buf.is_empty() {
+                let n = buf.len().min(conn.recv_buf.len());
+                for (dst, src) in buf.iter_mut().zip(&conn.recv_buf) {
+                    *dst = *src;
+                }
+                if consume {
+                    conn.recv_buf.drain(..n);
+                    conn.maybe_update_window(link);
+                }
+                Some(Ok(n))
+            } else if conn.eof {
+                Some(Ok(0))
+            } else {
+                conn.error.map(|kind| Err(kind.into()))
+            }
+        })
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_or_peek(buf, true)
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.wait(None, |conn, link| {
+            if let Some(kind) = conn.error {
+                return Some(Err(kind.into()));
+            }
+            if conn.fin_sent || conn.state != State::Connected {
+                return Some(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            // A probe waits for everything before it to be acked, and is
+            // only sent with enough data to fill it
+            if let Some(size) = conn.probe_size() {
+                let len = size - HEADER_LEN;
+                if buf.len() >= len {
+                    if !conn.outgoing.is_empty() {
+                        return None;
+                    }
+                    conn.probe(link, buf[..len].to_vec());
+                    return Some(Ok(len));
+                }
+            }
+            let len = buf.len().min(conn.payload_size());
+            if !conn.can_send(len) {
+                return None;
+            }
+            conn.queue(link, ST_DATA, buf[..len].to_vec());
+            Some(Ok(len))
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut shared = self.inner.lock();
+        let Shared { conns, link, .. } = &mut *shared;
+        if let Some(conn) = conns.get_mut(&self.key) {
+            conn.dropped = true;
+            if conn.state == State::SynSent {
+                conn.state = State::Closed;
+            }
+            conn.maybe_close(link);
+        }
+    }
+}
+
+impl Transport for UtpStream {
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let timeout = Some(timeout);
+        let result = self.wait(timeout, |conn, _| {
+            if !conn.recv_buf.is_empty() || conn.eof || conn.error.is_some() {
+                Some(Ok(()))
+            } else {
+                None
+            }
+        });
+        match result {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_or_peek(buf, false)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout.set(timeout);
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.key.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(conditions: LinkConditions) -> (UtpSocket, UtpStream, UtpStream) {
+        let a = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UtpSocket::bind("127.0.0.1:0").unwrap();
+        a.simulate(conditions);
+        b.simulate(conditions);
+        let addr = b.local_addr().unwrap();
+        let accept = thread::spawn(move || (b.accept().unwrap(), b));
+        let client = a.connect(addr, Duration::from_secs(10)).unwrap();
+        let (server, _b) = accept.join().unwrap();
+        (a, client, server)
+    }
+
+    // Send `len` bytes one way and check they all arrive, in order
+    fn transfer(client: &mut UtpStream, mut server: UtpStream, len: usize) -> UtpStream {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let expected = data.clone();
+        let reader = thread::spawn(move || {
+            let mut got = vec![0; len];
+            server.read_exact(&mut got).unwrap();
+            (got, server)
+        });
+        client.write_all(&data).unwrap();
+        let (got, server) = reader.join().unwrap();
+        assert!(got == expected, "data corrupted in transfer");
+        server
+    }
+
+    // Keep sending until the MTU search is over
+    fn search_mtu(conditions: LinkConditions) -> usize {
+        let (_a, mut client, mut server) = pair(conditions);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        loop {
+            server = transfer(&mut client, server, 100_000);
+            let conn = client.inner.lock();
+            let conn = &conn.conns[&client.key];
+            if conn.probe_size().is_none() && conn.probe.is_none() || Instant::now() > deadline {
+                return conn.mtu_floor;
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet() {
+        let mut packet = Packet::new(ST_DATA, 7, b"abc".to_vec());
+        packet.ack_nr = 65535;
+        packet.sack = Some(vec![0b101, 0, 0, 0]);
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), HEADER_LEN + 6 + 3);
+        assert_eq!(Packet::decode(&bytes), Some(packet));
+        assert_eq!(Packet::decode(&bytes[..19]), None);
+
+        // Unknown extensions are skipped
+        let mut bytes = Packet::new(ST_DATA, 7, b"abc".to_vec()).encode();
+        bytes[1] = 0x7f;
+        bytes.splice(HEADER_LEN..HEADER_LEN, vec![0, 2, 9, 9]);
+        assert_eq!(Packet::decode(&bytes).unwrap().payload, b"abc");
+
+        assert!(seq_lt(65535, 0));
+        assert!(!seq_lt(0, 65535));
+    }
+
+    #[test]
+    fn test_ledbat() {
+        let mut conn = Conn::new("127.0.0.1:1".parse().unwrap(), 1, 2, State::Connected);
+        let now = Instant::now();
+        conn.ledbat(1000, 1000, now);
+        let grown = conn.max_window;
+        assert!(grown > INITIAL_WINDOW);
+
+        // 300 ms of queueing on top of the base delay
+        conn.ledbat(301_000, 1000, now);
+        assert!(conn.max_window < grown);
+    }
+
+    #[test]
+    fn test_syn_flood() {
+        let socket = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for id in 0..MAX_BACKLOG as u16 + 36 {
+            let mut syn = Packet::new(ST_SYN, 1, vec![]);
+            syn.conn_id = id * 2;
+            udp.send_to(&syn.encode(), addr).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while socket.inner.lock().backlog.len() < MAX_BACKLOG && Instant::now() < deadline {
+            thread::sleep(TICK);
+        }
+        thread::sleep(TICK * 10);
+        {
+            let shared = socket.inner.lock();
+            assert_eq!(shared.backlog.len(), MAX_BACKLOG);
+            assert_eq!(shared.conns.len(), MAX_BACKLOG);
+        }
+
+        // Nobody accepted them, they make room again
+        socket
+            .inner
+            .tick(Instant::now() + BACKLOG_TIMEOUT + Duration::from_secs(1));
+        let shared = socket.inner.lock();
+        assert!(shared.backlog.is_empty() && shared.conns.is_empty());
+    }
+
+    #[test]
+    fn test_stream() {
+        let (_a, mut client, server) = pair(LinkConditions::default());
+        let mut server = transfer(&mut client, server, 100_000);
+
+        server.write_all(b"pong").unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Nothing to read yet, then the FIN shows up as end of stream
+        assert!(!client.wait_readable(Duration::from_millis(10)).unwrap());
+        drop(server);
+        assert!(client.wait_readable(Duration::from_secs(5)).unwrap());
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_loss_and_delay() {
+        let conditions = LinkConditions {
+            loss: 0.05,
+            delay: Duration::from_millis(10),
+            mtu: 0,
+        };
+        let (_a, mut client, server) = pair(conditions);
+        transfer(&mut client, server, 200_000);
+    }
+
+    #[test]
+    fn test_mtu_probing() {
+        let conditions = LinkConditions {
+            mtu: 1200,
+            ..LinkConditions::default()
+        };
+        let mut mtu = search_mtu(conditions);
+        assert!(
+            mtu <= 1200 && mtu > 1200 - MTU_SEARCH_DONE,
+            "found mtu {}",
+            mtu
+        );
+
+        // Probes lost to congestion don't bring the MTU down
+        let conditions = LinkConditions {
+            loss: 0.05,
+            ..LinkConditions::default()
+        };
+        mtu = search_mtu(conditions);
+        assert!(mtu > MTU_CEILING - MTU_SEARCH_DONE, "found mtu {}", mtu);
+    }
+}
@@ -0,0 +1,229 @@
+// Full hash check of data already on disk.
+//
+// Pieces are handed out to one worker per core, each with its own file
+// handles. Workers stream pieces through the hasher, so memory use doesn't
+// depend on the piece length or the torrent size.
+
+use crate::bitfield::Bitfield;
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::{FileInfo, TorrentFile};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Where a check is at, passed to the progress callback after every piece
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyProgress {
+    pub checked: usize,
+    pub valid: usize,
+    pub total: usize,
+}
+
+/// Hash every piece of the torrent under `dir` and return the ones that are
+/// complete. Missing or short files just mean missing pieces.
+pub fn verify<F>(torrent: &TorrentFile, dir: &Path, progress: F) -> Bitfield
+where
+    F: FnMut(VerifyProgress),
+{
+    if torrent.is_v2_only() {
+        return verify_v2(torrent, dir, progress);
+    }
+    verify_files(
+        dir,
+        &torrent.files,
+        torrent.piece_length(),
+        &torrent.piece_hashes,
+        progress,
+    )
+}
+
+pub fn verify_files<F>(
+    dir: &Path,
+    files: &[FileInfo],
+    piece_length: u64,
+    piece_hashes: &[[u8; 20]],
+    mut progress: F,
+) -> Bitfield
+where
+    F: FnMut(VerifyProgress),
+{
+    let total = piece_hashes.len();
+    let mut have = Bitfield::new(total);
+    let mut state = VerifyProgress {
+        checked: 0,
+        valid: 0,
+        total,
+    };
+
+    hash_pieces(
+        || FileStorage::from_files(dir, files, piece_length),
+        total,
+        |index, hash| {
+            state.checked += 1;
+            if matches!(hash, Ok(hash) if hash == piece_hashes[index]) {
+                have.set_piece(index);
+                state.valid += 1;
+            }
+            progress(state);
+        },
+    );
+
+    have
+}
+
+// v2 only torrents have no SHA-1 hashes, each piece is checked against its
+// file's merkle tree instead
+fn verify_v2<F>(torrent: &TorrentFile, dir: &Path, mut progress: F) -> Bitfield
+where
+    F: FnMut(VerifyProgress),
+{
+    let total = torrent.num_pieces();
+    let mut have = Bitfield::new(total);
+    let mut state = VerifyProgress {
+        checked: 0,
+        valid: 0,
+        total,
+    };
+
+    check_pieces(
+        || FileStorage::from_files(dir, &torrent.files, torrent.piece_length()),
+        total,
+        |storage, index| match torrent.v2_piece(index) {
+            Some((file, piece, length)) => match storage.read_block(index as u32, 0, length as u32)
+            {
+                Ok(data) => torrent.verify_piece_v2(file, piece, &data),
+                Err(_) => false,
+            },
+            None => false,
+        },
+        |index, valid| {
+            state.checked += 1;
+            if valid {
+                have.set_piece(index);
+                state.valid += 1;
+            }
+            progress(state);
+        },
+    );
+
+    have
+}
+
+/// Hash pieces 0..num_pieces on every core, each worker reading through its
+/// own storage from `open`. `done` is called on this thread as results come
+/// in, in no particular order.
+pub fn hash_pieces<O, F>(open: O, num_pieces: usize, done: F)
+where
+    O: Fn() -> FileStorage + Sync,
+    F: FnMut(usize, io::Result<[u8; 20]>),
+{
+    check_pieces(
+        open,
+        num_pieces,
+        |storage, index| storage.hash_piece(index as u32),
+        done,
+    )
+}
+
+// Run `check` on pieces 0..num_pieces on every core, see hash_pieces
+fn check_pieces<O, C, T, F>(open: O, num_pieces: usize, check: C, mut done: F)
+where
+    O: Fn() -> FileStorage + Sync,
+    C: Fn(&mut FileStorage, usize) -> T + Sync,
+    T: Send,
+    F: FnMut(usize, T),
+{
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(num_pieces)
+        .max(1);
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            let open = &open;
+            let check = &check;
+            scope.spawn(move || {
+                let mut storage = open();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= num_pieces {
+                        break;
+                    }
+                    let result = check(&mut storage, index);
+                    if tx.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        for (index, result) in rx {
+            done(index, result);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_verify() {
+        let dir = env::temp_dir().join(format!("bittorrent-verify-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let files = [
+            FileInfo {
+                path: vec!["t".to_string(), "a".to_string()],
+                length: 100,
+                ..FileInfo::default()
+            },
+            FileInfo {
+                path: vec!["t".to_string(), "b".to_string()],
+                length: 60,
+                ..FileInfo::default()
+            },
+        ];
+        let data: Vec<u8> = (0..160).map(|i| i as u8).collect();
+        let hashes: Vec<[u8; 20]> = data
+            .chunks(32)
+            .map(|piece| {
+                let mut hash = [0; 20];
+                hash.copy_from_slice(&Sha1::digest(piece));
+                hash
+            })
+            .collect();
+
+        // Nothing on disk yet
+        let have = verify_files(&dir, &files, 32, &hashes, |_| {});
+        assert!((0..5).all(|i| !have.has_piece(i)));
+
+        // Corrupt the second piece and leave the last file short
+        let mut a = data[..100].to_vec();
+        a[40] ^= 0xff;
+        fs::create_dir_all(dir.join("t")).unwrap();
+        fs::write(dir.join("t/a"), a).unwrap();
+        fs::write(dir.join("t/b"), &data[100..150]).unwrap();
+
+        let mut updates = vec![];
+        let have = verify_files(&dir, &files, 32, &hashes, |p| updates.push(p));
+        let pieces: Vec<bool> = (0..5).map(|i| have.has_piece(i)).collect();
+        assert_eq!(pieces, [true, false, true, true, false]);
+        assert_eq!(updates.len(), 5);
+        let last = updates.last().unwrap();
+        assert_eq!((last.checked, last.valid, last.total), (5, 3, 5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
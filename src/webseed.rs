@@ -0,0 +1,506 @@
+// Web seeds (BEP 19): plain HTTP servers with a copy of the torrent's
+// files, downloaded from with Range requests.
+//
+// A piece may span several files, each part gets its own request. Servers
+// that fail are left alone for a while, twice as long every time.
+//
+// HTTP seeds (BEP 17) are scripts that take the info hash and a piece
+// number instead, and answer 503 with the number of seconds to wait when
+// they're busy. Busy seeds are waited on for a few seconds at least, and
+// only so many times in a row before they count as failing.
+
+use crate::storage::{layout, split_range};
+use crate::torrent::TorrentFile;
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{RANGE, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// Shortest wait after a 503, whatever the seed says
+pub const MIN_RETRY_AFTER: Duration = Duration::from_secs(5);
+// Busy replies in a row before backing off like any other failure
+const MAX_BUSY: u32 = 5;
+// Read this much past the expected body, enough to tell it was too long
+const BODY_MARGIN: u64 = 1024;
+
+// Characters left alone in path components
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Which of the two HTTP protocols a seed speaks, named after the
+/// metainfo keys they come from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedKind {
+    UrlList,
+    HttpSeed,
+}
+
+// An HTTP seed is busy and asked us to come back later
+#[derive(Debug)]
+struct RetryAfter(Duration);
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "busy, retry in {}s", self.0.as_secs())
+    }
+}
+
+impl Error for RetryAfter {}
+
+#[derive(Debug)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: SeedKind,
+    // Bytes of pieces that passed the hash check
+    pub downloaded: u64,
+    client: Client,
+    failures: u32,
+    // 503s in a row
+    busy: u32,
+    retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, kind: SeedKind) -> WebSeed {
+        WebSeed {
+            url: url.to_string(),
+            kind,
+            downloaded: 0,
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_else(|_| Client::new()),
+            failures: 0,
+            busy: 0,
+            retry_at: None,
+        }
+    }
+
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    /// When a failing seed may be tried again
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    /// Back off after a failed request or a piece that didn't check out
+    pub fn failed(&mut self, now: Instant) {
+        let backoff = (FIRST_BACKOFF * 2u32.pow(self.failures.min(7))).min(MAX_BACKOFF);
+        self.failures += 1;
+        self.retry_at = Some(now + backoff);
+    }
+
+    // A URL ending in a slash is a directory for single file torrents,
+    // multi file torrents always add the torrent name and file path
+    pub fn file_url(&self, torrent: &TorrentFile, file: usize) -> String {
+        let path = &torrent.files[file].path;
+        let single = torrent.files.len() == 1 && path.len() == 1;
+        if single && !self.url.ends_with('/') {
+            return self.url.clone();
+        }
+
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let path: Vec<String> = path
+            .iter()
+            .map(|c| utf8_percent_encode(c, PATH).to_string())
+            .collect();
+        url + &path.join("/")
+    }
+
+    /// Download piece `index`. The caller still has to check its hash.
+    pub fn fetch_piece(
+        &mut self,
+        torrent: &TorrentFile,
+        index: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let result = match self.kind {
+            SeedKind::UrlList => self.try_fetch_ranges(torrent, index),
+            SeedKind::HttpSeed => self.try_fetch_http_seed(torrent, index),
+        };
+        let now = Instant::now();
+        match &result {
+            Ok(_) => {
+                self.failures = 0;
+                self.busy = 0;
+                self.retry_at = None;
+            }
+            // Busy isn't broken, wait as long as asked without backing off
+            Err(e) => match e.downcast_ref::<RetryAfter>() {
+                Some(RetryAfter(wait)) if self.busy < MAX_BUSY => {
+                    self.busy += 1;
+                    let wait = (*wait).clamp(MIN_RETRY_AFTER, MAX_BACKOFF);
+                    self.retry_at = Some(now + wait);
+                }
+                _ => self.failed(now),
+            },
+        }
+        result
+    }
+
+    pub fn http_seed_url(&self, torrent: &TorrentFile, index: usize) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        format!(
+            "{}{}info_hash={}&piece={}&ranges=0-{}",
+            self.url,
+            separator,
+            percent_encode(&torrent.info_hash, NON_ALPHANUMERIC),
+            index,
+            torrent.piece_size(index) - 1
+        )
+    }
+
+    fn try_fetch_http_seed(
+        &self,
+        torrent: &TorrentFile,
+        index: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let url = self.http_seed_url(torrent, index);
+        let response = self.client.get(&url).send()?;
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        let size = torrent.piece_size(index);
+        let body = read_body(response, size + BODY_MARGIN)?;
+
+        match status {
+            StatusCode::OK => {}
+            // The body is how many seconds to wait, some servers use the
+            // Retry-After header instead
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let wait = String::from_utf8_lossy(&body)
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .or(retry_after)
+                    .ok_or_else(|| format!("{} answered 503", self.url))?;
+                return Err(Box::new(RetryAfter(Duration::from_secs(wait))));
+            }
+            status => return Err(format!("{} answered {}", self.url, status).into()),
+        }
+
+        if body.len() as u64 != size {
+            return Err(
+                format!("{} sent {} bytes, expected {}", self.url, body.len(), size).into(),
+            );
+        }
+        Ok(body)
+    }
+
+    fn try_fetch_ranges(
+        &self,
+        torrent: &TorrentFile,
+        index: usize,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let files = layout(Path::new(""), &torrent.files);
+        let size = torrent.piece_size(index);
+        let mut piece = Vec::with_capacity(size as usize);
+        for (i, offset, length) in split_range(&files, torrent.piece_offset(index), size) {
+            // Servers don't have padding files
+            if torrent.files[i].attr.padding {
+                piece.resize(piece.len() + length as usize, 0);
+                continue;
+            }
+            let url = self.file_url(torrent, i);
+            piece.extend(self.fetch_range(&url, offset, length, files[i].length)?);
+        }
+        Ok(piece)
+    }
+
+    fn fetch_range(
+        &self,
+        url: &str,
+        offset: u64,
+        length: u64,
+        file_length: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .send()?;
+
+        // A server may ignore the range when it covers the whole file
+        let status = response.status();
+        let whole_file = offset == 0 && length == file_length;
+        if status != StatusCode::PARTIAL_CONTENT && !(status == StatusCode::OK && whole_file) {
+            return Err(format!("{} answered {}", url, status).into());
+        }
+
+        let body = read_body(response, length + BODY_MARGIN)?;
+        if body.len() as u64 != length {
+            return Err(format!("{} sent {} bytes, expected {}", url, body.len(), length).into());
+        }
+        Ok(body)
+    }
+}
+
+// Read no more than `limit` bytes of the body, however much the server sends
+fn read_body(response: Response, limit: u64) -> io::Result<Vec<u8>> {
+    let mut body = vec![];
+    response.take(limit).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create, CreateOptions};
+    use percent_encoding::percent_decode_str;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    // Stand-in for a web server: answers Range requests for `files`, keyed
+    // by URL path. The first `fail` requests get a 503.
+    fn serve(files: HashMap<String, Vec<u8>>, fail: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut path = String::new();
+                let mut range = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    let lower = line.to_lowercase();
+                    if lower.starts_with("get ") {
+                        path = line.split(' ').nth(1).unwrap().to_string();
+                    } else if let Some(value) = lower.strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                    line.clear();
+                }
+
+                let (status, body) = match (files.get(&path), range) {
+                    _ if n < fail => ("503 Service Unavailable", vec![]),
+                    (Some(data), Some((start, end))) => {
+                        ("206 Partial Content", data[start..=end].to_vec())
+                    }
+                    _ => ("404 Not Found", vec![]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        url
+    }
+
+    // Answers requests with `responses` in order, passing on the request
+    // targets
+    fn respond(responses: Vec<(&'static str, Vec<u8>)>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/seed.php", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for ((status, body), stream) in responses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                tx.send(line.split(' ').nth(1).unwrap().to_string())
+                    .unwrap();
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_fetch_across_files() {
+        let dir = env::temp_dir().join(format!("bittorrent-webseed-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("my pkg");
+        fs::create_dir_all(&root).unwrap();
+        let a: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..30_000).map(|i| (i / 7) as u8).collect();
+        fs::write(root.join("a"), &a).unwrap();
+        fs::write(root.join("b"), &b).unwrap();
+
+        let options = CreateOptions {
+            piece_length: Some(1 << 14),
+            ..CreateOptions::default()
+        };
+        fs::write(dir.join("t.torrent"), create(&root, &options).unwrap()).unwrap();
+        let torrent = TorrentFile::open(&dir.join("t.torrent")).unwrap();
+
+        let mut files = HashMap::new();
+        files.insert("/my%20pkg/a".to_string(), a.clone());
+        files.insert("/my%20pkg/b".to_string(), b.clone());
+        let url = serve(files, 1);
+
+        // The first request fails and the seed backs off
+        let mut seed = WebSeed::new(&url, SeedKind::UrlList);
+        assert_eq!(seed.file_url(&torrent, 1), format!("{}my%20pkg/b", url));
+        assert!(seed.fetch_piece(&torrent, 1).is_err());
+        let now = Instant::now();
+        assert!(!seed.is_available(now));
+        assert!(seed.is_available(now + FIRST_BACKOFF));
+
+        // Piece 1 is the end of a and the start of b
+        let data: Vec<u8> = a.iter().chain(&b).cloned().collect();
+        let piece = seed.fetch_piece(&torrent, 1).unwrap();
+        assert_eq!(piece, &data[1 << 14..2 << 14]);
+        assert!(seed.is_available(Instant::now()));
+        let last = torrent.num_pieces() - 1;
+        assert_eq!(
+            seed.fetch_piece(&torrent, last).unwrap(),
+            &data[last << 14..]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_http_seed() {
+        let dir = env::temp_dir().join(format!("bittorrent-httpseed-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..40_000).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("file.bin"), &data).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(1 << 14),
+            ..CreateOptions::default()
+        };
+        let bytes = create(&dir.join("file.bin"), &options).unwrap();
+        fs::write(dir.join("t.torrent"), bytes).unwrap();
+        let torrent = TorrentFile::open(&dir.join("t.torrent")).unwrap();
+
+        let (url, requests) = respond(vec![
+            ("503 Service Unavailable", b"120".to_vec()),
+            ("200 OK", data[2 << 14..].to_vec()),
+            ("500 Internal Server Error", vec![]),
+            // More than asked for
+            ("200 OK", vec![0; 100_000]),
+        ]);
+        let mut seed = WebSeed::new(&url, SeedKind::HttpSeed);
+
+        // Busy: wait as long as the server says, without backing off
+        let before = Instant::now();
+        assert!(seed.fetch_piece(&torrent, 2).is_err());
+        let retry_at = seed.retry_at().unwrap();
+        assert!(retry_at >= before + Duration::from_secs(120));
+        assert!(retry_at <= Instant::now() + Duration::from_secs(120));
+
+        assert_eq!(seed.fetch_piece(&torrent, 2).unwrap(), &data[2 << 14..]);
+        let query = requests.recv().unwrap();
+        assert_eq!(query, requests.recv().unwrap());
+        let (path, query) = query.split_once('?').unwrap();
+        assert_eq!(path, "/seed.php");
+        let params: HashMap<&str, Vec<u8>> = query
+            .split('&')
+            .map(|p| p.split_once('=').unwrap())
+            .map(|(k, v)| (k, percent_decode_str(v).collect()))
+            .collect();
+        assert_eq!(params["info_hash"], torrent.info_hash);
+        assert_eq!(params["piece"], b"2");
+        assert_eq!(
+            params["ranges"],
+            format!("0-{}", 40_000 - (2 << 14) - 1).as_bytes()
+        );
+
+        // Other errors back off as usual
+        assert!(seed.fetch_piece(&torrent, 0).is_err());
+        assert!(!seed.is_available(Instant::now() + FIRST_BACKOFF / 2));
+
+        // Reading stops a little past the piece
+        let err = seed.fetch_piece(&torrent, 0).unwrap_err().to_string();
+        assert!(err.contains(&format!("sent {} bytes", (1 << 14) + BODY_MARGIN)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_busy_http_seed() {
+        let dir = env::temp_dir().join(format!("bittorrent-busyseed-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file.bin"), vec![9; 1000]).unwrap();
+        let bytes = create(&dir.join("file.bin"), &CreateOptions::default()).unwrap();
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+
+        let mut responses = vec![
+            ("503 Service Unavailable", b"0".to_vec()),
+            // The status line goes first in the head, so this adds a header
+            ("503 Service Unavailable\r\nRetry-After: 0", vec![]),
+        ];
+        responses.extend(vec![("503 Service Unavailable", b"1".to_vec()); 4]);
+        // More than asked for
+        responses.push(("200 OK", vec![9; 5000]));
+        let (url, _requests) = respond(responses);
+        let mut seed = WebSeed::new(&url, SeedKind::HttpSeed);
+
+        // Waits no less than the minimum, even when told not to wait
+        for _ in 0..2 {
+            let before = Instant::now();
+            assert!(seed.fetch_piece(&torrent, 0).is_err());
+            assert!(seed.retry_at().unwrap() >= before + MIN_RETRY_AFTER);
+        }
+        // A seed that stays busy backs off like a failing one
+        for _ in 2..MAX_BUSY {
+            seed.fetch_piece(&torrent, 0).unwrap_err();
+        }
+        let before = Instant::now();
+        seed.fetch_piece(&torrent, 0).unwrap_err();
+        assert!(seed.retry_at().unwrap() >= before + FIRST_BACKOFF);
+
+        let err = seed.fetch_piece(&torrent, 0).unwrap_err();
+        assert!(err.to_string().contains("expected 1000"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut seed = WebSeed::new("http://example.invalid/", SeedKind::UrlList);
+        let now = Instant::now();
+        seed.failed(now);
+        seed.failed(now);
+        assert_eq!(seed.retry_at(), Some(now + FIRST_BACKOFF * 2));
+        for _ in 0..20 {
+            seed.failed(now);
+        }
+        assert_eq!(seed.retry_at(), Some(now + MAX_BACKOFF));
+    }
+}